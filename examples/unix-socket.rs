@@ -0,0 +1,43 @@
+extern crate websocket;
+
+#[cfg(unix)]
+fn main() {
+	use std::fs;
+	use std::os::unix::net::{UnixListener, UnixStream};
+	use std::thread;
+
+	use websocket::{Client, Message};
+	use websocket::client::request::Url;
+	use websocket::server::Request;
+
+	let path = "/tmp/rust-websocket-example.sock";
+	let _ = fs::remove_file(path);
+	let listener = UnixListener::bind(path).unwrap();
+
+	// The server reads the request and echoes messages over the Unix socket
+	let server = thread::spawn(move || {
+		let stream = listener.accept().unwrap().0;
+		let request = Request::read(stream.try_clone().unwrap(), stream).unwrap();
+		request.validate().unwrap();
+		let mut client = request.accept().send().unwrap();
+		client.run_echo().unwrap();
+	});
+
+	// The URL only provides the Host header and resource name
+	let url = Url::parse("ws://localhost/").unwrap();
+	let stream = UnixStream::connect(path).unwrap();
+	let mut client = Client::connect_on(&url, &stream, &stream).unwrap();
+
+	client.send_message(&Message::text("Hello over a Unix socket")).unwrap();
+	let message = client.recv_until(|_| true, None).unwrap();
+	println!("Recv: {:?}", message);
+
+	client.send_message(&Message::close()).unwrap();
+	server.join().unwrap();
+	fs::remove_file(path).unwrap();
+}
+
+#[cfg(not(unix))]
+fn main() {
+	println!("Unix domain sockets are not available on this platform");
+}
@@ -0,0 +1,128 @@
+//! Link quality accounting for sequenced pings.
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, BigEndian};
+
+/// The most pings presumed lost which are remembered, in case their pong arrives late.
+const MAX_LOST: usize = 256;
+
+/// Link quality statistics gathered from sequenced pings.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkStats {
+	/// The number of sequenced pings sent.
+	pub sent: u64,
+	/// The number of pongs received for sequenced pings.
+	pub received: u64,
+	/// The number of pings which are presumed lost, because a pong for a later ping
+	/// arrived first and their own pong has not arrived since.
+	pub lost: u64,
+	/// The number of pongs which arrived after the pong for a later ping.
+	pub reordered: u64,
+	/// The round trip time of the most recently answered ping.
+	pub last_rtt: Option<Duration>,
+}
+
+/// Tracks sequenced pings and their pongs.
+pub struct LinkTracker {
+	stats: LinkStats,
+	next: u64,
+	highest: Option<u64>,
+	outstanding: BTreeMap<u64, Instant>,
+	lost: BTreeMap<u64, Instant>,
+}
+
+impl LinkTracker {
+	/// Creates a new LinkTracker.
+	pub fn new() -> LinkTracker {
+		LinkTracker {
+			stats: LinkStats::default(),
+			next: 0,
+			highest: None,
+			outstanding: BTreeMap::new(),
+			lost: BTreeMap::new(),
+		}
+	}
+	/// Returns the statistics gathered so far.
+	pub fn stats(&self) -> LinkStats {
+		self.stats
+	}
+	/// Returns the payload for the next sequenced ping, along with its sequence number.
+	///
+	/// The ping is not counted until `sent()` is called.
+	pub fn next_payload(&self) -> (u64, Vec<u8>) {
+		let mut payload = vec![0; 8];
+		BigEndian::write_u64(&mut payload, self.next);
+		(self.next, payload)
+	}
+	/// Records that the ping returned by `next_payload()` was sent.
+	pub fn sent(&mut self) {
+		self.outstanding.insert(self.next, Instant::now());
+		self.next += 1;
+		self.stats.sent += 1;
+	}
+	/// Records a received pong, ignoring any which do not answer a sequenced ping.
+	pub fn pong(&mut self, payload: &[u8]) {
+		if payload.len() != 8 {
+			return;
+		}
+		let sequence = BigEndian::read_u64(payload);
+		let now = Instant::now();
+
+		if let Some(sent) = self.lost.remove(&sequence) {
+			self.stats.lost -= 1;
+			self.stats.reordered += 1;
+			self.stats.received += 1;
+			self.stats.last_rtt = Some(now.duration_since(sent));
+			return;
+		}
+		let sent = match self.outstanding.remove(&sequence) {
+			Some(sent) => sent,
+			None => return,
+		};
+		self.stats.received += 1;
+		self.stats.last_rtt = Some(now.duration_since(sent));
+
+		match self.highest {
+			Some(highest) if sequence < highest => self.stats.reordered += 1,
+			_ => self.highest = Some(sequence),
+		}
+		let later = self.outstanding.split_off(&sequence);
+		let earlier = ::std::mem::replace(&mut self.outstanding, later);
+		self.stats.lost += earlier.len() as u64;
+		self.lost.extend(earlier);
+		while self.lost.len() > MAX_LOST {
+			let oldest = *self.lost.keys().next().unwrap();
+			self.lost.remove(&oldest);
+		}
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+
+	fn send(tracker: &mut LinkTracker) -> Vec<u8> {
+		let (_, payload) = tracker.next_payload();
+		tracker.sent();
+		payload
+	}
+
+	#[test]
+	fn test_link_tracker() {
+		let mut tracker = LinkTracker::new();
+		let first = send(&mut tracker);
+		let second = send(&mut tracker);
+		let third = send(&mut tracker);
+
+		tracker.pong(&second);
+		assert_eq!(tracker.stats().lost, 1);
+		tracker.pong(&first);
+		tracker.pong(&third);
+		tracker.pong(b"unrelated");
+
+		let stats = tracker.stats();
+		assert_eq!((stats.sent, stats.received, stats.lost, stats.reordered), (3, 3, 0, 1));
+		assert!(stats.last_rtt.is_some());
+	}
+}
@@ -1,27 +1,45 @@
 //! Contains the WebSocket client.
 
-use std::net::TcpStream;
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
 use std::marker::PhantomData;
+use std::str;
+use std::io::{Read, Write};
 use std::io::Result as IoResult;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use ws;
 use ws::util::url::ToWebSocketUrlComponents;
-use ws::receiver::{DataFrameIterator, MessageIterator};
-use result::WebSocketResult;
-use stream::WebSocketStream;
-use dataframe::DataFrame;
+use ws::receiver::{DataFrameIterator, MessageIterator, MessageMeta};
+use result::{WebSocketResult, WebSocketError};
+use stream::{WebSocketStream, SharedSslStream, TcpOptions};
+use shared::SharedSender;
+use dataframe::{DataFrame, Opcode};
+use message::{Message, Type, CloseCode};
+use deflate::{DeflateParams, PreparedMessage};
+use metrics::Metrics;
 use ws::dataframe::DataFrame as DataFrameable;
+use self::link::LinkTracker;
+use server::limit::ConnectionPermit;
 
-use openssl::ssl::{SslContext, SslMethod, SslStream};
+use hyper::Error as HttpError;
+use hyper::header::{Headers, Host};
+use openssl::ssl::{Ssl, SslContext, SslMethod, SslStream};
+use openssl::ssl::error::SslError;
 
-pub use self::request::Request;
+pub use self::request::{Request, HandshakeInProgress};
 pub use self::response::Response;
+pub use self::link::LinkStats;
+pub use self::socks::Socks5Proxy;
 
 pub use sender::Sender;
 pub use receiver::Receiver;
 
 pub mod request;
 pub mod response;
+pub mod link;
+pub mod socks;
 
 /// Represents a WebSocket client, which can send and receive messages/data frames.
 ///
@@ -59,9 +77,114 @@ pub mod response;
 pub struct Client<F, S, R> {
 	sender: S,
 	receiver: R,
+	unanswered_pings: usize,
+	max_unanswered_pings: Option<usize>,
+	auto_pong: bool,
+	keepalive: Option<(Duration, Duration)>,
+	last_ping_at: Option<Instant>,
+	awaiting_pong_since: Option<Instant>,
+	last_pong_at: Option<Instant>,
+	close_sent: bool,
+	close_received: Option<Option<CloseData>>,
+	close_status: CloseStatus,
+	failure: Option<&'static str>,
+	link: LinkTracker,
+	real_peer_addr: Option<IpAddr>,
+	compression: Option<DeflateParams>,
+	protocol: Option<String>,
+	response_headers: Headers,
+	permit: Option<ConnectionPermit>,
 	_dataframe: PhantomData<fn(F)>
 }
 
+/// The status code and reason carried by a close message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseData {
+	/// The status code of the close message.
+	pub status_code: u16,
+	/// The reason given for closing, which may be empty.
+	pub reason: String,
+}
+
+impl CloseData {
+	/// Creates a new CloseData with the given status code and reason.
+	pub fn new<S: Into<String>>(status_code: u16, reason: S) -> CloseData {
+		CloseData {
+			status_code: status_code,
+			reason: reason.into(),
+		}
+	}
+	/// Creates a new CloseData with the given typed status code and reason.
+	pub fn with_code<S: Into<String>>(code: CloseCode, reason: S) -> CloseData {
+		CloseData::new(code.into(), reason)
+	}
+	/// Returns the status code as a `CloseCode`.
+	pub fn code(&self) -> CloseCode {
+		CloseCode::from(self.status_code)
+	}
+	/// Creates a CloseData with status code 1013 (Try Again Later), which tells the
+	/// remote endpoint that the server is overloaded and it should back off before
+	/// reconnecting. The reason may say for how long, for example.
+	pub fn try_again_later<S: Into<String>>(reason: S) -> CloseData {
+		CloseData::with_code(CloseCode::TryAgainLater, reason)
+	}
+	/// Returns true if the status code is 1013 (Try Again Later), in which case the
+	/// connection should only be retried after backing off.
+	pub fn is_try_again_later(&self) -> bool {
+		self.code() == CloseCode::TryAgainLater
+	}
+	/// Creates a close message carrying this status code and reason.
+	pub fn into_message(self) -> Message<'static> {
+		Message::close_because(self.status_code, self.reason)
+	}
+	/// Parses the payload of a received close message, returning None if it is empty.
+	///
+	/// Fails with a protocol error if the payload is a single byte, or if the status code
+	/// is not one that may be sent in a close message (1005 and 1006 in particular are
+	/// reserved for reporting a missing status and an abnormal close locally), and with a
+	/// UTF-8 error if the reason is not valid UTF-8.
+	pub fn parse(payload: &[u8]) -> WebSocketResult<Option<CloseData>> {
+		if payload.is_empty() {
+			return Ok(None);
+		}
+		if payload.len() < 2 {
+			return Err(WebSocketError::ProtocolError("Truncated close status code"));
+		}
+		let status_code = (payload[0] as u16) << 8 | payload[1] as u16;
+		match status_code {
+			1000...1003 | 1007...1014 | 3000...4999 => (),
+			_ => return Err(WebSocketError::ProtocolError("Invalid close status code")),
+		}
+		let reason = try!(str::from_utf8(&payload[2..]));
+		Ok(Some(CloseData::new(status_code, reason)))
+	}
+	fn from_payload(payload: &[u8]) -> Option<CloseData> {
+		if payload.len() < 2 {
+			return None;
+		}
+		Some(CloseData {
+			status_code: (payload[0] as u16) << 8 | payload[1] as u16,
+			reason: String::from_utf8_lossy(&payload[2..]).into_owned(),
+		})
+	}
+}
+
+/// Describes whether and how the connection of a Client has been closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseStatus {
+	/// The closing handshake has not been completed.
+	NotClosed,
+	/// Close messages were both sent and received. This holds the status code and
+	/// reason sent by the remote endpoint, if any.
+	CleanClose(Option<CloseData>),
+	/// Receiving failed before the closing handshake was completed, for example
+	/// because the connection was reset. This holds a description of the error.
+	AbnormalClose(String),
+	/// A close message was sent, but `Client::close()` gave up waiting for the remote
+	/// endpoint's close message.
+	TimedOut,
+}
+
 impl Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>> {
 	/// Connects to the given ws:// or wss:// URL and return a Request to be sent.
 	///
@@ -79,22 +202,128 @@ impl Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>> {
 	/// A connection is established, however the request is not sent to
 	/// the server until a call to ```send()```.
 	pub fn connect_ssl_context<T: ToWebSocketUrlComponents>(components: T, context: &SslContext) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		Client::connect_ssl_context_with_fallback(components, context, &[])
+	}
+	/// Connects to the given ws:// or wss:// URL, falling back to the given addresses
+	/// if the host name cannot be resolved.
+	///
+	/// Normal resolution is always tried first, and the fallback addresses are only
+	/// used when it fails, not when connecting to a resolved address fails. Pinned
+	/// addresses can go stale if the host moves, so they should be refreshed from
+	/// successful resolutions where possible.
+	pub fn connect_with_fallback<T: ToWebSocketUrlComponents>(components: T, fallback: &[SocketAddr]) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		let context = try!(SslContext::new(SslMethod::Tlsv1));
+		Client::connect_ssl_context_with_fallback(components, &context, fallback)
+	}
+	/// Connects to the specified wss:// URL using the given SSL context, falling back
+	/// to the given addresses if the host name cannot be resolved.
+	///
+	/// See `connect_with_fallback()` for details.
+	pub fn connect_ssl_context_with_fallback<T: ToWebSocketUrlComponents>(components: T, context: &SslContext, fallback: &[SocketAddr]) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		Client::connect_ssl_context_with(components, context, fallback, None)
+	}
+	/// Connects to the given ws:// or wss:// URL, setting the given socket options as
+	/// soon as the TCP connection is established, and returns a Request to be sent.
+	///
+	/// Unlike setting them on the Request's stream, this also covers the TLS handshake.
+	pub fn connect_with_tcp_options<T: ToWebSocketUrlComponents>(components: T, options: &TcpOptions) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		let context = try!(SslContext::new(SslMethod::Tlsv1));
+		Client::connect_ssl_context_with_tcp_options(components, &context, options)
+	}
+	/// Connects to the specified wss:// URL using the given SSL context, setting the
+	/// given socket options as soon as the TCP connection is established.
+	///
+	/// See `connect_with_tcp_options()` for details.
+	pub fn connect_ssl_context_with_tcp_options<T: ToWebSocketUrlComponents>(components: T, context: &SslContext, options: &TcpOptions) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		Client::connect_ssl_context_with(components, context, &[], Some(options))
+	}
+	fn connect_ssl_context_with<T: ToWebSocketUrlComponents>(components: T, context: &SslContext, fallback: &[SocketAddr], options: Option<&TcpOptions>) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
 		let (host, resource_name, secure) = try!(components.to_components());
 
-		let connection = try!(TcpStream::connect(
-			(&host.hostname[..], host.port.unwrap_or(if secure { 443 } else { 80 }))
+		let connection = try!(connect_tcp(
+			&host.hostname[..],
+			host.port.unwrap_or(if secure { 443 } else { 80 }),
+			fallback,
+			None
 		));
-
-		let stream = if secure {
-			let sslstream = try!(SslStream::connect(context, connection));
-			WebSocketStream::Ssl(sslstream)
+		if let Some(options) = options {
+			try!(options.apply(&connection));
 		}
-		else {
-			WebSocketStream::Tcp(connection)
-		};
 
+		let stream = try!(secure_stream(connection, context, &host.hostname, secure));
+		Request::new((host, resource_name, secure), try!(stream.try_clone()), stream)
+	}
+	/// Connects to the given ws:// or wss:// URL through a SOCKS5 proxy and returns a
+	/// Request to be sent.
+	///
+	/// The proxy connects to the host and port of the URL, and the TLS and WebSocket
+	/// handshakes then take place through it. Failures reported by the proxy are
+	/// returned as `WebSocketError::ProxyError`. Socket options are set with
+	/// `Socks5Proxy::set_tcp_options()`.
+	pub fn connect_socks5<T: ToWebSocketUrlComponents>(components: T, proxy: &Socks5Proxy) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		let context = try!(SslContext::new(SslMethod::Tlsv1));
+		Client::connect_ssl_context_socks5(components, &context, proxy)
+	}
+	/// Connects to the specified wss:// URL through a SOCKS5 proxy using the given SSL
+	/// context.
+	///
+	/// See `connect_socks5()` for details.
+	pub fn connect_ssl_context_socks5<T: ToWebSocketUrlComponents>(components: T, context: &SslContext, proxy: &Socks5Proxy) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		let (host, resource_name, secure) = try!(components.to_components());
+
+		let connection = try!(proxy.connect(
+			&host.hostname[..],
+			host.port.unwrap_or(if secure { 443 } else { 80 })
+		));
+
+		let stream = try!(secure_stream(connection, context, &host.hostname, secure));
 		Request::new((host, resource_name, secure), try!(stream.try_clone()), stream)
 	}
+	/// Connects to the given ws:// or wss:// URL and performs the handshake, giving up
+	/// with `WebSocketError::Timeout` if it takes too long.
+	///
+	/// Each address the host name resolves to gets `timeout` to accept the TCP
+	/// connection, and the addresses are tried in turn. Once connected, the TLS
+	/// handshake and the WebSocket upgrade together must finish within `timeout`.
+	/// The read and write timeouts of the returned Client's stream are cleared.
+	pub fn connect_with_timeout<T: ToWebSocketUrlComponents>(components: T, timeout: Duration) -> WebSocketResult<Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>> {
+		let context = try!(SslContext::new(SslMethod::Tlsv1));
+		Client::connect_ssl_context_with_timeout(components, &context, timeout)
+	}
+	/// Connects to the specified wss:// URL using the given SSL context and performs
+	/// the handshake, giving up with `WebSocketError::Timeout` if it takes too long.
+	///
+	/// See `connect_with_timeout()` for details.
+	pub fn connect_ssl_context_with_timeout<T: ToWebSocketUrlComponents>(components: T, context: &SslContext, timeout: Duration) -> WebSocketResult<Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>> {
+		Client::connect_ssl_context_with_timeout_and(components, context, timeout, None)
+	}
+	/// Like `connect_with_timeout()`, but sets the given socket options as soon as the
+	/// TCP connection is established, so that they cover the handshakes too.
+	pub fn connect_with_timeout_and_tcp_options<T: ToWebSocketUrlComponents>(components: T, timeout: Duration, options: &TcpOptions) -> WebSocketResult<Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>> {
+		let context = try!(SslContext::new(SslMethod::Tlsv1));
+		Client::connect_ssl_context_with_timeout_and_tcp_options(components, &context, timeout, options)
+	}
+	/// Like `connect_ssl_context_with_timeout()`, but sets the given socket options as
+	/// soon as the TCP connection is established.
+	pub fn connect_ssl_context_with_timeout_and_tcp_options<T: ToWebSocketUrlComponents>(components: T, context: &SslContext, timeout: Duration, options: &TcpOptions) -> WebSocketResult<Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>> {
+		Client::connect_ssl_context_with_timeout_and(components, context, timeout, Some(options))
+	}
+	fn connect_ssl_context_with_timeout_and<T: ToWebSocketUrlComponents>(components: T, context: &SslContext, timeout: Duration, options: Option<&TcpOptions>) -> WebSocketResult<Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>> {
+		let (host, resource_name, secure) = try!(components.to_components());
+
+		let connection = try!(connect_tcp(
+			&host.hostname[..],
+			host.port.unwrap_or(if secure { 443 } else { 80 }),
+			&[],
+			Some(timeout)
+		).map_err(timed_out));
+		if let Some(options) = options {
+			try!(options.apply(&connection));
+		}
+
+		let deadline = Instant::now() + timeout;
+		handshake_before((host, resource_name, secure), connection, context, deadline).map_err(timed_out)
+	}
 
     /// Shuts down the sending half of the client connection, will cause all pending
     /// and future IO to return immediately with an appropriate value.
@@ -113,6 +342,424 @@ impl Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>> {
     pub fn shutdown(&mut self) -> IoResult<()> {
         self.receiver.shutdown_all()
     }
+
+	/// Returns an iterator over incoming messages which stops waiting after `timeout`
+	/// passes without any data arriving.
+	///
+	/// When a read times out, `Err(WebSocketError::Timeout)` is yielded and the iterator
+	/// carries on, so an idle connection can be closed without a separate thread. Any
+	/// part of a message which has already arrived is kept, and the next call carries
+	/// on receiving it. The iterator ends after yielding an error which leaves the
+	/// connection unusable (see `WebSocketError::is_recoverable()`).
+	///
+	/// The timeout is set as the stream's read timeout until the iterator is dropped,
+	/// when the previous read timeout is restored.
+	pub fn incoming_messages_timeout<'a>(&'a mut self, timeout: Duration) -> WebSocketResult<TimeoutMessages<'a>> {
+		let previous = {
+			let stream = self.receiver.get_mut().get_mut();
+			let previous = try!(stream.read_timeout());
+			try!(stream.set_read_timeout(Some(timeout)));
+			previous
+		};
+		Ok(TimeoutMessages {
+			client: self,
+			previous: previous,
+			finished: false,
+		})
+	}
+	/// Closes the connection with the closing handshake, consuming the Client.
+	///
+	/// A close message with the given status code and reason is sent, unless one has
+	/// already been sent. Messages are then received and discarded, with pings still
+	/// answered, until the remote endpoint sends its close message or `timeout` passes.
+	/// Finally the sending half of the connection is shut down.
+	///
+	/// Returns `CloseStatus::CleanClose` if the closing handshake was completed, or
+	/// `CloseStatus::TimedOut` if it was not within `timeout`. If the connection ended
+	/// without a close message, `CloseStatus::AbnormalClose` is returned. Errors sending or flushing the
+	/// close message, or shutting down, are returned as they are, but a pong which cannot
+	/// be sent only stops the wait, with `CloseStatus::AbnormalClose`.
+	pub fn close(mut self, code: u16, reason: &str, timeout: Duration) -> WebSocketResult<CloseStatus> {
+		let deadline = Instant::now() + timeout;
+		if !self.close_sent {
+			try!(self.send_message(&Message::close_because(code, reason)));
+		}
+		while self.close_received.is_none() {
+			let remaining = match remaining_until(deadline) {
+				Ok(remaining) => remaining,
+				Err(_) => break,
+			};
+			try!(self.receiver.get_mut().get_mut().set_read_timeout(Some(remaining)));
+			match self.recv_owned_message() {
+				Ok(ref message) if message.opcode == Type::Ping && !self.auto_pong => {
+					let pong = Message::pong(message.payload.clone());
+					if let Err(e) = self.send_message(&pong) {
+						if self.close_status == CloseStatus::NotClosed {
+							self.close_status = CloseStatus::AbnormalClose(e.to_string());
+						}
+						break;
+					}
+				}
+				Ok(_) => (),
+				Err(e) => match timed_out(e) {
+					WebSocketError::Timeout => break,
+					ref e if !e.is_recoverable() => break,
+					_ => (),
+				},
+			}
+		}
+		if self.close_status == CloseStatus::NotClosed {
+			self.close_status = CloseStatus::TimedOut;
+		}
+		// The close message may still be waiting to be coalesced with later data frames
+		try!(ws::Sender::flush(&mut self.sender));
+		try!(self.shutdown_sender());
+		Ok(self.close_status.clone())
+	}
+}
+
+/// An iterator over incoming messages with a read timeout. See
+/// `Client::incoming_messages_timeout()`.
+pub struct TimeoutMessages<'a> {
+	client: &'a mut Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>,
+	previous: Option<Duration>,
+	finished: bool,
+}
+
+impl<'a> Iterator for TimeoutMessages<'a> {
+	type Item = WebSocketResult<Message<'static>>;
+
+	fn next(&mut self) -> Option<WebSocketResult<Message<'static>>> {
+		if self.finished {
+			return None;
+		}
+		match self.client.recv_owned_message() {
+			Ok(message) => Some(Ok(message)),
+			Err(e) => {
+				let e = timed_out(e);
+				if !e.is_recoverable() {
+					self.finished = true;
+				}
+				Some(Err(e))
+			}
+		}
+	}
+}
+
+impl<'a> Drop for TimeoutMessages<'a> {
+	fn drop(&mut self) {
+		let _ = self.client.receiver.get_mut().get_mut().set_read_timeout(self.previous);
+	}
+}
+
+impl<R: Read, W: Write> Client<DataFrame, Sender<W>, Receiver<R>> {
+	/// Performs the handshake over an already established connection, and returns the
+	/// Client if the server accepted it.
+	///
+	/// No name resolution or connecting is done: the request is written to `writer` and
+	/// the response read from `reader`, which may be the two halves of any transport,
+	/// such as a Unix socket or an SSH channel. Streams which implement `Read` and `Write`
+	/// for shared references, like `TcpStream` and `UnixStream`, can be passed as
+	/// `&stream` twice. The `Sec-WebSocket-Accept` header is checked as usual.
+	///
+	/// To change the request before it is sent, use `Request::new()` instead.
+	pub fn connect_on<T: ToWebSocketUrlComponents>(components: T, reader: R, writer: W) -> WebSocketResult<Client<DataFrame, Sender<W>, Receiver<R>>> {
+		let request = try!(Request::new(components, reader, writer));
+		let response = try!(request.send());
+		try!(response.validate());
+		Ok(response.begin())
+	}
+	/// Sends a message compressed in advance with `PreparedMessage`, which lets the same
+	/// message be broadcast to many clients while only compressing it once.
+	///
+	/// It is sent compressed if this connection compresses outgoing messages, and as it
+	/// is otherwise. Returns an error without sending anything if this connection keeps
+	/// its compression context across outgoing messages, which must be avoided by
+	/// negotiating `*_no_context_takeover` for this direction.
+	pub fn send_prepared(&mut self, message: &PreparedMessage) -> WebSocketResult<()> {
+		self.sender.send_prepared(message, self.compression.as_ref())
+	}
+	/// Sets the hooks called as data frames and messages are sent and received, or
+	/// removes them if `metrics` is None. See `Metrics`.
+	///
+	/// This sets them on both the Sender and the Receiver, so they carry on being
+	/// called after the Client is split.
+	pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+		self.sender.set_metrics(metrics.clone());
+		self.receiver.set_metrics(metrics);
+	}
+}
+
+// Starts TLS if `secure` is set, naming the host with SNI unless it is an IP address.
+fn secure_stream(connection: TcpStream, context: &SslContext, hostname: &str, secure: bool) -> WebSocketResult<WebSocketStream> {
+	if secure {
+		let ssl = try!(Ssl::new(context));
+		if hostname.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().is_err() {
+			try!(ssl.set_hostname(hostname));
+		}
+		let sslstream = try!(SslStream::connect(ssl, connection));
+		Ok(WebSocketStream::Ssl(SharedSslStream::new(sslstream)))
+	}
+	else {
+		Ok(WebSocketStream::Tcp(connection))
+	}
+}
+
+fn connect_tcp(hostname: &str, port: u16, fallback: &[SocketAddr], timeout: Option<Duration>) -> IoResult<TcpStream> {
+	let addrs: Vec<SocketAddr> = match (hostname, port).to_socket_addrs() {
+		Ok(addrs) => addrs.collect(),
+		Err(_) if !fallback.is_empty() => Vec::new(),
+		Err(e) => return Err(e),
+	};
+	let addrs = if addrs.is_empty() && !fallback.is_empty() { fallback } else { &addrs[..] };
+	match timeout {
+		Some(timeout) => {
+			let mut last_error = None;
+			for addr in addrs {
+				match TcpStream::connect_timeout(addr, timeout) {
+					Ok(stream) => return Ok(stream),
+					Err(e) => last_error = Some(e),
+				}
+			}
+			Err(last_error.unwrap_or_else(|| IoError::new(
+				ErrorKind::InvalidInput,
+				"could not resolve to any addresses"
+			)))
+		}
+		None => TcpStream::connect(addrs),
+	}
+}
+
+// Completes the TLS and WebSocket handshakes on `connection` before `deadline`.
+fn handshake_before(components: (Host, String, bool), connection: TcpStream, context: &SslContext, deadline: Instant)
+	-> WebSocketResult<Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>> {
+	let remaining = try!(remaining_until(deadline));
+	try!(connection.set_read_timeout(Some(remaining)));
+	try!(connection.set_write_timeout(Some(remaining)));
+
+	let mut stream = try!(secure_stream(connection, context, &components.0.hostname, components.2));
+
+	let remaining = try!(remaining_until(deadline));
+	try!(stream.set_read_timeout(Some(remaining)));
+	try!(stream.set_write_timeout(Some(remaining)));
+
+	let request = try!(Request::new(components, try!(stream.try_clone()), stream));
+	let mut response = try!(request.send_timeout(try!(remaining_until(deadline))));
+	try!(response.validate());
+	{
+		let stream = response.get_mut_reader().get_mut();
+		try!(stream.set_read_timeout(None));
+		try!(stream.set_write_timeout(None));
+	}
+	Ok(response.begin())
+}
+
+fn remaining_until(deadline: Instant) -> WebSocketResult<Duration> {
+	let now = Instant::now();
+	if now >= deadline {
+		return Err(WebSocketError::Timeout);
+	}
+	Ok(deadline - now)
+}
+
+// Turns the errors a socket timeout surfaces as into `WebSocketError::Timeout`.
+fn timed_out<E: Into<WebSocketError>>(error: E) -> WebSocketError {
+	fn is_timeout(error: &IoError) -> bool {
+		error.kind() == ErrorKind::TimedOut || error.kind() == ErrorKind::WouldBlock
+	}
+	match error.into() {
+		WebSocketError::IoError(ref e) if is_timeout(e) => WebSocketError::Timeout,
+		WebSocketError::HttpError(HttpError::Io(ref e)) if is_timeout(e) => WebSocketError::Timeout,
+		WebSocketError::SslError(SslError::StreamError(ref e)) if is_timeout(e) => WebSocketError::Timeout,
+		error => error,
+	}
+}
+
+impl<S: ws::Sender, R: ws::Receiver<DataFrame>> Client<DataFrame, S, R> {
+	/// Sends every message received back to the remote endpoint until the connection
+	/// is closed, which is mostly useful for test servers.
+	///
+	/// Text and binary messages are echoed unchanged, pings are answered with a pong
+	/// and pongs are ignored. A close message is answered with a close message carrying
+	/// the same status code, after which this returns `Ok(())`. Any error while sending
+	/// or receiving is returned immediately.
+	pub fn run_echo(&mut self) -> WebSocketResult<()> {
+		loop {
+			let message = try!(self.recv_owned_message());
+			match message.opcode {
+				Type::Close => {
+					let reply = match message.cd_status_code {
+						Some(code) => Message::close_because(code, ""),
+						None => Message::close(),
+					};
+					return self.send_message(&reply);
+				}
+				Type::Ping if !self.auto_pong => {
+					let pong = Message::pong(message.payload);
+					try!(self.send_message(&pong));
+				}
+				Type::Ping | Type::Pong => (),
+				_ => try!(self.send_message(&message)),
+			}
+		}
+	}
+	/// Receives messages until one satisfies `predicate`, and returns it.
+	///
+	/// Messages which do not satisfy the predicate are discarded, although pings are
+	/// still answered with a pong and a close message is always returned.
+	///
+	/// If `timeout` elapses before a matching message arrives, an I/O error of kind
+	/// `TimedOut` is returned. The timeout is only checked between messages, so a read
+	/// timeout should also be set on the underlying stream to bound a blocking read.
+	pub fn recv_until<P>(&mut self, predicate: P, timeout: Option<Duration>) -> WebSocketResult<Message<'static>>
+	where P: FnMut(&Message) -> bool {
+		self.recv_until_with(predicate, timeout, |_| ())
+	}
+	/// Like `recv_until()`, but every discarded message is passed to `discarded`.
+	pub fn recv_until_with<P, C>(&mut self, mut predicate: P, timeout: Option<Duration>, mut discarded: C) -> WebSocketResult<Message<'static>>
+	where P: FnMut(&Message) -> bool, C: FnMut(Message<'static>) {
+		let deadline = timeout.map(|timeout| Instant::now() + timeout);
+		loop {
+			if let Some(deadline) = deadline {
+				if Instant::now() >= deadline {
+					return Err(WebSocketError::IoError(IoError::new(
+						ErrorKind::TimedOut, "No matching message received before the timeout"
+					)));
+				}
+			}
+			let message = try!(self.recv_owned_message());
+			match message.opcode {
+				Type::Ping if !self.auto_pong => {
+					let pong = Message::pong(message.payload.clone());
+					try!(self.send_message(&pong));
+				}
+				Type::Close => return Ok(message),
+				_ => (),
+			}
+			if predicate(&message) {
+				return Ok(message);
+			}
+			discarded(message);
+		}
+	}
+	/// Receives the next text or binary message, reporting the end of the connection as
+	/// `WebSocketError::ConnectionClosed`.
+	///
+	/// Pings are answered with a pong and pongs are ignored. When the remote endpoint
+	/// sends a close message, it is answered with a close message unless one has already
+	/// been sent, and `ConnectionClosed` is returned with `clean` set and the status code
+	/// and reason it sent, if any. If the connection ends without a close message,
+	/// `ConnectionClosed` is returned with `clean` unset and no status code: the status
+	/// codes 1005 and 1006, which stand for these cases, are never reported as if they
+	/// had been received. Every later call returns the same error. Other errors, such as
+	/// a reset connection, are returned as they are.
+	pub fn recv_data_message(&mut self) -> WebSocketResult<Message<'static>> {
+		loop {
+			if let Some(ref data) = self.close_received {
+				return Err(WebSocketError::ConnectionClosed {
+					clean: true,
+					code: data.as_ref().map(|data| data.status_code),
+					reason: data.as_ref().map(|data| data.reason.clone()),
+				});
+			}
+			let message = match self.recv_owned_message() {
+				Ok(message) => message,
+				Err(WebSocketError::NoDataAvailable) => return Err(WebSocketError::ConnectionClosed {
+					clean: false,
+					code: None,
+					reason: None,
+				}),
+				Err(e) => return Err(e),
+			};
+			match message.opcode {
+				Type::Text | Type::Binary => return Ok(message),
+				Type::Ping if !self.auto_pong => {
+					try!(self.send_message(&Message::pong(message.payload)));
+				}
+				Type::Ping | Type::Pong => (),
+				Type::Close => if !self.close_sent {
+					let reply = match message.cd_status_code {
+						Some(code) => Message::close_because(code, ""),
+						None => Message::close(),
+					};
+					// The remote endpoint may not wait for the reply
+					let _ = self.send_message(&reply);
+				},
+			}
+		}
+	}
+	/// Splits this client into a cloneable `SharedSender` and the Receiver.
+	///
+	/// The Sender is moved to a writer thread, and every `SharedSender` handle queues
+	/// its messages for that thread, so sends from different threads are serialized
+	/// and never interleave. See `SharedSender` for details.
+	pub fn split_shared(self) -> (SharedSender, R)
+	where S: Send + 'static {
+		(SharedSender::new(self.sender), self.receiver)
+	}
+	/// Returns an iterator over incoming text and binary messages, which handles control
+	/// messages itself.
+	///
+	/// Pings are answered with a pong and pongs are ignored. When a close message is
+	/// received, it is answered with a close message and the iterator ends. The iterator
+	/// also ends after yielding an error.
+	pub fn data_messages<'a>(&'a mut self) -> DataMessages<'a, S, R> {
+		DataMessages {
+			client: self,
+			finished: false,
+		}
+	}
+	fn recv_owned_message(&mut self) -> WebSocketResult<Message<'static>> {
+		let dataframes = try!(self.recv_message_dataframes());
+		if self.receiver.validates_utf8() {
+			ws::Message::from_dataframes(dataframes)
+		}
+		else {
+			ws::Message::from_dataframes_unvalidated(dataframes)
+		}
+	}
+}
+
+/// An iterator over incoming text and binary messages. See `Client::data_messages()`.
+pub struct DataMessages<'a, S: 'a, R: 'a> {
+	client: &'a mut Client<DataFrame, S, R>,
+	finished: bool,
+}
+
+impl<'a, S: ws::Sender, R: ws::Receiver<DataFrame>> Iterator for DataMessages<'a, S, R> {
+	type Item = WebSocketResult<Message<'static>>;
+
+	fn next(&mut self) -> Option<WebSocketResult<Message<'static>>> {
+		while !self.finished {
+			let message = match self.client.recv_owned_message() {
+				Ok(message) => message,
+				Err(e) => {
+					self.finished = true;
+					return Some(Err(e));
+				}
+			};
+			let result = match message.opcode {
+				Type::Text | Type::Binary => return Some(Ok(message)),
+				Type::Ping if !self.client.auto_pong => {
+					self.client.send_message(&Message::pong(message.payload))
+				}
+				Type::Ping | Type::Pong => Ok(()),
+				Type::Close => {
+					self.finished = true;
+					let reply = match message.cd_status_code {
+						Some(code) => Message::close_because(code, ""),
+						None => Message::close(),
+					};
+					self.client.send_message(&reply)
+				}
+			};
+			if let Err(e) = result {
+				self.finished = true;
+				return Some(Err(e));
+			}
+		}
+		None
+	}
 }
 
 impl<F: DataFrameable, S: ws::Sender, R: ws::Receiver<F>> Client<F, S, R> {
@@ -123,22 +770,327 @@ impl<F: DataFrameable, S: ws::Sender, R: ws::Receiver<F>> Client<F, S, R> {
 		Client {
 			sender: sender,
 			receiver: receiver,
+			unanswered_pings: 0,
+			max_unanswered_pings: None,
+			auto_pong: false,
+			keepalive: None,
+			last_ping_at: None,
+			awaiting_pong_since: None,
+			last_pong_at: None,
+			close_sent: false,
+			close_received: None,
+			close_status: CloseStatus::NotClosed,
+			failure: None,
+			link: LinkTracker::new(),
+			real_peer_addr: None,
+			compression: None,
+			protocol: None,
+			response_headers: Headers::new(),
+			permit: None,
 			_dataframe: PhantomData
 		}
 	}
+	/// Returns the number of pings sent by this client since the last pong was received.
+	///
+	/// Only pings and pongs which pass through the `send_*` and `recv_*` methods of this
+	/// `Client` are counted; the `incoming_*` iterators and a split Sender and Receiver
+	/// are not tracked.
+	pub fn unanswered_pings(&self) -> usize {
+		self.unanswered_pings
+	}
+	/// Sets the maximum number of pings which may be awaiting a pong at once.
+	///
+	/// Once this many pings are unanswered, sending another ping fails with
+	/// `WebSocketError::UnansweredPings` instead, as the remote endpoint is most likely
+	/// no longer responding. Receiving any pong resets the count. `None` (the default)
+	/// disables the limit.
+	pub fn set_max_unanswered_pings(&mut self, max: Option<usize>) {
+		self.max_unanswered_pings = max;
+	}
+	/// Returns the maximum number of pings which may be awaiting a pong at once.
+	pub fn max_unanswered_pings(&self) -> Option<usize> {
+		self.max_unanswered_pings
+	}
+	/// Sets whether received pings are answered with a pong automatically.
+	///
+	/// When enabled, a pong carrying the same payload is sent as soon as a ping is
+	/// received by the `recv_*` methods of this `Client`, and the ping is still returned
+	/// to the caller. Pings longer than 125 bytes, which only a non-strict Receiver
+	/// accepts, are not answered. Messages are always sent whole, so the pong never
+	/// lands in the middle of a fragmented message sent by this `Client`. This is
+	/// disabled by default.
+	pub fn set_auto_pong(&mut self, auto_pong: bool) {
+		self.auto_pong = auto_pong;
+	}
+	/// Returns whether received pings are answered with a pong automatically.
+	pub fn auto_pong(&self) -> bool {
+		self.auto_pong
+	}
+	/// Sends a ping every `interval`, and considers the connection dead if a ping goes
+	/// unanswered for longer than `timeout`, or stops doing so if `interval` is None (the
+	/// default).
+	///
+	/// There is no background thread, so this is done by `poll_keepalive()`, which the
+	/// `recv_*` methods call before reading. A read timeout should be set on the
+	/// underlying stream so that they return regularly while the connection is idle.
+	pub fn set_keepalive(&mut self, interval: Option<Duration>, timeout: Duration) {
+		self.keepalive = interval.map(|interval| (interval, timeout));
+	}
+	/// Sends a ping if one is due and no close message has been sent, and fails with
+	/// `WebSocketError::UnansweredPings` if a ping has gone unanswered for longer than
+	/// the timeout given to `set_keepalive()`, in which case the connection is marked as
+	/// abnormally closed.
+	pub fn poll_keepalive(&mut self) -> WebSocketResult<()> {
+		let (interval, timeout) = match self.keepalive {
+			Some(keepalive) => keepalive,
+			None => return Ok(()),
+		};
+		let now = Instant::now();
+		if let Some(since) = self.awaiting_pong_since {
+			if now.duration_since(since) >= timeout {
+				let error = WebSocketError::UnansweredPings;
+				if self.close_status == CloseStatus::NotClosed {
+					self.close_status = CloseStatus::AbnormalClose(error.to_string());
+				}
+				return Err(error);
+			}
+		}
+		match self.last_ping_at {
+			_ if self.close_sent => Ok(()),
+			Some(last) if now.duration_since(last) < interval => Ok(()),
+			_ => self.send_message(&Message::ping(Vec::new())),
+		}
+	}
+	/// Returns when the most recent pong was received, if any.
+	///
+	/// Like `unanswered_pings()`, this only sees pongs received by this `Client`.
+	pub fn last_pong_at(&self) -> Option<Instant> {
+		self.last_pong_at
+	}
+	/// Returns the real address of the remote endpoint, if it has been determined.
+	///
+	/// `Server::serve()` sets this using its trusted proxies, so that it is the address
+	/// of the client rather than of a reverse proxy in front of the server.
+	pub fn real_peer_addr(&self) -> Option<IpAddr> {
+		self.real_peer_addr
+	}
+	/// Sets the real address of the remote endpoint.
+	pub fn set_real_peer_addr(&mut self, addr: Option<IpAddr>) {
+		self.real_peer_addr = addr;
+	}
+	/// Returns the permessage-deflate parameters negotiated during the handshake, or None
+	/// if messages are not being compressed.
+	pub fn compression(&self) -> Option<&DeflateParams> {
+		self.compression.as_ref()
+	}
+	/// Records the permessage-deflate parameters in use.
+	///
+	/// This does not set up compression itself, which is done by middleware on the Sender
+	/// and Receiver (see `DeflateParams::middleware()`). `Response::begin()` and the
+	/// server's `Response::send()` do both.
+	pub fn set_compression(&mut self, params: Option<DeflateParams>) {
+		self.compression = params;
+	}
+	/// Returns the subprotocol agreed during the handshake, if any.
+	pub fn protocol(&self) -> Option<&str> {
+		self.protocol.as_ref().map(|protocol| &protocol[..])
+	}
+	/// Records the subprotocol in use. `Response::begin()` and the server's
+	/// `Response::send()` set this from the `Sec-WebSocket-Protocol` header.
+	pub fn set_protocol(&mut self, protocol: Option<String>) {
+		self.protocol = protocol;
+	}
+	/// Returns the headers of the handshake response.
+	///
+	/// For a client these are the headers the server sent with its 101 response, and for
+	/// a server they are the headers it sent. Empty if the Client was not made by a
+	/// handshake.
+	pub fn response_headers(&self) -> &Headers {
+		&self.response_headers
+	}
+	/// Records the headers of the handshake response. `Response::begin()` and the
+	/// server's `Response::send()` set this.
+	pub fn set_response_headers(&mut self, headers: Headers) {
+		self.response_headers = headers;
+	}
+	/// Sets the permit counting this connection against a Server's connection limit,
+	/// which is released when the Client is dropped. `server::Response::send()` sets it.
+	pub fn set_permit(&mut self, permit: Option<ConnectionPermit>) {
+		self.permit = permit;
+	}
+	/// Takes the permit counting this connection against a Server's connection limit.
+	///
+	/// Splitting the Client or dropping it releases the permit, so it should be taken
+	/// first and kept with the halves if they outlive the Client.
+	pub fn take_permit(&mut self) -> Option<ConnectionPermit> {
+		self.permit.take()
+	}
+	/// Sends a ping carrying an increasing sequence number, returning the sequence number.
+	///
+	/// Pongs answering these pings are used to compute the statistics returned by
+	/// `link_stats()`.
+	pub fn send_sequenced_ping(&mut self) -> WebSocketResult<u64> {
+		let (sequence, payload) = self.link.next_payload();
+		try!(self.send_message(&Message::ping(payload)));
+		self.link.sent();
+		Ok(sequence)
+	}
+	/// Returns link quality statistics for the pings sent by `send_sequenced_ping()`.
+	///
+	/// Only pongs which pass through the `recv_*` methods of this `Client` are counted.
+	pub fn link_stats(&self) -> LinkStats {
+		self.link.stats()
+	}
+	/// Returns whether and how the connection has been closed.
+	///
+	/// This is updated as close messages and errors pass through the `send_*` and
+	/// `recv_*` methods of this `Client`, so the `incoming_*` iterators and a split
+	/// Sender and Receiver are not tracked.
+	pub fn close_status(&self) -> &CloseStatus {
+		&self.close_status
+	}
+	fn observe_close_sent(&mut self) {
+		self.close_sent = true;
+		self.update_close_status();
+	}
+	fn observe_received(&mut self, dataframe: &F) {
+		if dataframe.opcode() == Opcode::Pong as u8 {
+			self.unanswered_pings = 0;
+			self.awaiting_pong_since = None;
+			self.last_pong_at = Some(Instant::now());
+			self.link.pong(&dataframe.payload());
+		}
+		else if dataframe.opcode() == Opcode::Close as u8 && self.close_received.is_none() {
+			self.close_received = Some(CloseData::from_payload(&dataframe.payload()));
+			self.update_close_status();
+		}
+	}
+	fn observe_pings_sent(&mut self, pings: usize) {
+		if pings > 0 {
+			let now = Instant::now();
+			self.unanswered_pings += pings;
+			self.last_ping_at = Some(now);
+			if self.awaiting_pong_since.is_none() {
+				self.awaiting_pong_since = Some(now);
+			}
+		}
+	}
+	fn answer_ping(&mut self, dataframe: &F) -> WebSocketResult<()> {
+		if !self.auto_pong || dataframe.opcode() != Opcode::Ping as u8 {
+			return Ok(());
+		}
+		let payload = dataframe.payload();
+		if payload.len() > 125 {
+			return Ok(());
+		}
+		self.send_message(&Message::pong(payload.into_owned()))
+	}
+	fn observe_error(&mut self, error: &WebSocketError) {
+		if self.close_status == CloseStatus::NotClosed && !error.is_recoverable() {
+			self.close_status = CloseStatus::AbnormalClose(error.to_string());
+		}
+		// Some errors must fail the connection with a close message
+		let code = match error.close_code() {
+			Some(code) => code,
+			None => return,
+		};
+		if !self.close_sent {
+			let reason = match *error {
+				WebSocketError::DataFrameError(message) | WebSocketError::ProtocolError(message) => message,
+				_ => "Invalid UTF-8",
+			};
+			let _ = self.send_message(&Message::close_because(code, reason));
+		}
+		// The connection has failed, so nothing more is read from it
+		if let WebSocketError::DataFrameError(message) = *error {
+			self.failure = Some(message);
+		}
+	}
+	fn check_failure(&self) -> WebSocketResult<()> {
+		match self.failure {
+			Some(message) => Err(WebSocketError::DataFrameError(message)),
+			None => Ok(()),
+		}
+	}
+	fn update_close_status(&mut self) {
+		if self.close_sent && self.close_status == CloseStatus::NotClosed {
+			if let Some(ref data) = self.close_received {
+				self.close_status = CloseStatus::CleanClose(data.clone());
+			}
+		}
+	}
+	fn recv_message_dataframes(&mut self) -> WebSocketResult<Vec<F>> {
+		try!(self.check_failure());
+		try!(self.poll_keepalive());
+		match self.receiver.recv_message_dataframes() {
+			Ok(dataframes) => {
+				for dataframe in dataframes.iter() {
+					self.observe_received(dataframe);
+					try!(self.answer_ping(dataframe));
+				}
+				Ok(dataframes)
+			}
+			Err(e) => {
+				self.observe_error(&e);
+				Err(e)
+			}
+		}
+	}
+	fn check_pings(&self, pings: usize) -> WebSocketResult<()> {
+		match self.max_unanswered_pings {
+			Some(max) if pings > 0 && self.unanswered_pings + pings > max => {
+				Err(WebSocketError::UnansweredPings)
+			}
+			_ => Ok(()),
+		}
+	}
 	/// Sends a single data frame to the remote endpoint.
 	pub fn send_dataframe<D>(&mut self, dataframe: &D) -> WebSocketResult<()>
 	where D: DataFrameable {
-		self.sender.send_dataframe(dataframe)
+		let pings = if dataframe.opcode() == Opcode::Ping as u8 { 1 } else { 0 };
+		try!(self.check_pings(pings));
+		try!(self.sender.send_dataframe(dataframe));
+		self.observe_pings_sent(pings);
+		if dataframe.opcode() == Opcode::Close as u8 {
+			self.observe_close_sent();
+		}
+		Ok(())
 	}
 	/// Sends a single message to the remote endpoint.
 	pub fn send_message<'m, M, D>(&mut self, message: &'m M) -> WebSocketResult<()>
 	where M: ws::Message<'m, D>, D: DataFrameable {
-		self.sender.send_message(message)
+		let pings = message.dataframes()
+			.filter(|dataframe| dataframe.opcode() == Opcode::Ping as u8)
+			.count();
+		let close = message.dataframes()
+			.any(|dataframe| dataframe.opcode() == Opcode::Close as u8);
+		try!(self.check_pings(pings));
+		try!(self.sender.send_message(message));
+		self.observe_pings_sent(pings);
+		if close {
+			self.observe_close_sent();
+		}
+		Ok(())
 	}
 	/// Reads a single data frame from the remote endpoint.
+	///
+	/// Once the connection has been failed with a close message because of an invalid
+	/// data frame, such as one which is masked when it should not be, this and the other
+	/// `recv_*` methods return the same error without reading anything more.
 	pub fn recv_dataframe(&mut self) -> WebSocketResult<F> {
-		self.receiver.recv_dataframe()
+		try!(self.check_failure());
+		try!(self.poll_keepalive());
+		match self.receiver.recv_dataframe() {
+			Ok(dataframe) => {
+				self.observe_received(&dataframe);
+				try!(self.answer_ping(&dataframe));
+				Ok(dataframe)
+			}
+			Err(e) => {
+				self.observe_error(&e);
+				Err(e)
+			}
+		}
 	}
 	/// Returns an iterator over incoming data frames.
 	pub fn incoming_dataframes<'a>(&'a mut self) -> DataFrameIterator<'a, R, F> {
@@ -147,7 +1099,27 @@ impl<F: DataFrameable, S: ws::Sender, R: ws::Receiver<F>> Client<F, S, R> {
 	/// Reads a single message from this receiver.
 	pub fn recv_message<'m, M, I>(&mut self) -> WebSocketResult<M>
 	where M: ws::Message<'m, F, DataFrameIterator = I>, I: Iterator<Item = F> {
-		self.receiver.recv_message()
+		let dataframes = try!(self.recv_message_dataframes());
+		if self.receiver.validates_utf8() {
+			ws::Message::from_dataframes(dataframes)
+		}
+		else {
+			ws::Message::from_dataframes_unvalidated(dataframes)
+		}
+	}
+	/// Reads a single message from this receiver, along with information about how
+	/// it was received.
+	pub fn recv_message_with_meta<'m, M, I>(&mut self) -> WebSocketResult<(M, MessageMeta)>
+	where M: ws::Message<'m, F, DataFrameIterator = I>, I: Iterator<Item = F> {
+		let dataframes = try!(self.recv_message_dataframes());
+		let meta = self.receiver.message_meta(&dataframes);
+		let message = if self.receiver.validates_utf8() {
+			try!(ws::Message::from_dataframes(dataframes))
+		}
+		else {
+			try!(ws::Message::from_dataframes_unvalidated(dataframes))
+		};
+		Ok((message, meta))
 	}
 	/// Returns an iterator over incoming messages.
 	///
@@ -219,6 +1191,14 @@ impl<F: DataFrameable, S: ws::Sender, R: ws::Receiver<F>> Client<F, S, R> {
 	///
 	/// This allows the Sender and Receiver to be sent to different threads.
 	///
+	/// For a connected Client, each half owns its own handle to the connection. For TCP
+	/// this is a duplicate of the socket, so the Sender can write while the Receiver is
+	/// blocked reading, without either waiting for the other. Dropping one half does not
+	/// close the connection, which stays open until both are dropped. To stop a thread
+	/// blocked in the Receiver, call `shutdown_all()` on the Sender. For SSL, both halves
+	/// use the same OpenSSL session, which OpenSSL does not support using from two threads
+	/// at once; `split_shared()` serializes sends, but receiving still needs care.
+	///
 	///```no_run
 	///# extern crate websocket;
 	///# fn main() {
@@ -249,3 +1229,541 @@ impl<F: DataFrameable, S: ws::Sender, R: ws::Receiver<F>> Client<F, S, R> {
 		(self.sender, self.receiver)
 	}
 }
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use hyper::buffer::BufReader;
+	use message::Message;
+	use result::{WebSocketError, CONTROL_FRAME_TOO_LONG, FRAGMENTED_CONTROL_FRAME};
+	use std::net::TcpListener;
+	use url::Url;
+
+	#[test]
+	fn test_connect_with_fallback() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let url = Url::parse("ws://unresolvable.invalid/").unwrap();
+		let request = Client::connect_with_fallback(&url, &[addr]).unwrap();
+		assert_eq!(request.get_reader().get_ref().peer_addr().unwrap(), addr);
+		assert!(Client::connect(&url).is_err());
+	}
+
+	#[test]
+	fn test_close_status() {
+		let data = [0x88, 0x04, 0x03, 0xE8, b'o', b'k']; // Close 1000 "ok"
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		assert_eq!(*client.close_status(), CloseStatus::NotClosed);
+		client.recv_dataframe().unwrap();
+		assert_eq!(*client.close_status(), CloseStatus::NotClosed);
+		client.send_message(&Message::close()).unwrap();
+		assert_eq!(*client.close_status(), CloseStatus::CleanClose(Some(CloseData {
+			status_code: 1000,
+			reason: "ok".to_string(),
+		})));
+		// Errors after a clean close do not change the status
+		assert!(client.recv_dataframe().is_err());
+		assert!(match *client.close_status() { CloseStatus::CleanClose(_) => true, _ => false });
+
+		let data = [0x81]; // Connection lost mid-frame
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		assert!(client.recv_dataframe().is_err());
+		assert!(match *client.close_status() { CloseStatus::AbnormalClose(_) => true, _ => false });
+	}
+
+	#[test]
+	fn test_recv_data_message() {
+		// Ping, text, then close 1000 "bye"
+		let data = [0x89, 0x00, 0x81, 0x02, b'h', b'i', 0x88, 0x05, 0x03, 0xE8, b'b', b'y', b'e'];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		assert_eq!(client.recv_data_message(), Ok(Message::text("hi")));
+		let closed = || WebSocketError::ConnectionClosed {
+			clean: true,
+			code: Some(1000),
+			reason: Some("bye".to_string()),
+		};
+		assert_eq!(client.recv_data_message(), Err(closed()));
+		assert_eq!(client.recv_data_message(), Err(closed()));
+		assert_eq!(&client.get_sender().get_ref()[..], &[0x8A, 0x00, 0x88, 0x02, 0x03, 0xE8][..]);
+
+		// A close message without a status code
+		let data = [0x88, 0x00];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		assert_eq!(client.recv_data_message(), Err(WebSocketError::ConnectionClosed {
+			clean: true,
+			code: None,
+			reason: None,
+		}));
+
+		// The connection ends part way through a data frame
+		let data = [0x81, 0x05, b'h'];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		assert_eq!(client.recv_data_message(), Err(WebSocketError::ConnectionClosed {
+			clean: false,
+			code: None,
+			reason: None,
+		}));
+	}
+
+	#[test]
+	fn test_invalid_utf8_closes() {
+		let data = [0x01, 0x02, b'a', 0xFF]; // The rest of the message never arrives
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		match client.recv_until(|_| true, None) {
+			Err(WebSocketError::Utf8Error(_)) => (),
+			other => panic!("Expected a UTF-8 error but got {:?}", other),
+		}
+		assert_eq!(&client.get_sender().get_ref()[..4], &[0x88, 0x0F, 0x03, 0xEF]);
+	}
+
+	#[test]
+	fn test_invalid_control_frames_close() {
+		// A ping claiming a 200 byte payload, which never arrives
+		let oversized = [0x89, 0x7E, 0x00, 0xC8];
+		let fragmented = [0x09, 0x01, b'a'];
+		let cases = [(&oversized[..], CONTROL_FRAME_TOO_LONG), (&fragmented[..], FRAGMENTED_CONTROL_FRAME)];
+		for &(data, reason) in cases.iter() {
+			let mut client = Client::new(
+				Sender::new(Vec::new(), false),
+				Receiver::new(BufReader::new(data), false)
+			);
+			match client.recv_until(|_| true, None) {
+				Err(WebSocketError::DataFrameError(_)) | Err(WebSocketError::ProtocolError(_)) => (),
+				other => panic!("Expected the control frame to be rejected but got {:?}", other),
+			}
+			assert_eq!(&client.get_sender().get_ref()[..4], &[0x88, 2 + reason.len() as u8, 0x03, 0xEA]);
+		}
+	}
+
+	#[test]
+	fn test_unmasked_frame_to_server() {
+		// A server's Receiver expects masked data frames, and its Sender does not mask
+		let data = [0x81, 0x02, b'h', b'i', 0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), true)
+		);
+		match client.recv_until(|_| true, None) {
+			Err(WebSocketError::DataFrameError("Expected masked data frame")) => (),
+			other => panic!("Expected the unmasked frame to be rejected but got {:?}", other),
+		}
+		assert_eq!(&client.get_sender().get_ref()[..4], &[0x88, 0x1C, 0x03, 0xEA]);
+		// The connection has failed, so the next message is not received
+		match client.recv_until(|_| true, None) {
+			Err(WebSocketError::DataFrameError("Expected masked data frame")) => (),
+			other => panic!("Expected the connection to have failed but got {:?}", other),
+		}
+		match *client.close_status() {
+			CloseStatus::AbnormalClose(_) => (),
+			ref other => panic!("Expected an abnormal close but got {:?}", other),
+		}
+
+		// And a client's Receiver rejects masked data frames
+		let data = [0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), true),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		match client.recv_until(|_| true, None) {
+			Err(WebSocketError::DataFrameError("Expected unmasked data frame")) => (),
+			other => panic!("Expected the masked frame to be rejected but got {:?}", other),
+		}
+		assert_eq!(client.get_sender().get_ref()[1], 0x80 | 0x1E);
+	}
+
+	#[test]
+	fn test_close_code() {
+		use message::CloseCode;
+		for code in 0..5000u16 {
+			assert_eq!(u16::from(CloseCode::from(code)), code);
+		}
+		assert_eq!(CloseCode::from(1009), CloseCode::TooBig);
+		assert_eq!(CloseCode::from(4000), CloseCode::Other(4000));
+		let data = CloseData::with_code(CloseCode::PolicyViolation, "no");
+		assert_eq!(data.status_code, 1008);
+		assert_eq!(data.code(), CloseCode::PolicyViolation);
+	}
+
+	#[test]
+	fn test_close_data_parse() {
+		let message = CloseData::with_code(CloseCode::GoingAway, "bye").into_message();
+		assert_eq!(CloseData::parse(&DataFrameable::payload(&message)).unwrap(), Some(CloseData::new(1001, "bye")));
+		assert_eq!(CloseData::parse(&[]).unwrap(), None);
+		assert_eq!(CloseData::parse(&[0x0f, 0xa0]).unwrap(), Some(CloseData::new(4000, "")));
+		for payload in &[&[0x03][..], &[0x03, 0xed], &[0x03, 0xee], &[0x03, 0xf7], &[0x13, 0x88]] {
+			match CloseData::parse(payload) {
+				Err(WebSocketError::ProtocolError(_)) => (),
+				other => panic!("Expected a protocol error for {:?} but got {:?}", payload, other),
+			}
+		}
+		match CloseData::parse(&[0x03, 0xe8, 0xff]) {
+			Err(WebSocketError::Utf8Error(_)) => (),
+			other => panic!("Expected a UTF-8 error but got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_try_again_later() {
+		let mut sender = Sender::new(Vec::new(), false);
+		ws::Sender::send_message(&mut sender, &CloseData::try_again_later("30").into_message()).unwrap();
+
+		let data = sender.get_ref().clone();
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		client.recv_dataframe().unwrap();
+		client.send_message(&Message::close()).unwrap();
+		match *client.close_status() {
+			CloseStatus::CleanClose(Some(ref data)) => assert!(data.is_try_again_later()),
+			ref other => panic!("Expected a clean close but got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn test_data_messages() {
+		let data = [
+			0x89, 0x01, b'p', // Ping
+			0x81, 0x01, b'a', // Text
+			0x8A, 0x00, // Pong
+			0x82, 0x01, 0x01, // Binary
+			0x88, 0x02, 0x03, 0xE8, // Close 1000
+			0x81, 0x01, b'b', // Text after close
+		];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		let messages: Vec<_> = client.data_messages().map(|message| message.unwrap()).collect();
+		assert_eq!(messages, vec![Message::text("a"), Message::binary(vec![1])]);
+		assert_eq!(&client.get_sender().get_ref()[..], &[0x8A, 0x01, b'p', 0x88, 0x02, 0x03, 0xE8][..]);
+	}
+
+	#[test]
+	fn test_max_unanswered_pings() {
+		let data = [0x8A, 0x00]; // Empty pong
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		client.set_max_unanswered_pings(Some(2));
+
+		client.send_message(&Message::ping(b"1".to_vec())).unwrap();
+		client.send_message(&Message::ping(b"2".to_vec())).unwrap();
+		assert_eq!(client.unanswered_pings(), 2);
+		match client.send_message(&Message::ping(b"3".to_vec())) {
+			Err(WebSocketError::UnansweredPings) => (),
+			other => panic!("Expected UnansweredPings but got {:?}", other),
+		}
+
+		client.recv_dataframe().unwrap();
+		assert_eq!(client.unanswered_pings(), 0);
+		client.send_message(&Message::ping(b"3".to_vec())).unwrap();
+	}
+
+	#[test]
+	fn test_auto_pong() {
+		let data = [
+			0x89, 0x01, b'p', // Ping
+			0x81, 0x01, b'a',
+		];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		client.set_auto_pong(true);
+		assert_eq!(client.recv_until(|m| m.opcode == Type::Text, None).unwrap(), Message::text("a"));
+		// The ping was answered once, by the Client rather than recv_until()
+		assert_eq!(client.get_sender().get_ref(), &vec![0x8A, 0x01, b'p']);
+	}
+
+	#[test]
+	fn test_keepalive() {
+		let data = [0x8A, 0x00]; // Empty pong
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		client.set_keepalive(Some(Duration::from_secs(3600)), Duration::from_secs(3600));
+		client.poll_keepalive().unwrap();
+		client.poll_keepalive().unwrap();
+		// Only one ping is sent per interval
+		assert_eq!(client.get_sender().get_ref(), &vec![0x89, 0x00]);
+		assert_eq!(client.last_pong_at(), None);
+		client.recv_dataframe().unwrap();
+		assert!(client.last_pong_at().is_some());
+		assert_eq!(client.unanswered_pings(), 0);
+
+		client.set_keepalive(Some(Duration::from_secs(0)), Duration::from_secs(0));
+		client.poll_keepalive().unwrap();
+		match client.recv_dataframe() {
+			Err(WebSocketError::UnansweredPings) => (),
+			other => panic!("Expected UnansweredPings but got {:?}", other),
+		}
+		match *client.close_status() {
+			CloseStatus::AbnormalClose(_) => (),
+			ref other => panic!("Expected an abnormal close but got {:?}", other),
+		}
+
+		// No pings are sent once closing
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		client.set_keepalive(Some(Duration::from_secs(0)), Duration::from_secs(3600));
+		client.send_message(&Message::close()).unwrap();
+		client.poll_keepalive().unwrap();
+		assert_eq!(client.get_sender().get_ref(), &vec![0x88, 0x00]);
+	}
+
+	#[test]
+	fn test_run_echo() {
+		let data = [
+			0x81, 0x02, b'h', b'i', // Text
+			0x89, 0x01, b'p', // Ping
+			0x8A, 0x00, // Pong
+			0x88, 0x02, 0x03, 0xE8, // Close (1000)
+			0x81, 0x01, b'x', // Text after close
+		];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		client.run_echo().unwrap();
+
+		let expected = [
+			0x81, 0x02, b'h', b'i',
+			0x8A, 0x01, b'p',
+			0x88, 0x02, 0x03, 0xE8,
+		];
+		assert_eq!(&client.get_sender().get_ref()[..], &expected[..]);
+	}
+
+	#[test]
+	fn test_recv_until() {
+		let data = [
+			0x81, 0x01, b'a', // Text
+			0x89, 0x01, b'p', // Ping
+			0x81, 0x01, b'b', // Text
+			0x81, 0x01, b'c', // Text
+		];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		let mut discarded = Vec::new();
+		let message = client.recv_until_with(
+			|message| &*message.payload == b"b",
+			None,
+			|message| discarded.push(message)
+		).unwrap();
+
+		assert_eq!(message, Message::text("b"));
+		assert_eq!(discarded, vec![Message::text("a"), Message::ping(b"p".to_vec())]);
+		assert_eq!(&client.get_sender().get_ref()[..], &[0x8A, 0x01, b'p'][..]);
+	}
+
+	#[test]
+	fn test_split_concurrently() {
+		use std::io::{Read, Write};
+		use std::net::TcpStream;
+		use std::thread;
+		use stream::WebSocketStream;
+
+		fn assert_send<T: Send>(_: &T) {}
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let stream = WebSocketStream::Tcp(listener.accept().unwrap().0);
+		let client = Client::new(
+			Sender::new(stream.try_clone().unwrap(), false),
+			Receiver::new(BufReader::new(stream), true)
+		);
+		let (mut sender, mut receiver) = client.split();
+		assert_send(&sender);
+		assert_send(&receiver);
+
+		// The Receiver blocks until the peer answers what the Sender sends meanwhile
+		let thread = thread::spawn(move || {
+			let message = ws::Receiver::recv_message::<_, Message, _>(&mut receiver).unwrap();
+			(message, receiver)
+		});
+		ws::Sender::send_message(&mut sender, &Message::text("a")).unwrap();
+		let mut sent = [0; 3];
+		peer.read_exact(&mut sent).unwrap();
+		assert_eq!(sent, [0x81, 0x01, b'a']);
+		peer.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'b']).unwrap();
+		let (message, _receiver) = thread.join().unwrap();
+		assert_eq!(message, Message::text("b"));
+
+		// Dropping the Sender leaves the connection open for the Receiver
+		drop(sender);
+		let mut byte = [0];
+		peer.set_nonblocking(true).unwrap();
+		assert_eq!(peer.read(&mut byte).unwrap_err().kind(), ::std::io::ErrorKind::WouldBlock);
+	}
+
+	#[test]
+	fn test_connect_on() {
+		use std::net::TcpStream;
+		use std::thread;
+		use server;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let thread = thread::spawn(move || {
+			let stream = listener.accept().unwrap().0;
+			let request = server::Request::read(&stream, &stream).unwrap();
+			request.validate().unwrap();
+			let mut client = request.accept().send().unwrap();
+			client.run_echo().unwrap();
+		});
+
+		let stream = TcpStream::connect(addr).unwrap();
+		let url = Url::parse("ws://example.com/chat").unwrap();
+		let mut client = Client::connect_on(&url, &stream, &stream).unwrap();
+		client.send_message(&Message::text("hi")).unwrap();
+		assert_eq!(client.recv_until(|_| true, None).unwrap(), Message::text("hi"));
+		client.send_message(&Message::close()).unwrap();
+		thread.join().unwrap();
+	}
+
+	#[test]
+	fn test_connect_with_timeout() {
+		use std::thread;
+		use server;
+
+		// The server accepts the connection but never answers the request
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+		let start = Instant::now();
+		match Client::connect_with_timeout(&url, Duration::from_millis(100)) {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+		}
+		assert!(start.elapsed() < Duration::from_secs(5));
+		drop(listener);
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+		let thread = thread::spawn(move || {
+			let stream = listener.accept().unwrap().0;
+			let request = server::Request::read(&stream, &stream).unwrap();
+			let mut client = request.accept().send().unwrap();
+			client.run_echo().unwrap();
+		});
+		let options = TcpOptions { nodelay: true, keepalive: None };
+		let mut client = Client::connect_with_timeout_and_tcp_options(&url, Duration::from_secs(5), &options).unwrap();
+		assert_eq!(client.get_receiver().get_ref().get_ref().read_timeout().unwrap(), None);
+		assert_eq!(client.get_sender().get_ref().write_timeout().unwrap(), None);
+		assert!(client.get_sender().get_ref().tcp_ref().nodelay().unwrap());
+		client.send_message(&Message::close()).unwrap();
+		thread.join().unwrap();
+	}
+
+	#[test]
+	fn test_incoming_messages_timeout() {
+		use std::io::Write;
+		use std::sync::mpsc;
+		use std::thread;
+		use server;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+		let (tx, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			let stream = listener.accept().unwrap().0;
+			let request = server::Request::read(&stream, &stream).unwrap();
+			let mut client = request.accept().send().unwrap();
+			// Half of a text frame, then the rest once the client has timed out
+			(&stream).write_all(&[0x81, 0x05, b'H', b'e']).unwrap();
+			rx.recv().unwrap();
+			(&stream).write_all(b"llo").unwrap();
+			client.run_echo().unwrap();
+		});
+		let mut client = Client::connect_with_timeout(&url, Duration::from_secs(5)).unwrap();
+		{
+			let mut messages = client.incoming_messages_timeout(Duration::from_millis(50)).unwrap();
+			assert_eq!(messages.next().unwrap(), Err(WebSocketError::Timeout));
+			tx.send(()).unwrap();
+			assert_eq!(messages.next().unwrap(), Ok(Message::text("Hello")));
+			assert_eq!(messages.next().unwrap(), Err(WebSocketError::Timeout));
+		}
+		assert_eq!(client.get_receiver().get_ref().get_ref().read_timeout().unwrap(), None);
+		client.send_message(&Message::close()).unwrap();
+		thread.join().unwrap();
+	}
+
+	#[test]
+	fn test_close() {
+		use std::sync::mpsc;
+		use std::thread;
+		use server;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+		let (tx, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			// The first connection sends more messages before acknowledging the close
+			let stream = listener.accept().unwrap().0;
+			let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+			let message = client.recv_until(|_| true, None).unwrap();
+			assert_eq!(message, Message::close_because(1000, "bye"));
+			client.send_message(&Message::text("late")).unwrap();
+			client.send_message(&Message::ping(b"p".to_vec())).unwrap();
+			let pong = client.recv_until(|_| true, None).unwrap();
+			assert_eq!(pong, Message::pong(b"p".to_vec()));
+			client.send_message(&Message::close_because(1000, "")).unwrap();
+			assert!(client.recv_until(|_| true, None).is_err());
+
+			// The second never does
+			let stream = listener.accept().unwrap().0;
+			let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+			client.recv_until(|_| true, None).unwrap();
+			rx.recv().unwrap();
+
+			// The third closes first
+			let stream = listener.accept().unwrap().0;
+			let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+			client.send_message(&Message::close_because(1001, "")).unwrap();
+			let message = client.recv_until(|_| true, None).unwrap();
+			assert_eq!(message, Message::close_because(1000, "bye"));
+		});
+
+		let client = Client::connect_with_timeout(&url, Duration::from_secs(5)).unwrap();
+		let status = client.close(1000, "bye", Duration::from_secs(5)).unwrap();
+		assert_eq!(status, CloseStatus::CleanClose(Some(CloseData::new(1000, ""))));
+
+		let client = Client::connect_with_timeout(&url, Duration::from_secs(5)).unwrap();
+		let status = client.close(1001, "", Duration::from_millis(50)).unwrap();
+		assert_eq!(status, CloseStatus::TimedOut);
+		tx.send(()).unwrap();
+
+		// The reply is flushed even though sending is coalesced
+		let mut client = Client::connect_with_timeout(&url, Duration::from_secs(5)).unwrap();
+		assert_eq!(client.recv_until(|_| false, None).unwrap(), Message::close_because(1001, ""));
+		client.get_mut_sender().set_coalesce(Some((Duration::from_secs(10), 1 << 16))).unwrap();
+		let status = client.close(1000, "bye", Duration::from_secs(5)).unwrap();
+		assert_eq!(status, CloseStatus::CleanClose(Some(CloseData::new(1001, ""))));
+		thread.join().unwrap();
+	}
+}
@@ -1,21 +1,37 @@
 //! Structs for client-side (outbound) WebSocket requests
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
 
 pub use url::Url;
 
 use hyper::version::HttpVersion;
 use hyper::buffer::BufReader;
-use hyper::header::Headers;
+use hyper::header::{Headers, Host, UserAgent};
 use hyper::header::{Connection, ConnectionOption};
 use hyper::header::{Upgrade, Protocol, ProtocolName};
 
 use unicase::UniCase;
 
 use header::{WebSocketKey, WebSocketVersion, WebSocketProtocol, WebSocketExtensions, Origin};
-use result::WebSocketResult;
+use result::{WebSocketResult, WebSocketError};
+use client::{Client, Sender, Receiver, remaining_until, timed_out};
 use client::response::Response;
+use dataframe::DataFrame;
+use deflate::DeflateParams;
+use stream::WebSocketStream;
+use ws::dataframe::DataFrame as DataFrameable;
+use ws;
 use ws::util::url::ToWebSocketUrlComponents;
 
+// Headers which custom_headers() leaves alone.
+const RESERVED_HEADERS: [&'static str; 5] = [
+	"Host",
+	"Connection",
+	"Upgrade",
+	"Sec-WebSocket-Key",
+	"Sec-WebSocket-Version",
+];
+
 /// Represents a WebSocket request.
 ///
 /// Note that nothing is written to the internal Writer until the `send()` method is called.
@@ -51,6 +67,7 @@ impl<R: Read, W: Write> Request<R, W> {
 		}]));
 		headers.set(WebSocketVersion::WebSocket13);
 		headers.set(WebSocketKey::new());
+		headers.set(UserAgent(concat!("rust-websocket/", env!("CARGO_PKG_VERSION")).to_string()));
 		
 		Ok(Request {
 			version: HttpVersion::Http11,
@@ -80,6 +97,20 @@ impl<R: Read, W: Write> Request<R, W> {
 	pub fn origin(&self) -> Option<&Origin> {
 		self.headers.get()
 	}
+	/// Short-cut to obtain the Host value.
+	pub fn host(&self) -> Option<&Host> {
+		self.headers.get()
+	}
+	/// Short-cut to obtain the UserAgent value.
+	///
+	/// This defaults to `rust-websocket/` followed by the version of this crate.
+	pub fn user_agent(&self) -> Option<&UserAgent> {
+		self.headers.get()
+	}
+	/// Sets the User-Agent header sent with this request.
+	pub fn set_user_agent<S: Into<String>>(&mut self, user_agent: S) {
+		self.headers.set(UserAgent(user_agent.into()));
+	}
 	/// Short-cut to obtain a mutable reference to the WebSocketKey value.
 	///
 	/// Note that to add a header that does not already exist, ```Request.headers.set()```
@@ -115,6 +146,36 @@ impl<R: Read, W: Write> Request<R, W> {
 	pub fn origin_mut(&mut self) -> Option<&mut Origin> {
 		self.headers.get_mut()
 	}
+	/// Offers the permessage-deflate extension with each set of parameters in turn, in
+	/// order of preference, after any extensions which are already offered.
+	///
+	/// If the server accepts it, `Response::begin()` sets up compression on the Client.
+	pub fn offer_deflate(&mut self, alternatives: &[DeflateParams]) {
+		if self.extensions().is_none() {
+			self.headers.set(WebSocketExtensions(Vec::new()));
+		}
+		if let Some(extensions) = self.extensions_mut() {
+			extensions.0.extend(DeflateParams::offer(alternatives).0);
+		}
+	}
+	/// Merges the given headers into this request, replacing any existing headers with
+	/// the same names.
+	///
+	/// Headers the handshake depends on (`Host`, `Connection`, `Upgrade`,
+	/// `Sec-WebSocket-Key` and `Sec-WebSocket-Version`) are skipped, so they cannot be
+	/// clobbered. This is useful for sending `Authorization` or application headers
+	/// which a gateway checks before the upgrade.
+	pub fn custom_headers(&mut self, headers: &Headers) {
+		for header in headers.iter() {
+			let name = header.name();
+			if RESERVED_HEADERS.iter().any(|reserved| reserved.eq_ignore_ascii_case(name)) {
+				continue;
+			}
+			if let Some(raw) = headers.get_raw(name) {
+				self.headers.set_raw(name.to_string(), raw.to_vec());
+			}
+		}
+	}
 	/// Returns a reference to the inner Reader.
 	pub fn get_reader(&self) -> &BufReader<R> {
 		&self.reader
@@ -136,9 +197,375 @@ impl<R: Read, W: Write> Request<R, W> {
 		(self.reader, self.writer)
 	}
 	/// Sends the request to the server and returns a response.
+	///
+	/// The request is written with a single write, then the Writer is flushed.
 	pub fn send(mut self) -> WebSocketResult<Response<R, W>> {
-		try!(write!(&mut self.writer, "GET {} {}\r\n", self.resource_name, self.version));
-		try!(write!(&mut self.writer, "{}\r\n", self.headers));
+		let data = self.head();
+		try!(self.writer.write_all(&data));
+		try!(self.writer.flush());
+		Response::read(self)
+	}
+	/// Sends the request immediately followed by the given message in a single write,
+	/// without waiting for the response, and returns the response.
+	///
+	/// This saves a round trip before the first message, but the message is sent before
+	/// it is known whether the server accepts the connection. If it does not, the message
+	/// will have been sent to a server which does not speak the WebSocket protocol, or
+	/// which may interpret it as the start of another HTTP request. Only use this with
+	/// servers which are known to accept the request, and with messages which are safe
+	/// to send to them regardless. The message does not pass through any middleware.
+	pub fn send_pipelined<'m, M, D>(mut self, message: &'m M) -> WebSocketResult<Response<R, W>>
+	where M: ws::Message<'m, D>, D: DataFrameable {
+		let mut data = self.head();
+		for ref dataframe in message.dataframes() {
+			try!(dataframe.write_to(&mut data, true));
+		}
+		try!(self.writer.write_all(&data));
+		try!(self.writer.flush());
 		Response::read(self)
 	}
+	/// Starts a handshake which can be driven from a readiness loop, for streams which
+	/// have been set to nonblocking mode.
+	///
+	/// Nothing is written until `HandshakeInProgress::advance()` is first called.
+	pub fn begin_handshake_nonblocking(self) -> HandshakeInProgress<R, W> {
+		let head = self.head();
+		HandshakeInProgress {
+			request: Some(self),
+			head: head,
+			written: 0,
+		}
+	}
+	fn head(&self) -> Vec<u8> {
+		format!("GET {} {}\r\n{}\r\n", self.resource_name, self.version, self.headers).into_bytes()
+	}
+}
+
+impl Request<WebSocketStream, WebSocketStream> {
+	/// Sends the request and reads the response like `send()`, failing with
+	/// `WebSocketError::Timeout` unless the whole response head arrives within `timeout`.
+	///
+	/// Unlike a read timeout, this limit cannot be extended by a server which trickles in
+	/// the response a few bytes at a time. The stream's read and write timeouts are put
+	/// back as they were afterwards. If the time runs out, the request and its stream
+	/// are dropped part way through the response, so any retry needs a new connection.
+	pub fn send_timeout(mut self, timeout: Duration) -> WebSocketResult<Response<WebSocketStream, WebSocketStream>> {
+		let deadline = Instant::now() + timeout;
+		let read_timeout = try!(self.writer.read_timeout());
+		let write_timeout = try!(self.writer.write_timeout());
+		let result = self.exchange_before(deadline);
+		try!(self.writer.set_read_timeout(read_timeout));
+		try!(self.writer.set_write_timeout(write_timeout));
+		try!(result.map_err(timed_out));
+		Response::read(self)
+	}
+	// Writes the request and buffers the response head, giving up at the deadline.
+	fn exchange_before(&mut self, deadline: Instant) -> WebSocketResult<()> {
+		let head = self.head();
+		let mut written = 0;
+		while written < head.len() {
+			try!(self.writer.set_write_timeout(Some(try!(remaining_until(deadline)))));
+			match self.writer.write(&head[written..]) {
+				Ok(0) => return Err(WebSocketError::IoError(io::Error::new(
+					io::ErrorKind::WriteZero, "Failed to write the whole request"
+				))),
+				Ok(n) => written += n,
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+				Err(e) => return Err(From::from(e)),
+			}
+		}
+		try!(self.writer.flush());
+		while !has_complete_head(self.reader.get_buf()) {
+			try!(self.writer.set_read_timeout(Some(try!(remaining_until(deadline)))));
+			match self.reader.read_into_buf() {
+				// Leave the parser to report the connection closing or the head being too large
+				Ok(0) => break,
+				Ok(_) => (),
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+				Err(e) => return Err(From::from(e)),
+			}
+		}
+		Ok(())
+	}
+}
+
+/// A handshake on a nonblocking stream. See `Request::begin_handshake_nonblocking()`.
+pub struct HandshakeInProgress<R: Read, W: Write> {
+	request: Option<Request<R, W>>,
+	head: Vec<u8>,
+	written: usize,
+}
+
+impl<R: Read, W: Write> HandshakeInProgress<R, W> {
+	/// Writes as much of the request and reads as much of the response as the stream
+	/// allows without blocking.
+	///
+	/// Returns an I/O error of kind `WouldBlock` if the stream is not ready, in which
+	/// case `advance()` should be called again once it is. Once the whole response has
+	/// arrived, it is validated and the Client returned, with the stream left in
+	/// nonblocking mode. Any other error ends the handshake, as does returning the
+	/// Client, and calling `advance()` after that fails.
+	pub fn advance(&mut self) -> WebSocketResult<Client<DataFrame, Sender<W>, Receiver<R>>> {
+		let result = self.poll();
+		match result {
+			Err(WebSocketError::IoError(ref e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+			_ => self.request = None,
+		}
+		let request = try!(result);
+		let response = try!(Response::read(request));
+		try!(response.validate());
+		Ok(response.begin())
+	}
+	/// Returns true once the whole request has been written.
+	pub fn is_request_sent(&self) -> bool {
+		self.written == self.head.len()
+	}
+	/// Returns true once `advance()` has returned the Client or an error other than
+	/// `WouldBlock`.
+	pub fn is_finished(&self) -> bool {
+		self.request.is_none()
+	}
+	// Returns the request once the head of the response is in its buffer.
+	fn poll(&mut self) -> WebSocketResult<Request<R, W>> {
+		{
+			let request = try!(self.request.as_mut().ok_or(
+				WebSocketError::RequestError("The handshake has already finished")
+			));
+			while self.written < self.head.len() {
+				match request.writer.write(&self.head[self.written..]) {
+					Ok(0) => return Err(WebSocketError::IoError(io::Error::new(
+						io::ErrorKind::WriteZero, "Failed to write the whole request"
+					))),
+					Ok(n) => self.written += n,
+					Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+					Err(e) => return Err(From::from(e)),
+				}
+			}
+			try!(request.writer.flush());
+			while !has_complete_head(request.reader.get_buf()) {
+				match request.reader.read_into_buf() {
+					Ok(0) if request.reader.get_buf().is_empty() => {
+						return Err(WebSocketError::IoError(io::Error::new(
+							io::ErrorKind::ConnectionAborted, "Connection closed"
+						)));
+					}
+					Ok(0) => return Err(WebSocketError::ResponseError("Incomplete or oversized response")),
+					Ok(_) => (),
+					Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+					Err(e) => return Err(From::from(e)),
+				}
+			}
+		}
+		Ok(self.request.take().unwrap())
+	}
+}
+
+fn has_complete_head(buf: &[u8]) -> bool {
+	buf.windows(4).any(|window| window == b"\r\n\r\n")
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use hyper::header::{Host, UserAgent};
+	use message::Message;
+	use std::io::{self, Write};
+
+	/// Records every write separately.
+	struct Writes(Vec<Vec<u8>>);
+
+	impl Write for Writes {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.push(buf.to_vec());
+			Ok(buf.len())
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_send_single_write() {
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let data = b"HTTP/1.1 101 Switching Protocols\r\n\r\n";
+
+		let request = Request::new((host.clone(), "/"), &data[..], Writes(Vec::new())).unwrap();
+		let response = request.send().unwrap();
+		assert_eq!(response.get_writer().0.len(), 1);
+		assert!(response.get_writer().0[0].ends_with(b"\r\n\r\n"));
+
+		let request = Request::new((host, "/"), &data[..], Writes(Vec::new())).unwrap();
+		let response = request.send_pipelined(&Message::text("hi")).unwrap();
+		let writes = &response.get_writer().0;
+		assert_eq!(writes.len(), 1);
+		let frame = &writes[0][writes[0].len() - 8..];
+		assert_eq!(&frame[..2], &[0x81, 0x82]);
+		assert_eq!(frame[6] ^ frame[2], b'h');
+	}
+
+	#[test]
+	fn test_user_agent() {
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let mut request = Request::new((host, "/"), &b""[..], Vec::new()).unwrap();
+		assert!(request.user_agent().unwrap().0.starts_with("rust-websocket/"));
+
+		request.set_user_agent("my-app/1.0");
+		assert_eq!(request.user_agent(), Some(&UserAgent("my-app/1.0".to_string())));
+	}
+
+	#[test]
+	fn test_custom_headers() {
+		use hyper::header::{Authorization, Bearer};
+
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let data = b"HTTP/1.1 101 Switching Protocols\r\n\r\n";
+		let mut request = Request::new((host, "/"), &data[..], Writes(Vec::new())).unwrap();
+		let key = request.key().unwrap().clone();
+
+		let mut headers = Headers::new();
+		headers.set(Authorization(Bearer { token: "s3cr3t".to_string() }));
+		headers.set_raw("X-App-Id", vec![b"42".to_vec()]);
+		headers.set(WebSocketKey::new());
+		headers.set_raw("upgrade", vec![b"h2c".to_vec()]);
+		request.custom_headers(&headers);
+
+		let response = request.send().unwrap();
+		let sent = String::from_utf8(response.get_writer().0[0].clone()).unwrap();
+		assert!(sent.contains("Authorization: Bearer s3cr3t\r\n"));
+		assert!(sent.contains("X-App-Id: 42\r\n"));
+		assert!(sent.contains("Upgrade: websocket\r\n"));
+		assert!(!sent.contains("h2c"));
+		assert_eq!(response.get_request().key(), Some(&key));
+	}
+
+	/// Returns each chunk in turn, with `WouldBlock` before each one.
+	struct Trickle(Vec<Vec<u8>>, bool);
+
+	impl io::Read for Trickle {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.1 = !self.1;
+			if self.1 {
+				return Err(io::Error::new(io::ErrorKind::WouldBlock, "not ready"));
+			}
+			if self.0.is_empty() {
+				return Ok(0);
+			}
+			let chunk = self.0.remove(0);
+			buf[..chunk.len()].copy_from_slice(&chunk);
+			Ok(chunk.len())
+		}
+	}
+
+	/// Accepts at most 16 bytes at a time, with `WouldBlock` before each write.
+	struct Choked(Vec<u8>, bool);
+
+	impl Write for Choked {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.1 = !self.1;
+			if self.1 {
+				return Err(io::Error::new(io::ErrorKind::WouldBlock, "not ready"));
+			}
+			let len = ::std::cmp::min(buf.len(), 16);
+			self.0.extend_from_slice(&buf[..len]);
+			Ok(len)
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_handshake_nonblocking() {
+		use std::str::FromStr;
+
+		let response: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+			Connection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n\x81\x02hi";
+		let chunks = response.chunks(10).map(|chunk| chunk.to_vec()).collect();
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let mut request = Request::new((host, "/"), Trickle(chunks, false), Choked(Vec::new(), false)).unwrap();
+		request.headers.set(WebSocketKey::from_str("dGhlIHNhbXBsZSBub25jZQ==").unwrap());
+		let head = request.head();
+
+		let mut handshake = request.begin_handshake_nonblocking();
+		let mut blocked = 0;
+		let mut client = loop {
+			match handshake.advance() {
+				Ok(client) => break client,
+				Err(WebSocketError::IoError(ref e)) if e.kind() == io::ErrorKind::WouldBlock => blocked += 1,
+				Err(e) => panic!("handshake failed: {:?}", e),
+			}
+		};
+		assert!(blocked > 10);
+		assert!(handshake.is_request_sent());
+		assert!(handshake.is_finished());
+		assert!(handshake.advance().is_err());
+		assert_eq!(client.get_sender().get_ref().0, head);
+
+		// The frame which arrived with the response is not lost
+		let message = loop {
+			match client.recv_until(|_| true, None) {
+				Err(WebSocketError::IoError(ref e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+				result => break result.unwrap(),
+			}
+		};
+		assert_eq!(message, Message::text("hi"));
+	}
+
+	#[test]
+	fn test_handshake_nonblocking_rejected() {
+		let response = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n".to_vec();
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let request = Request::new((host, "/"), Trickle(vec![response], false), Choked(Vec::new(), false)).unwrap();
+		let mut handshake = request.begin_handshake_nonblocking();
+		let result = loop {
+			match handshake.advance() {
+				Err(WebSocketError::IoError(ref e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+				result => break result,
+			}
+		};
+		assert!(result.is_err());
+		assert!(handshake.is_finished());
+	}
+
+	#[test]
+	fn test_send_timeout() {
+		use std::net::{TcpListener, TcpStream};
+		use std::thread;
+		use std::time::{Duration, Instant};
+		use stream::WebSocketStream;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let thread = thread::spawn(move || {
+			// The first server answers promptly
+			let mut stream = listener.accept().unwrap().0;
+			stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n").unwrap();
+
+			// The second trickles in its response, never quite timing out a single read
+			let mut stream = listener.accept().unwrap().0;
+			for &byte in b"HTTP/1.1 101 Switching Protocols\r\n\r\n" {
+				thread::sleep(Duration::from_millis(20));
+				if stream.write_all(&[byte]).is_err() {
+					break;
+				}
+			}
+		});
+
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let stream = WebSocketStream::Tcp(TcpStream::connect(addr).unwrap());
+		let request = Request::new((host.clone(), "/"), stream.try_clone().unwrap(), stream).unwrap();
+		let response = request.send_timeout(Duration::from_secs(5)).unwrap();
+		assert_eq!(response.get_writer().read_timeout().unwrap(), None);
+		assert_eq!(response.get_writer().write_timeout().unwrap(), None);
+
+		let stream = WebSocketStream::Tcp(TcpStream::connect(addr).unwrap());
+		let request = Request::new((host, "/"), stream.try_clone().unwrap(), stream).unwrap();
+		let start = Instant::now();
+		match request.send_timeout(Duration::from_millis(150)) {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+		}
+		assert!(start.elapsed() < Duration::from_millis(500));
+		thread.join().unwrap();
+	}
 }
@@ -2,22 +2,26 @@
 use std::option::Option;
 use std::io::{Read, Write};
 
+use flate2::read::{GzDecoder, ZlibDecoder};
+
 use hyper::status::StatusCode;
 use hyper::buffer::BufReader;
 use hyper::version::HttpVersion;
-use hyper::header::Headers;
+use hyper::header::{Headers, ContentLength, ContentEncoding, TransferEncoding, Encoding};
 use hyper::header::{Connection, ConnectionOption};
 use hyper::header::{Upgrade, Protocol, ProtocolName};
-use hyper::http::h1::parse_response;
+use hyper::http::h1::{parse_response, HttpReader};
 
 use unicase::UniCase;
 
-use header::{WebSocketAccept, WebSocketProtocol, WebSocketExtensions};
+use header::{WebSocketAccept, WebSocketProtocol, WebSocketExtensions, WebSocketVersion};
+use header::extensions::Extension;
 
 use client::{Client, Request, Sender, Receiver};
 use result::{WebSocketResult, WebSocketError};
 use dataframe::DataFrame;
 use ws::dataframe::DataFrame as DataFrameable;
+use deflate::{self, DeflateParams};
 use ws;
 
 /// Represents a WebSocket response.
@@ -29,6 +33,7 @@ pub struct Response<R: Read, W: Write> {
 	/// The HTTP version of this response
 	pub version: HttpVersion,
 
+	reason: String,
 	request: Request<R, W>
 }
 
@@ -39,19 +44,20 @@ impl<R: Read, W: Write> Response<R, W> {
 	///
 	/// This is called by Request.send(), and does not need to be called by the user.
 	pub fn read(mut request: Request<R, W>) -> WebSocketResult<Response<R, W>> {
-		let (status, version, headers) = {
+		let (status, reason, version, headers) = {
 			let reader = request.get_mut_reader();
 
 			let response = try!(parse_response(reader));
 
 			let status = StatusCode::from_u16(response.subject.0);
-			(status, response.version, response.headers)
+			(status, response.subject.1.into_owned(), response.version, response.headers)
 		};
 
 		Ok(Response {
 			status: status,
 			headers: headers,
 			version: version,
+			reason: reason,
 			request: request
 		})
 	}
@@ -68,7 +74,59 @@ impl<R: Read, W: Write> Response<R, W> {
 	pub fn extensions(&self) -> Option<&WebSocketExtensions> {
 		self.headers.get()
 	}
-		/// Returns a reference to the inner Reader.
+	/// Returns the extensions accepted by the server, each paired with the offer from
+	/// the request which it corresponds to.
+	///
+	/// See `WebSocketExtensions::match_accepted()`.
+	pub fn accepted_extensions(&self) -> WebSocketResult<Vec<(&Extension, &Extension)>> {
+		let accepted = match self.extensions() {
+			Some(accepted) => accepted,
+			None => return Ok(Vec::new()),
+		};
+		let offers = try!(self.request.extensions().ok_or(WebSocketError::ResponseError(
+			"Extension was accepted but not offered"
+		)));
+		let offers = try!(offers.match_accepted(accepted));
+		Ok(accepted.iter().zip(offers).collect())
+	}
+	/// Returns the permessage-deflate parameters accepted by the server, or None if it
+	/// did not accept the extension.
+	///
+	/// An error is returned if the parameters are invalid, or if the server limits the
+	/// client's window size although the offer did not allow it.
+	pub fn deflate(&self) -> WebSocketResult<Option<DeflateParams>> {
+		let accepted = try!(self.accepted_extensions());
+		let (extension, offer) = match accepted.iter().find(|&&(extension, _)| extension.name == deflate::EXTENSION_NAME) {
+			Some(&(extension, offer)) => (extension, offer),
+			None => return Ok(None),
+		};
+		let params = try!(DeflateParams::from_extension(extension));
+		let offer = try!(DeflateParams::from_extension(offer));
+		if params.client_max_window_bits.is_some() && offer.client_max_window_bits.is_none() {
+			return Err(WebSocketError::ResponseError(
+				"Server limited the client window size without being allowed to"
+			));
+		}
+		Ok(Some(params))
+	}
+	/// Short-cut to obtain the WebSocketVersion value.
+	///
+	/// Servers typically only send this when rejecting a request with an
+	/// unsupported WebSocket version.
+	pub fn version(&self) -> Option<&WebSocketVersion> {
+		self.headers.get()
+	}
+	/// Returns the reason phrase exactly as it appeared in the status line.
+	///
+	/// Some servers use a custom reason phrase to explain why a handshake failed.
+	pub fn reason(&self) -> &str {
+		&self.reason
+	}
+	/// Returns the status code and reason phrase of the status line.
+	pub fn handshake_status(&self) -> (StatusCode, String) {
+		(self.status, self.reason.clone())
+	}
+	/// Returns a reference to the inner Reader.
 	pub fn get_reader(&self) -> &BufReader<R> {
 		self.request.get_reader()
 	}
@@ -93,6 +151,63 @@ impl<R: Read, W: Write> Response<R, W> {
 		self.request.into_inner()
 	}
 
+	/// Reads the body of this response, which servers often use to explain why
+	/// a handshake was rejected.
+	///
+	/// The body is read up to the Content-Length, or until the connection is closed if
+	/// no length was given. A chunked Transfer-Encoding is de-chunked, any gzip or deflate
+	/// Content-Encoding or Transfer-Encoding is decoded, and at most `limit` bytes of the
+	/// decoded body are returned.
+	///
+	/// A Switching Protocols response has no body, so an error is returned for one.
+	pub fn read_body<'a>(&'a mut self, limit: usize) -> WebSocketResult<Vec<u8>> {
+		if self.status == StatusCode::SwitchingProtocols {
+			return Err(WebSocketError::ResponseError(
+				"Switching Protocols response has no body"
+			));
+		}
+		let length = self.headers.get::<ContentLength>().map(|length| length.0);
+		let mut encodings = match self.headers.get::<ContentEncoding>() {
+			Some(&ContentEncoding(ref encodings)) => encodings.clone(),
+			None => Vec::new(),
+		};
+		// Transfer codings are applied after content codings, and chunked comes last
+		let mut chunked = false;
+		if let Some(&TransferEncoding(ref transfer)) = self.headers.get::<TransferEncoding>() {
+			encodings.extend(transfer.iter().cloned());
+			if encodings.last() == Some(&Encoding::Chunked) {
+				encodings.pop();
+				chunked = true;
+			}
+		}
+
+		let reader = self.request.get_mut_reader();
+		let mut body: Box<Read + 'a> = if chunked {
+			Box::new(HttpReader::ChunkedReader(reader, None))
+		}
+		else {
+			match length {
+				Some(length) => Box::new(reader.take(length)),
+				None => Box::new(reader),
+			}
+		};
+		// Encodings are listed in the order they were applied
+		for encoding in encodings.iter().rev() {
+			body = match *encoding {
+				Encoding::Gzip => Box::new(GzDecoder::new(body)),
+				Encoding::Deflate => Box::new(ZlibDecoder::new(body)),
+				Encoding::Identity => body,
+				_ => return Err(WebSocketError::ResponseError(
+					"Unsupported response Content-Encoding"
+				)),
+			};
+		}
+
+		let mut buf = Vec::new();
+		try!(body.take(limit as u64).read_to_end(&mut buf));
+		Ok(buf)
+	}
+
 	/// Check if this response constitutes a successful handshake.
 	pub fn validate(&self) -> WebSocketResult<()> {
 		if self.status != StatusCode::SwitchingProtocols {
@@ -113,6 +228,7 @@ impl<R: Read, W: Write> Response<R, W> {
 		if self.headers.get() != Some(&(Connection(vec![ConnectionOption::ConnectionHeader(UniCase("Upgrade".to_string()))]))) {
 			return Err(WebSocketError::ResponseError("Connection field must be 'Upgrade'"));
 		}
+		try!(self.deflate());
 		Ok(())
 	}
 
@@ -126,11 +242,140 @@ impl<R: Read, W: Write> Response<R, W> {
 	}
 	/// Consume this response and return a Client ready to transmit/receive data frames.
 	///
+	/// If the server accepted the permessage-deflate extension, messages are compressed
+	/// and decompressed by middleware on the Sender and Receiver.
+	///
 	/// Does not check if the response was valid. Use `validate()` to ensure that the response constitutes a successful handshake.
 	pub fn begin(self) -> Client<DataFrame, Sender<W>, Receiver<R>> {
+		let compression = self.deflate().unwrap_or(None);
+		let protocol = self.protocol().and_then(|protocols| protocols.first().cloned());
+		let headers = self.headers.clone();
 		let (reader, writer) = self.into_inner();
-		let sender = Sender::new(writer, true);
-		let receiver = Receiver::new(reader, false);
-		Client::new(sender, receiver)
+		let mut sender = Sender::new(writer, true);
+		let mut receiver = Receiver::new(reader, false);
+		if let Some(params) = compression {
+			let (deflater, inflater) = params.middleware(true);
+			sender.add_middleware(deflater);
+			receiver.add_middleware(inflater);
+		}
+		let mut client = Client::new(sender, receiver);
+		client.set_compression(compression);
+		client.set_protocol(protocol);
+		client.set_response_headers(headers);
+		client
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use std::io::Write;
+	use flate2::Compression;
+	use flate2::write::GzEncoder;
+	use hyper::header::Host;
+	use hyper::status::StatusCode;
+	use client::Request;
+	use client::response::Response;
+
+	#[test]
+	fn test_read_gzip_body() {
+		let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(b"Origin not allowed").unwrap();
+		let compressed = encoder.finish().unwrap();
+
+		let mut data = format!(
+			"HTTP/1.1 403 Forbidden\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+			compressed.len()
+		).into_bytes();
+		data.extend(compressed);
+
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let request = Request::new((host, "/"), &data[..], Vec::new()).unwrap();
+		let mut response = Response::read(request).unwrap();
+
+		assert_eq!(&response.read_body(1024).unwrap()[..], &b"Origin not allowed"[..]);
+	}
+
+	#[test]
+	fn test_read_chunked_body() {
+		let data = b"HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\n\r\n\
+			7\r\nOrigin \r\nb\r\nnot allowed\r\n0\r\n\r\n";
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let request = Request::new((host, "/"), &data[..], Vec::new()).unwrap();
+		let mut response = Response::read(request).unwrap();
+
+		assert_eq!(&response.read_body(10).unwrap()[..], &b"Origin not"[..]);
+	}
+
+	#[test]
+	fn test_validate_padded_accept() {
+		use std::str::FromStr;
+		use header::WebSocketKey;
+
+		let data = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+			Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo= \t\r\n\r\n";
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let mut request = Request::new((host, "/"), &data[..], Vec::new()).unwrap();
+		request.headers.set(WebSocketKey::from_str("dGhlIHNhbXBsZSBub25jZQ==").unwrap());
+		let response = Response::read(request).unwrap();
+
+		response.validate().unwrap();
+	}
+
+	#[test]
+	fn test_handshake_status() {
+		let data = b"HTTP/1.1 429 Slow Down Please\r\nContent-Length: 0\r\n\r\n";
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let request = Request::new((host, "/"), &data[..], Vec::new()).unwrap();
+		let response = Response::read(request).unwrap();
+
+		assert_eq!(response.handshake_status(), (StatusCode::TooManyRequests, "Slow Down Please".to_string()));
+	}
+
+	#[test]
+	fn test_deflate_handshake() {
+		use std::str::FromStr;
+		use header::WebSocketKey;
+		use deflate::DeflateParams;
+		use message::Message;
+		use ws::Receiver;
+
+		let mut data = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+			Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+			Sec-WebSocket-Extensions: permessage-deflate\r\n\r\n".to_vec();
+		// "Hello" compressed, from RFC 7692 section 7.2.3.1
+		data.extend(&[0xc1, 0x07, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let mut request = Request::new((host, "/"), &data[..], Vec::new()).unwrap();
+		request.headers.set(WebSocketKey::from_str("dGhlIHNhbXBsZSBub25jZQ==").unwrap());
+		request.offer_deflate(&[DeflateParams::default()]);
+		let response = Response::read(request).unwrap();
+		response.validate().unwrap();
+
+		let client = response.begin();
+		assert_eq!(client.compression(), Some(&DeflateParams::default()));
+		let (_, mut receiver) = client.split();
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("Hello"));
+	}
+
+	#[test]
+	fn test_begin_response_headers() {
+		use std::str::FromStr;
+		use header::{WebSocketKey, WebSocketProtocol};
+
+		let data = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+			Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\nSec-WebSocket-Protocol: chat\r\n\
+			X-Session: abc123\r\n\r\n";
+		let host = Host { hostname: "localhost".to_string(), port: None };
+		let mut request = Request::new((host, "/"), &data[..], Vec::new()).unwrap();
+		request.headers.set(WebSocketKey::from_str("dGhlIHNhbXBsZSBub25jZQ==").unwrap());
+		request.headers.set(WebSocketProtocol(vec!["chat".to_string(), "superchat".to_string()]));
+		let response = Response::read(request).unwrap();
+		response.validate().unwrap();
+
+		let client = response.begin();
+		assert_eq!(client.protocol(), Some("chat"));
+		let session = client.response_headers().get_raw("X-Session").unwrap();
+		assert_eq!(session, &[b"abc123".to_vec()][..]);
 	}
 }
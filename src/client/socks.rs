@@ -0,0 +1,257 @@
+//! Connects through a SOCKS5 proxy (RFC 1928), optionally authenticating with a
+//! username and password (RFC 1929).
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+
+use result::{WebSocketResult, WebSocketError};
+use stream::TcpOptions;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// A SOCKS5 proxy to connect through. See `Client::connect_socks5()`.
+///
+/// By default the host name being connected to is sent to the proxy to resolve, so
+/// that no lookups are made locally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Socks5Proxy {
+	addr: SocketAddr,
+	auth: Option<(String, String)>,
+	remote_dns: bool,
+	tcp_options: Option<TcpOptions>,
+}
+
+impl Socks5Proxy {
+	/// Creates a Socks5Proxy for the proxy at `addr`, which authenticates with the given
+	/// username and password, if any.
+	pub fn new(addr: SocketAddr, auth: Option<(String, String)>) -> Socks5Proxy {
+		Socks5Proxy {
+			addr: addr,
+			auth: auth,
+			remote_dns: true,
+			tcp_options: None,
+		}
+	}
+	/// Returns the address of the proxy.
+	pub fn addr(&self) -> SocketAddr {
+		self.addr
+	}
+	/// Sets whether host names are resolved by the proxy rather than locally.
+	pub fn set_remote_dns(&mut self, remote_dns: bool) {
+		self.remote_dns = remote_dns;
+	}
+	/// Returns whether host names are resolved by the proxy rather than locally.
+	pub fn remote_dns(&self) -> bool {
+		self.remote_dns
+	}
+	/// Sets the socket options applied to the connection to the proxy as soon as it is
+	/// established, before any handshake, or leaves the operating system's defaults if
+	/// `options` is None (the default).
+	pub fn set_tcp_options(&mut self, options: Option<TcpOptions>) {
+		self.tcp_options = options;
+	}
+	/// Returns the socket options applied to the connection to the proxy, if any.
+	pub fn tcp_options(&self) -> Option<&TcpOptions> {
+		self.tcp_options.as_ref()
+	}
+	/// Connects to the proxy and asks it to connect to the given host and port,
+	/// returning the stream which is then tunnelled to that host.
+	///
+	/// Failures reported by the proxy, including refused authentication, are returned as
+	/// `WebSocketError::ProxyError`.
+	pub fn connect(&self, hostname: &str, port: u16) -> WebSocketResult<TcpStream> {
+		let mut stream = try!(TcpStream::connect(self.addr));
+		if let Some(ref options) = self.tcp_options {
+			try!(options.apply(&stream));
+		}
+		try!(self.authenticate(&mut stream));
+
+		let mut request = vec![VERSION, CONNECT, 0];
+		let ip = hostname.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().ok();
+		let ip = match ip {
+			Some(ip) => Some(ip),
+			None if !self.remote_dns => {
+				let addr = try!(try!((hostname, port).to_socket_addrs()).next().ok_or_else(|| {
+					io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")
+				}));
+				Some(addr.ip())
+			}
+			None => None,
+		};
+		match ip {
+			Some(IpAddr::V4(ip)) => {
+				request.push(ATYP_IPV4);
+				request.extend_from_slice(&ip.octets());
+			}
+			Some(IpAddr::V6(ip)) => {
+				request.push(ATYP_IPV6);
+				request.extend_from_slice(&ip.octets());
+			}
+			None => {
+				if hostname.len() > 255 {
+					return Err(WebSocketError::ProxyError("Host name too long for SOCKS5"));
+				}
+				request.push(ATYP_DOMAIN);
+				request.push(hostname.len() as u8);
+				request.extend_from_slice(hostname.as_bytes());
+			}
+		}
+		request.push((port >> 8) as u8);
+		request.push(port as u8);
+		try!(stream.write_all(&request));
+
+		let mut reply = [0; 4];
+		try!(read_reply(&mut stream, &mut reply));
+		if reply[0] != VERSION {
+			return Err(WebSocketError::ProxyError("Invalid SOCKS5 reply"));
+		}
+		if reply[1] != 0 {
+			return Err(WebSocketError::ProxyError(match reply[1] {
+				1 => "SOCKS5 general server failure",
+				2 => "Connection not allowed by the SOCKS5 proxy",
+				3 => "Network unreachable from the SOCKS5 proxy",
+				4 => "Host unreachable from the SOCKS5 proxy",
+				5 => "Connection refused by the destination host",
+				6 => "TTL expired",
+				7 => "SOCKS5 command not supported",
+				8 => "SOCKS5 address type not supported",
+				_ => "Unknown SOCKS5 failure",
+			}));
+		}
+		// Skip the address the proxy bound to, and its port
+		let len = match reply[3] {
+			ATYP_IPV4 => 4,
+			ATYP_IPV6 => 16,
+			ATYP_DOMAIN => {
+				let mut len = [0; 1];
+				try!(read_reply(&mut stream, &mut len));
+				len[0] as usize
+			}
+			_ => return Err(WebSocketError::ProxyError("Invalid SOCKS5 reply")),
+		};
+		let mut bound = vec![0; len + 2];
+		try!(read_reply(&mut stream, &mut bound));
+		Ok(stream)
+	}
+	fn authenticate(&self, stream: &mut TcpStream) -> WebSocketResult<()> {
+		let greeting: &[u8] = match self.auth {
+			Some(_) => &[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+			None => &[VERSION, 1, NO_AUTHENTICATION],
+		};
+		try!(stream.write_all(greeting));
+		let mut choice = [0; 2];
+		try!(read_reply(stream, &mut choice));
+		if choice[0] != VERSION {
+			return Err(WebSocketError::ProxyError("Invalid SOCKS5 reply"));
+		}
+		match (choice[1], &self.auth) {
+			(NO_AUTHENTICATION, _) => Ok(()),
+			(USERNAME_PASSWORD, &Some((ref username, ref password))) => {
+				if username.len() > 255 || password.len() > 255 {
+					return Err(WebSocketError::ProxyError("SOCKS5 username or password too long"));
+				}
+				let mut request = vec![1, username.len() as u8];
+				request.extend_from_slice(username.as_bytes());
+				request.push(password.len() as u8);
+				request.extend_from_slice(password.as_bytes());
+				try!(stream.write_all(&request));
+				let mut status = [0; 2];
+				try!(read_reply(stream, &mut status));
+				if status[1] != 0 {
+					return Err(WebSocketError::ProxyError("SOCKS5 authentication failed"));
+				}
+				Ok(())
+			}
+			(NO_ACCEPTABLE_METHODS, _) => Err(WebSocketError::ProxyError(
+				"No acceptable SOCKS5 authentication method"
+			)),
+			_ => Err(WebSocketError::ProxyError("Invalid SOCKS5 reply")),
+		}
+	}
+}
+
+fn read_reply(stream: &mut TcpStream, buf: &mut [u8]) -> WebSocketResult<()> {
+	match stream.read_exact(buf) {
+		Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+			Err(WebSocketError::ProxyError("SOCKS5 proxy closed the connection"))
+		}
+		result => Ok(try!(result)),
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+	use std::thread;
+	use client::Client;
+	use message::Message;
+	use result::WebSocketError;
+	use server;
+	use url::Url;
+
+	fn read_vec(stream: &mut TcpStream, len: usize) -> Vec<u8> {
+		let mut buf = vec![0; len];
+		stream.read_exact(&mut buf).unwrap();
+		buf
+	}
+
+	#[test]
+	fn test_connect_socks5() {
+		// The proxy checks the request, then serves the WebSocket itself
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let proxy_addr = listener.local_addr().unwrap();
+		let thread = thread::spawn(move || {
+			let mut stream = listener.accept().unwrap().0;
+			assert_eq!(read_vec(&mut stream, 4), vec![5, 2, 0, 2]);
+			stream.write_all(&[5, 2]).unwrap();
+			assert_eq!(read_vec(&mut stream, 11), b"\x01\x04user\x04pass".to_vec());
+			stream.write_all(&[1, 0]).unwrap();
+			let mut expected = vec![5, 1, 0, 3, 11];
+			expected.extend_from_slice(b"example.com");
+			expected.extend_from_slice(&[0x1F, 0x90]);
+			assert_eq!(read_vec(&mut stream, expected.len()), expected);
+			stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x1F, 0x90]).unwrap();
+
+			let request = server::Request::read(&stream, &stream).unwrap();
+			let mut client = request.accept().send().unwrap();
+			client.run_echo().unwrap();
+
+			// A second connection is refused by the destination
+			let mut stream = listener.accept().unwrap().0;
+			assert_eq!(read_vec(&mut stream, 3), vec![5, 1, 0]);
+			stream.write_all(&[5, 0]).unwrap();
+			assert_eq!(read_vec(&mut stream, 10), vec![5, 1, 0, 1, 10, 0, 0, 1, 0, 80]);
+			stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+		});
+
+		let url = Url::parse("ws://example.com:8080/").unwrap();
+		let mut proxy = Socks5Proxy::new(proxy_addr, Some(("user".to_string(), "pass".to_string())));
+		assert!(proxy.remote_dns());
+		proxy.set_tcp_options(Some(TcpOptions { nodelay: true, keepalive: None }));
+		let request = Client::connect_socks5(&url, &proxy).unwrap();
+		assert!(request.get_writer().tcp_ref().nodelay().unwrap());
+		let response = request.send().unwrap();
+		response.validate().unwrap();
+		let mut client = response.begin();
+		client.send_message(&Message::text("hi")).unwrap();
+		assert_eq!(client.recv_until(|_| true, None).unwrap(), Message::text("hi"));
+		client.send_message(&Message::close()).unwrap();
+		client.recv_until(|_| true, None).unwrap();
+
+		let url = Url::parse("ws://10.0.0.1/").unwrap();
+		let proxy = Socks5Proxy::new(proxy_addr, None);
+		match Client::connect_socks5(&url, &proxy) {
+			Err(WebSocketError::ProxyError("Connection refused by the destination host")) => (),
+			other => panic!("expected a proxy error, got {:?}", other.map(|_| ())),
+		}
+		thread.join().unwrap();
+	}
+}
@@ -0,0 +1,170 @@
+//! Provides write coalescing, which combines many small writes into fewer larger ones.
+use std::io::{self, Write};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct State {
+	writer: Box<Write + Send>,
+	buffer: Vec<u8>,
+	since: Option<Instant>,
+	error: Option<io::Error>,
+	closed: bool,
+}
+
+impl State {
+	fn flush(&mut self) {
+		self.since = None;
+		if self.buffer.is_empty() || self.error.is_some() {
+			return;
+		}
+		let buffer = mem::replace(&mut self.buffer, Vec::new());
+		let result = self.writer.write_all(&buffer).and_then(|_| self.writer.flush());
+		if let Err(e) = result {
+			self.error = Some(e);
+		}
+	}
+}
+
+/// Buffers written data until either `max_bytes` have been buffered, or `max_delay`
+/// has passed since the oldest buffered data was written, then writes it all at once.
+///
+/// A helper thread performs the writes which are due to `max_delay`. It stops when
+/// the Coalescer is dropped, after writing anything still buffered.
+pub struct Coalescer {
+	inner: Arc<(Mutex<State>, Condvar)>,
+	max_bytes: usize,
+}
+
+impl Coalescer {
+	/// Creates a new Coalescer which writes to `writer`.
+	pub fn new<W>(writer: W, max_delay: Duration, max_bytes: usize) -> Coalescer
+	where W: Write + Send + 'static {
+		let inner = Arc::new((Mutex::new(State {
+			writer: Box::new(writer),
+			buffer: Vec::new(),
+			since: None,
+			error: None,
+			closed: false,
+		}), Condvar::new()));
+
+		let timer = inner.clone();
+		thread::spawn(move || {
+			let &(ref state, ref wake) = &*timer;
+			let mut state = state.lock().unwrap();
+			loop {
+				if state.closed {
+					state.flush();
+					return;
+				}
+				state = match state.since {
+					Some(since) => {
+						let elapsed = since.elapsed();
+						if elapsed >= max_delay {
+							state.flush();
+							continue;
+						}
+						wake.wait_timeout(state, max_delay - elapsed).unwrap().0
+					}
+					None => wake.wait(state).unwrap(),
+				};
+			}
+		});
+
+		Coalescer {
+			inner: inner,
+			max_bytes: max_bytes,
+		}
+	}
+	/// Returns how long the oldest data which is still buffered has been waiting, or
+	/// None if nothing is buffered.
+	pub fn oldest_age(&self) -> Option<Duration> {
+		self.inner.0.lock().unwrap().since.map(|since| since.elapsed())
+	}
+	/// Writes everything which is buffered now.
+	pub fn flush(&self) -> io::Result<()> {
+		let mut state = self.inner.0.lock().unwrap();
+		state.flush();
+		match state.error.take() {
+			Some(e) => Err(e),
+			None => Ok(()),
+		}
+	}
+}
+
+impl Drop for Coalescer {
+	fn drop(&mut self) {
+		let &(ref state, ref wake) = &*self.inner;
+		state.lock().unwrap().closed = true;
+		wake.notify_one();
+	}
+}
+
+impl<'a> Write for &'a Coalescer {
+	/// Buffers the data, reporting any error from an earlier write.
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		let &(ref state, ref wake) = &*self.inner;
+		let mut state = state.lock().unwrap();
+		if let Some(e) = state.error.take() {
+			return Err(e);
+		}
+		state.buffer.extend_from_slice(data);
+		if state.buffer.len() >= self.max_bytes {
+			state.flush();
+			if let Some(e) = state.error.take() {
+				return Err(e);
+			}
+		}
+		else if state.since.is_none() {
+			state.since = Some(Instant::now());
+			wake.notify_one();
+		}
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Coalescer::flush(self)
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::io::{self, Write};
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
+
+	#[derive(Clone)]
+	struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+
+	impl Write for Writes {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().push(buf.to_vec());
+			Ok(buf.len())
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_coalescer_triggers() {
+		let writes = Writes(Arc::new(Mutex::new(Vec::new())));
+		let coalescer = Coalescer::new(writes.clone(), Duration::from_millis(50), 4);
+
+		assert_eq!(coalescer.oldest_age(), None);
+		(&coalescer).write_all(b"ab").unwrap();
+		(&coalescer).write_all(b"c").unwrap();
+		assert!(writes.0.lock().unwrap().is_empty());
+		assert!(coalescer.oldest_age().is_some());
+		// Reaching max_bytes writes at once
+		(&coalescer).write_all(b"d").unwrap();
+		assert_eq!(*writes.0.lock().unwrap(), vec![b"abcd".to_vec()]);
+		// Otherwise the helper thread writes after max_delay
+		(&coalescer).write_all(b"e").unwrap();
+		thread::sleep(Duration::from_millis(200));
+		assert_eq!(*writes.0.lock().unwrap(), vec![b"abcd".to_vec(), b"e".to_vec()]);
+	}
+}
@@ -1,7 +1,7 @@
 //! Module containing the default implementation of data frames.
 use std::io::Read;
 use std::borrow::Cow;
-use result::{WebSocketResult, WebSocketError};
+use result::{WebSocketResult, WebSocketError, EXPECTED_MASKED, EXPECTED_UNMASKED};
 use ws::dataframe::DataFrame as DataFrameable;
 use ws::util::header as dfh;
 use ws::util::mask;
@@ -40,7 +40,38 @@ impl DataFrame {
     /// Reads a DataFrame from a Reader.
     pub fn read_dataframe<R>(reader: &mut R, should_be_masked: bool) -> WebSocketResult<Self>
 	where R: Read {
-    	let header = try!(dfh::read_header(reader));
+		DataFrame::read_dataframe_limited(reader, Some(should_be_masked), None)
+	}
+
+    /// Reads a DataFrame from a Reader, accepting it whether or not it is masked and
+    /// skipping the checks described in `ws::util::header::read_header_lenient()`.
+    pub fn read_dataframe_lenient<R>(reader: &mut R) -> WebSocketResult<Self>
+	where R: Read {
+		DataFrame::read_dataframe_limited(reader, None, None)
+	}
+
+    /// Reads a DataFrame from a Reader, failing before its payload is read if it is
+    /// longer than `max_size` bytes.
+    ///
+    /// If `should_be_masked` is None, this reads like `read_dataframe_lenient()`.
+    pub fn read_dataframe_limited<R>(reader: &mut R, should_be_masked: Option<bool>, max_size: Option<u64>) -> WebSocketResult<Self>
+	where R: Read {
+    	let header = try!(match should_be_masked {
+			Some(_) => dfh::read_header(reader),
+			None => dfh::read_header_lenient(reader),
+		});
+		if max_size.map_or(false, |max| header.len > max) {
+			return Err(WebSocketError::DataFrameError("Data frame too large"));
+		}
+
+    	// The payload is read first, so that the reader is left at the next data frame
+    	let mut data: Vec<u8> = try!(reader.take(header.len).bytes().collect());
+    	match (header.mask, should_be_masked) {
+    		(Some(_), Some(false)) => return Err(WebSocketError::DataFrameError(EXPECTED_UNMASKED)),
+    		(None, Some(true)) => return Err(WebSocketError::DataFrameError(EXPECTED_MASKED)),
+    		(Some(mask), _) => mask::mask_in_place(mask, 0, &mut data),
+    		(None, _) => (),
+    	}
 
     	Ok(DataFrame {
     		finished: header.flags.contains(dfh::FIN),
@@ -50,27 +81,7 @@ impl DataFrame {
     			header.flags.contains(dfh::RSV3)
     		],
     		opcode: Opcode::new(header.opcode).expect("Invalid header opcode!"),
-    		data: match header.mask {
-    			Some(mask) => {
-    				if !should_be_masked {
-    					return Err(WebSocketError::DataFrameError(
-    						"Expected unmasked data frame"
-    					));
-    				}
-
-    				let data: Vec<u8> = try!(reader.take(header.len).bytes().collect());
-    				mask::mask_data(mask, &data)
-    			}
-    			None => {
-    				if should_be_masked {
-    					return Err(WebSocketError::DataFrameError(
-    						"Expected masked data frame"
-    					));
-    				}
-
-    				try!(reader.take(header.len).bytes().collect())
-    			}
-    		}
+    		data: data,
     	})
     }
 }
@@ -98,7 +109,7 @@ impl DataFrameable for DataFrame {
 }
 
 /// Represents a WebSocket data frame opcode
-#[derive(Clone, Debug, Copy, PartialEq)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
 pub enum Opcode {
 	/// A continuation data frame
 	Continuation,
@@ -159,6 +170,17 @@ impl Opcode {
 			_ => return None,
 		})
 	}
+	/// Returns true for the opcodes of control data frames (close, ping, pong and the
+	/// undefined control opcodes), which may not be fragmented and may arrive in the
+	/// middle of a fragmented message.
+	pub fn is_control(&self) -> bool {
+		*self as u8 >= 8
+	}
+	/// Returns true for the opcodes of data frames which carry (part of) a message:
+	/// continuation, text, binary and the undefined non-control opcodes.
+	pub fn is_data(&self) -> bool {
+		!self.is_control()
+	}
 }
 
 #[cfg(all(feature = "nightly", test))]
@@ -167,6 +189,16 @@ mod tests {
 	use ws::dataframe::DataFrame as DataFrameable;
     use test::Bencher;
 
+    #[test]
+    fn test_opcode_kinds() {
+        for op in 0..16 {
+            let opcode = Opcode::new(op).unwrap();
+            assert_eq!(opcode as u8, op);
+            assert_eq!(opcode.is_control(), op >= 8);
+            assert_eq!(opcode.is_data(), op < 8);
+        }
+        assert!(Opcode::new(16).is_none());
+    }
     #[test]
     fn test_read_dataframe() {
         let data = b"The quick brown fox jumps over the lazy dog";
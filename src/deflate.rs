@@ -0,0 +1,794 @@
+//! Provides compression for the permessage-deflate extension (RFC 7692).
+//!
+//! Each direction of a connection has its own compression context. When the
+//! `*_no_context_takeover` parameter has been negotiated for a direction, the
+//! sending endpoint resets its compressor after every message and the receiving
+//! endpoint resets its decompressor before every message. Otherwise the sliding
+//! window is kept across messages, so later messages may refer back to earlier ones.
+use std::cmp;
+use std::mem;
+
+use flate2::{Compress, Decompress, Compression, FlushCompress, FlushDecompress, Status};
+
+use dataframe::{DataFrame, Opcode};
+use header::WebSocketExtensions;
+use header::extensions::{Extension, Parameter};
+use message::{Message, Type};
+use middleware::Middleware;
+use receiver::DEFAULT_MAX_SIZE;
+use result::{WebSocketResult, WebSocketError};
+
+/// The name of the permessage-deflate extension.
+pub const EXTENSION_NAME: &'static str = "permessage-deflate";
+
+/// The trailer removed from the end of each compressed message (RFC 7692 7.2.1).
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// The largest, and default, LZ77 sliding window size in bits.
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// The parameters of a permessage-deflate extension offer or response.
+///
+/// The defaults offer or accept compression in both directions, keeping the
+/// compression context across messages and using the largest window size.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DeflateParams {
+	/// Whether the server resets its compression context after every message.
+	pub server_no_context_takeover: bool,
+	/// Whether the client resets its compression context after every message.
+	pub client_no_context_takeover: bool,
+	/// The largest window size in bits, from 8 to 15, which the server may compress with.
+	pub server_max_window_bits: Option<u8>,
+	/// The largest window size in bits, from 8 to 15, which the client may compress with.
+	///
+	/// In an offer, a value of 15 tells the server that it may limit the client's
+	/// window size.
+	pub client_max_window_bits: Option<u8>,
+}
+
+impl DeflateParams {
+	/// Reads the parameters from a permessage-deflate extension.
+	///
+	/// A `client_max_window_bits` parameter without a value, which is only valid in
+	/// an offer, is read as 15.
+	pub fn from_extension(extension: &Extension) -> WebSocketResult<DeflateParams> {
+		if extension.name != EXTENSION_NAME {
+			return Err(WebSocketError::ProtocolError(
+				"Extension is not permessage-deflate"
+			));
+		}
+		let mut params = DeflateParams::default();
+		for param in extension.params.iter() {
+			match &param.name[..] {
+				"server_no_context_takeover" => params.server_no_context_takeover = true,
+				"client_no_context_takeover" => params.client_no_context_takeover = true,
+				"server_max_window_bits" => {
+					let value = try!(param.value.as_ref().ok_or(WebSocketError::ProtocolError(
+						"Missing server_max_window_bits value"
+					)));
+					params.server_max_window_bits = Some(try!(parse_window_bits(value)));
+				}
+				"client_max_window_bits" => {
+					params.client_max_window_bits = Some(match param.value {
+						Some(ref value) => try!(parse_window_bits(value)),
+						None => MAX_WINDOW_BITS,
+					});
+				}
+				_ => return Err(WebSocketError::ProtocolError(
+					"Unknown permessage-deflate parameter"
+				)),
+			}
+		}
+		Ok(params)
+	}
+
+	/// Creates a permessage-deflate extension with these parameters.
+	pub fn to_extension(&self) -> Extension {
+		let mut extension = Extension::new(EXTENSION_NAME.to_string());
+		if self.server_no_context_takeover {
+			extension.params.push(Parameter::new("server_no_context_takeover".to_string(), None));
+		}
+		if self.client_no_context_takeover {
+			extension.params.push(Parameter::new("client_no_context_takeover".to_string(), None));
+		}
+		if let Some(bits) = self.server_max_window_bits {
+			extension.params.push(Parameter::new("server_max_window_bits".to_string(), Some(bits.to_string())));
+		}
+		if let Some(bits) = self.client_max_window_bits {
+			extension.params.push(Parameter::new("client_max_window_bits".to_string(), Some(bits.to_string())));
+		}
+		extension
+	}
+
+	/// Chooses the parameters to accept from a client's offers, for a server configured
+	/// with these parameters, or returns None if permessage-deflate was not offered.
+	///
+	/// The first permessage-deflate offer which can be read is accepted. The server
+	/// resets either context if either side asks for it, and limits each window size to
+	/// the smaller of what was offered and what it is configured with. The client's
+	/// window size is only limited if the client said it may be.
+	pub fn negotiate(&self, offers: &WebSocketExtensions) -> Option<DeflateParams> {
+		let offer = match offers.iter()
+			.filter(|offer| offer.name == EXTENSION_NAME)
+			.filter_map(|offer| DeflateParams::from_extension(offer).ok())
+			.next() {
+			Some(offer) => offer,
+			None => return None,
+		};
+		Some(DeflateParams {
+			server_no_context_takeover: offer.server_no_context_takeover || self.server_no_context_takeover,
+			client_no_context_takeover: offer.client_no_context_takeover || self.client_no_context_takeover,
+			server_max_window_bits: match (offer.server_max_window_bits, self.server_max_window_bits) {
+				(Some(offered), Some(configured)) => Some(cmp::min(offered, configured)),
+				(offered, configured) => offered.or(configured),
+			},
+			client_max_window_bits: match (offer.client_max_window_bits, self.client_max_window_bits) {
+				(Some(offered), Some(configured)) => Some(cmp::min(offered, configured)),
+				_ => None,
+			},
+		})
+	}
+
+	/// Creates an extensions header offering each set of parameters in turn, in order of
+	/// preference.
+	///
+	/// Use `WebSocketExtensions::match_accepted()` to find which one the server accepted.
+	pub fn offer(alternatives: &[DeflateParams]) -> WebSocketExtensions {
+		WebSocketExtensions(alternatives.iter().map(|params| params.to_extension()).collect())
+	}
+
+	/// Creates the compressor and decompressor for one endpoint of a connection
+	/// using these parameters.
+	///
+	/// `client` determines which direction's parameters apply to the compressor.
+	pub fn contexts(&self, client: bool) -> (Compressor, Decompressor) {
+		if client {
+			(Compressor::new(self.client_no_context_takeover),
+			 Decompressor::new(self.server_no_context_takeover))
+		} else {
+			(Compressor::new(self.server_no_context_takeover),
+			 Decompressor::new(self.client_no_context_takeover))
+		}
+	}
+
+	/// Creates the middleware which compresses outgoing messages and decompresses
+	/// incoming ones for one endpoint of a connection using these parameters.
+	///
+	/// The compressor only supports the largest window size, so if the window size of
+	/// the outgoing direction is limited, outgoing messages are sent uncompressed,
+	/// which the extension allows.
+	pub fn middleware(&self, client: bool) -> (Deflater, Inflater) {
+		let (compressor, decompressor) = self.contexts(client);
+		let window_bits = if client {
+			self.client_max_window_bits
+		} else {
+			self.server_max_window_bits
+		};
+		let compress = window_bits.unwrap_or(MAX_WINDOW_BITS) == MAX_WINDOW_BITS;
+		(Deflater {
+			compressor: if compress { Some(compressor) } else { None },
+			compressing: false,
+		 },
+		 Inflater {
+			decompressor: decompressor,
+			message: None,
+			max_message_size: Some(DEFAULT_MAX_SIZE),
+			discarding: false,
+		 })
+	}
+}
+
+fn parse_window_bits(value: &str) -> WebSocketResult<u8> {
+	match value.trim_matches('"').parse::<u8>() {
+		Ok(bits) if bits >= 8 && bits <= MAX_WINDOW_BITS => Ok(bits),
+		_ => Err(WebSocketError::ProtocolError("Invalid permessage-deflate window size")),
+	}
+}
+
+/// A middleware which compresses outgoing text and binary messages, and sets the RSV1
+/// bit on their first data frame.
+///
+/// Data frames which already have the RSV1 bit set are passed on unchanged, along with
+/// the rest of their message.
+pub struct Deflater {
+	compressor: Option<Compressor>,
+	compressing: bool,
+}
+
+impl Middleware for Deflater {
+	fn outgoing(&mut self, mut dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+		let compressor = match self.compressor {
+			Some(ref mut compressor) => compressor,
+			None => return Ok(vec![dataframe]),
+		};
+		match dataframe.opcode {
+			Opcode::Text | Opcode::Binary => {
+				// A data frame which is already compressed keeps its RSV1 bit
+				self.compressing = !dataframe.reserved[0];
+				if self.compressing {
+					dataframe.reserved[0] = true;
+				}
+			}
+			Opcode::Continuation => (),
+			_ => return Ok(vec![dataframe]),
+		}
+		if self.compressing {
+			dataframe.data = try!(compressor.compress_part(&dataframe.data, dataframe.finished));
+		}
+		if dataframe.finished {
+			self.compressing = false;
+		}
+		Ok(vec![dataframe])
+	}
+}
+
+/// A middleware which decompresses incoming messages whose first data frame has the
+/// RSV1 bit set, and clears the bit.
+///
+/// The data frames of a fragmented compressed message are held back until the whole
+/// message has arrived, then passed on as a single data frame.
+///
+/// The Receiver's limit on the size of received messages, `DEFAULT_MAX_SIZE` until it is
+/// added to one, applies both to the compressed payload held back and to the payload
+/// once decompressed. A message which passes the limit fails with a protocol error and
+/// the rest of its data frames are skipped. Unless `*_no_context_takeover` was
+/// negotiated for the incoming direction, the decompressor can then no longer follow
+/// the remote endpoint's compressor, so the connection should be closed with status
+/// code 1009.
+pub struct Inflater {
+	decompressor: Decompressor,
+	message: Option<DataFrame>,
+	max_message_size: Option<usize>,
+	discarding: bool,
+}
+
+impl Inflater {
+	fn too_large(&mut self, finished: bool) -> WebSocketError {
+		self.message = None;
+		self.discarding = !finished;
+		WebSocketError::ProtocolError("Message too large")
+	}
+}
+
+impl Middleware for Inflater {
+	fn incoming(&mut self, dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+		let mut message = match dataframe.opcode {
+			Opcode::Continuation if self.discarding => {
+				self.discarding = !dataframe.finished;
+				return Ok(Vec::new());
+			}
+			Opcode::Continuation => match self.message.take() {
+				Some(mut message) => {
+					if dataframe.reserved[0] {
+						return Err(WebSocketError::ProtocolError(
+							"RSV1 set on a continuation data frame"
+						));
+					}
+					message.data.extend(dataframe.data);
+					message.finished = dataframe.finished;
+					message
+				}
+				None => return Ok(vec![dataframe]),
+			},
+			Opcode::Text | Opcode::Binary => {
+				// A new message abandons any unfinished one, as the Receiver will reject it
+				self.message = None;
+				self.discarding = false;
+				if !dataframe.reserved[0] {
+					return Ok(vec![dataframe]);
+				}
+				dataframe
+			}
+			_ => return Ok(vec![dataframe]),
+		};
+		if self.max_message_size.map_or(false, |max| message.data.len() > max) {
+			return Err(self.too_large(message.finished));
+		}
+		if !message.finished {
+			self.message = Some(message);
+			return Ok(Vec::new());
+		}
+		let data = mem::replace(&mut message.data, Vec::new());
+		message.data = try!(self.decompressor.decompress_limited(&data, self.max_message_size));
+		message.reserved[0] = false;
+		Ok(vec![message])
+	}
+	fn set_max_message_size(&mut self, max: Option<usize>) {
+		self.max_message_size = max;
+	}
+}
+
+/// Compresses the payloads of outgoing messages.
+pub struct Compressor {
+	inner: Compress,
+	no_context_takeover: bool,
+}
+
+impl Compressor {
+	/// Creates a new Compressor, which resets its context after every message if
+	/// `no_context_takeover` is set.
+	pub fn new(no_context_takeover: bool) -> Compressor {
+		Compressor {
+			inner: Compress::new(Compression::default(), false),
+			no_context_takeover: no_context_takeover,
+		}
+	}
+
+	/// Compresses the entire payload of one message.
+	pub fn compress(&mut self, payload: &[u8]) -> WebSocketResult<Vec<u8>> {
+		self.compress_part(payload, true)
+	}
+
+	/// Compresses part of the payload of one message, which ends with this part if
+	/// `last` is set.
+	///
+	/// The compressed parts of a message may be sent in separate data frames.
+	pub fn compress_part(&mut self, payload: &[u8], last: bool) -> WebSocketResult<Vec<u8>> {
+		let mut output = Vec::with_capacity(payload.len() / 2 + 64);
+		let start = self.inner.total_in();
+		loop {
+			let consumed = (self.inner.total_in() - start) as usize;
+			if output.capacity() - output.len() < 64 {
+				let len = output.len();
+				output.reserve(len + 64);
+			}
+			try!(self.inner.compress_vec(&payload[consumed..], &mut output, FlushCompress::Sync)
+				.map_err(|_| WebSocketError::DataFrameError("Unable to compress payload")));
+			let consumed = (self.inner.total_in() - start) as usize;
+			if consumed == payload.len() && output.len() < output.capacity() {
+				break;
+			}
+		}
+		if !last {
+			return Ok(output);
+		}
+		if output.ends_with(&TRAILER) {
+			let len = output.len() - TRAILER.len();
+			output.truncate(len);
+		}
+		if self.no_context_takeover {
+			self.inner.reset();
+		}
+		Ok(output)
+	}
+}
+
+/// A text or binary message compressed once, so that it can be sent to many connections
+/// without compressing it again for each of them.
+///
+/// The message is compressed with a fresh compression context and the largest window
+/// size. An endpoint which keeps its compression context across messages could not
+/// send it, since the remote endpoint's decompressor would then no longer match its
+/// compressor, so it can only be sent compressed on connections where the sending
+/// direction has negotiated `*_no_context_takeover`. On connections which do not
+/// compress outgoing messages at all, it is sent uncompressed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreparedMessage {
+	opcode: Opcode,
+	payload: Vec<u8>,
+	compressed: Vec<u8>,
+}
+
+impl PreparedMessage {
+	/// Compresses the given message, which must be a text or binary message.
+	pub fn new(message: &Message) -> WebSocketResult<PreparedMessage> {
+		let opcode = match message.opcode {
+			Type::Text => Opcode::Text,
+			Type::Binary => Opcode::Binary,
+			_ => return Err(WebSocketError::ProtocolError(
+				"Only text and binary messages can be prepared"
+			)),
+		};
+		let compressed = try!(Compressor::new(true).compress(&message.payload));
+		Ok(PreparedMessage {
+			opcode: opcode,
+			payload: message.payload.to_vec(),
+			compressed: compressed,
+		})
+	}
+
+	/// Returns the size of the compressed payload in bytes.
+	pub fn compressed_len(&self) -> usize {
+		self.compressed.len()
+	}
+
+	/// Returns true if this message can be sent by one endpoint of a connection which
+	/// negotiated `params`, or no compression if None.
+	///
+	/// `client` determines which direction's parameters apply, as in `contexts()`.
+	pub fn is_compatible(params: Option<&DeflateParams>, client: bool) -> bool {
+		PreparedMessage::compresses(params, client).is_ok()
+	}
+
+	/// Returns the data frame to send from one endpoint of a connection which negotiated
+	/// `params`, or no compression if None.
+	///
+	/// The data frame is compressed, with the RSV1 bit set so that the connection's
+	/// `Deflater` passes it on unchanged, unless the connection does not compress
+	/// outgoing messages. Returns an error if the connection keeps its compression
+	/// context across outgoing messages.
+	pub fn dataframe(&self, params: Option<&DeflateParams>, client: bool) -> WebSocketResult<DataFrame> {
+		if try!(PreparedMessage::compresses(params, client)) {
+			let mut dataframe = DataFrame::new(true, self.opcode, self.compressed.clone());
+			dataframe.reserved[0] = true;
+			Ok(dataframe)
+		}
+		else {
+			Ok(DataFrame::new(true, self.opcode, self.payload.clone()))
+		}
+	}
+
+	fn compresses(params: Option<&DeflateParams>, client: bool) -> WebSocketResult<bool> {
+		let params = match params {
+			Some(params) => params,
+			None => return Ok(false),
+		};
+		let (no_context_takeover, window_bits) = if client {
+			(params.client_no_context_takeover, params.client_max_window_bits)
+		} else {
+			(params.server_no_context_takeover, params.server_max_window_bits)
+		};
+		// As in `DeflateParams::middleware()`, a limited window means no compression
+		if window_bits.unwrap_or(MAX_WINDOW_BITS) != MAX_WINDOW_BITS {
+			return Ok(false);
+		}
+		if !no_context_takeover {
+			return Err(WebSocketError::ProtocolError(
+				"Prepared messages need no_context_takeover in the sending direction"
+			));
+		}
+		Ok(true)
+	}
+}
+
+/// Decompresses the payloads of incoming messages.
+pub struct Decompressor {
+	inner: Decompress,
+	no_context_takeover: bool,
+}
+
+impl Decompressor {
+	/// Creates a new Decompressor, which resets its context before every message if
+	/// `no_context_takeover` is set.
+	pub fn new(no_context_takeover: bool) -> Decompressor {
+		Decompressor {
+			inner: Decompress::new(false),
+			no_context_takeover: no_context_takeover,
+		}
+	}
+
+	/// Decompresses the entire payload of one message.
+	pub fn decompress(&mut self, payload: &[u8]) -> WebSocketResult<Vec<u8>> {
+		self.decompress_limited(payload, None)
+	}
+
+	/// Decompresses the entire payload of one message, stopping with a protocol error
+	/// once the decompressed payload grows longer than `max` bytes, if given.
+	pub fn decompress_limited(&mut self, payload: &[u8], max: Option<usize>) -> WebSocketResult<Vec<u8>> {
+		if self.no_context_takeover {
+			self.inner.reset(false);
+		}
+		let mut input = Vec::with_capacity(payload.len() + TRAILER.len());
+		input.extend_from_slice(payload);
+		input.extend_from_slice(&TRAILER);
+
+		let mut output = Vec::with_capacity(payload.len() * 2 + 64);
+		let start = self.inner.total_in();
+		loop {
+			let consumed = (self.inner.total_in() - start) as usize;
+			if output.capacity() - output.len() < 64 {
+				let len = output.len();
+				output.reserve(len + 64);
+			}
+			let status = try!(self.inner.decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+				.map_err(|_| WebSocketError::DataFrameError("Invalid compressed payload")));
+			if max.map_or(false, |max| output.len() > max) {
+				return Err(WebSocketError::ProtocolError("Message too large"));
+			}
+			let consumed = (self.inner.total_in() - start) as usize;
+			if status == Status::StreamEnd ||
+			   (consumed == input.len() && output.len() < output.capacity()) {
+				break;
+			}
+		}
+		Ok(output)
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use header::WebSocketExtensions;
+	use header::extensions::Extension;
+	use hyper::header::Header;
+	use std::str::FromStr;
+
+	#[test]
+	fn test_deflate_params_round_trip() {
+		let extension = Extension::from_str(
+			"permessage-deflate; client_no_context_takeover; client_max_window_bits=15"
+		).unwrap();
+		let params = DeflateParams::from_extension(&extension).unwrap();
+		assert!(params.client_no_context_takeover);
+		assert!(!params.server_no_context_takeover);
+
+		assert_eq!(params.client_max_window_bits, Some(15));
+
+		assert_eq!(
+			params.to_extension().to_string(),
+			"permessage-deflate; client_no_context_takeover; client_max_window_bits=15"
+		);
+		let extension = Extension::from_str("permessage-deflate; server_max_window_bits=16").unwrap();
+		assert!(DeflateParams::from_extension(&extension).is_err());
+	}
+
+	#[test]
+	fn test_negotiate() {
+		let offers = WebSocketExtensions::parse_header(&[
+			b"x-foo, permessage-deflate; server_max_window_bits=10; client_max_window_bits, permessage-deflate".to_vec()
+		]).unwrap();
+		let config = DeflateParams {
+			server_no_context_takeover: true,
+			client_max_window_bits: Some(12),
+			.. DeflateParams::default()
+		};
+		assert_eq!(config.negotiate(&offers).unwrap().to_extension().to_string(),
+			"permessage-deflate; server_no_context_takeover; server_max_window_bits=10; client_max_window_bits=12");
+
+		let offers = WebSocketExtensions::parse_header(&[b"permessage-deflate".to_vec()]).unwrap();
+		assert_eq!(config.negotiate(&offers).unwrap().client_max_window_bits, None);
+		assert_eq!(config.negotiate(&WebSocketExtensions(Vec::new())), None);
+	}
+
+	#[test]
+	fn test_middleware_round_trip() {
+		use dataframe::{DataFrame, Opcode};
+		use message::Message;
+		use receiver::Receiver;
+		use sender::Sender;
+		use hyper::buffer::BufReader;
+		use ws::{Sender as SenderTrait, Receiver as ReceiverTrait};
+
+		let text = "The quick brown fox jumps over the lazy dog. ".repeat(20);
+		for &no_context_takeover in [false, true].iter() {
+			let params = DeflateParams {
+				client_no_context_takeover: no_context_takeover,
+				.. DeflateParams::default()
+			};
+			let (deflater, _) = params.middleware(true);
+			let mut sender = Sender::new(Vec::new(), true);
+			sender.add_middleware(deflater);
+			sender.set_auto_fragment(Some(8));
+			for _ in 0..3 {
+				sender.send_message(&Message::text(&text[..])).unwrap();
+			}
+			// A message which is fragmented before being compressed
+			sender.send_dataframe(&DataFrame::new(false, Opcode::Text, b"Hello ".to_vec())).unwrap();
+			sender.send_dataframe(&DataFrame::new(true, Opcode::Continuation, b"world".to_vec())).unwrap();
+			sender.send_message(&Message::ping(b"ping".to_vec())).unwrap();
+			let data = sender.get_ref().clone();
+			assert!(data.len() < text.len());
+			// The first data frame is compressed and fragmented
+			assert_eq!(data[0], 0x41);
+
+			let (_, inflater) = params.middleware(false);
+			let mut receiver = Receiver::new(BufReader::new(&data[..]), true);
+			receiver.add_middleware(inflater);
+			for _ in 0..3 {
+				let message: Message = receiver.recv_message().unwrap();
+				assert_eq!(message, Message::text(&text[..]));
+			}
+			let message: Message = receiver.recv_message().unwrap();
+			assert_eq!(message, Message::text("Hello world"));
+			let message: Message = receiver.recv_message().unwrap();
+			assert_eq!(message, Message::ping(b"ping".to_vec()));
+		}
+	}
+
+	#[test]
+	fn test_message_meta() {
+		use message::Message;
+		use receiver::Receiver;
+		use sender::Sender;
+		use hyper::buffer::BufReader;
+		use ws::{MessageMeta, Receiver as ReceiverTrait};
+
+		let text = "The quick brown fox jumps over the lazy dog. ".repeat(4);
+		let params = DeflateParams::default();
+		let (deflater, _) = params.middleware(true);
+		let mut sender = Sender::new(Vec::new(), true);
+		sender.add_middleware(deflater);
+		sender.send_message_fragmented(&Message::text(&text[..]), 8).unwrap();
+		let mut data = sender.get_ref().clone();
+		let mut plain = Sender::new(Vec::new(), true);
+		plain.send_message_fragmented(&Message::text("plain"), 2).unwrap();
+		data.extend_from_slice(plain.get_ref());
+
+		let (_, inflater) = params.middleware(false);
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), true);
+		receiver.add_middleware(inflater);
+		let (message, meta): (Message, _) = receiver.recv_message_with_meta().unwrap();
+		assert_eq!(message, Message::text(&text[..]));
+		assert!(meta.compressed);
+		assert!(meta.frame_count > 1);
+		let (message, meta): (Message, _) = receiver.recv_message_with_meta().unwrap();
+		assert_eq!(message, Message::text("plain"));
+		assert_eq!(meta, MessageMeta { frame_count: 3, compressed: false });
+	}
+
+	#[test]
+	fn test_inflater_message_size_limit() {
+		use message::Message;
+		use receiver::Receiver;
+		use sender::Sender;
+		use hyper::buffer::BufReader;
+		use result::WebSocketError;
+		use ws::{Sender as SenderTrait, Receiver as ReceiverTrait};
+
+		let params = DeflateParams {
+			client_no_context_takeover: true,
+			server_no_context_takeover: true,
+			.. DeflateParams::default()
+		};
+		// Data which barely compresses, in many small compressed fragments
+		let mut seed = 1u32;
+		let noise: Vec<u8> = (0..4000).map(|_| {
+			seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+			(seed >> 16) as u8
+		}).collect();
+		let (deflater, _) = params.middleware(true);
+		let mut sender = Sender::new(Vec::new(), true);
+		sender.add_middleware(deflater);
+		sender.set_auto_fragment(Some(64));
+		sender.send_message(&Message::binary(noise)).unwrap();
+		sender.set_auto_fragment(None);
+		// A small message which decompresses to far more than the limit
+		assert!(Compressor::new(true).compress(&vec![0; 1 << 20]).unwrap().len() < 2000);
+		sender.send_message(&Message::binary(vec![0; 1 << 20])).unwrap();
+		sender.send_message(&Message::text("Hello")).unwrap();
+		let data = sender.get_ref().clone();
+
+		let (_, inflater) = params.middleware(false);
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), true);
+		receiver.add_middleware(inflater);
+		receiver.set_max_message_size(Some(2000));
+		for _ in 0..2 {
+			let result: WebSocketResult<Message> = receiver.recv_message();
+			assert_eq!(result.unwrap_err(), WebSocketError::ProtocolError("Message too large"));
+		}
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("Hello"));
+	}
+
+	#[test]
+	fn test_inflater_drops_unfinished_message() {
+		let (_, mut inflater) = DeflateParams::default().middleware(false);
+		let mut first = DataFrame::new(false, Opcode::Text, vec![0xf2, 0x48]);
+		first.reserved[0] = true;
+		assert_eq!(inflater.incoming(first).unwrap(), vec![]);
+		// An uncompressed message abandons the compressed one
+		let text = DataFrame::new(false, Opcode::Text, b"Hel".to_vec());
+		assert_eq!(inflater.incoming(text.clone()).unwrap(), vec![text]);
+		let rest = DataFrame::new(true, Opcode::Continuation, b"lo".to_vec());
+		assert_eq!(inflater.incoming(rest.clone()).unwrap(), vec![rest]);
+	}
+
+	#[test]
+	fn test_limited_window_sends_uncompressed() {
+		use message::Message;
+		use sender::Sender;
+		use ws::Sender as SenderTrait;
+
+		let params = DeflateParams { client_max_window_bits: Some(10), .. DeflateParams::default() };
+		let (deflater, _) = params.middleware(true);
+		let mut sender = Sender::new(Vec::new(), false);
+		sender.add_middleware(deflater);
+		sender.send_message(&Message::text("aaaa")).unwrap();
+		assert_eq!(sender.get_ref(), &vec![0x81, 0x04, b'a', b'a', b'a', b'a']);
+	}
+
+	#[test]
+	fn test_decompress_rfc_example() {
+		// RFC 7692 7.2.3.1
+		let mut decompressor = Decompressor::new(false);
+		let payload = [0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00];
+		assert_eq!(&decompressor.decompress(&payload).unwrap()[..], &b"Hello"[..]);
+		assert_eq!(&decompressor.decompress(&[0xf2, 0x00, 0x11, 0x00, 0x00]).unwrap()[..], &b"Hello"[..]);
+	}
+
+	#[test]
+	fn test_no_context_takeover_decompressible_by_fresh_peer() {
+		let params = DeflateParams {
+			server_no_context_takeover: false,
+			client_no_context_takeover: true,
+			.. DeflateParams::default()
+		};
+		let (mut compressor, _) = params.contexts(true);
+		let message = b"The quick brown fox jumps over the lazy dog";
+
+		for _ in 0..3 {
+			let compressed = compressor.compress(message).unwrap();
+			// The peer resets its decompressor for every message
+			let mut decompressor = Decompressor::new(true);
+			assert_eq!(&decompressor.decompress(&compressed).unwrap()[..], &message[..]);
+		}
+	}
+
+	#[test]
+	fn test_context_takeover_round_trip() {
+		let (mut compressor, _) = DeflateParams::default().contexts(true);
+		let (_, mut decompressor) = DeflateParams::default().contexts(false);
+		let message = b"The quick brown fox jumps over the lazy dog";
+
+		let first = compressor.compress(message).unwrap();
+		let second = compressor.compress(message).unwrap();
+		// The second message refers back to the first
+		assert!(second.len() < first.len());
+
+		assert_eq!(&decompressor.decompress(&first).unwrap()[..], &message[..]);
+		assert_eq!(&decompressor.decompress(&second).unwrap()[..], &message[..]);
+	}
+
+	#[test]
+	fn test_prepared_message() {
+		let message = Message::text("The quick brown fox jumps over the lazy dog");
+		let prepared = PreparedMessage::new(&message).unwrap();
+		assert!(PreparedMessage::new(&Message::ping(Vec::new())).is_err());
+
+		let params = DeflateParams {
+			server_no_context_takeover: true,
+			.. DeflateParams::default()
+		};
+		assert!(PreparedMessage::is_compatible(Some(&params), false));
+		assert!(!PreparedMessage::is_compatible(Some(&params), true));
+		assert!(prepared.dataframe(Some(&params), true).is_err());
+
+		// The client keeps its context, and sees the same data frame for every send
+		let (_, mut decompressor) = params.contexts(true);
+		for _ in 0..2 {
+			let dataframe = prepared.dataframe(Some(&params), false).unwrap();
+			assert!(dataframe.reserved[0]);
+			assert_eq!(dataframe.data.len(), prepared.compressed_len());
+			assert_eq!(&decompressor.decompress(&dataframe.data).unwrap()[..], &message.payload[..]);
+		}
+
+		// Without compression, or with a limited window, it is sent as it is
+		let limited = DeflateParams {
+			server_max_window_bits: Some(10),
+			.. DeflateParams::default()
+		};
+		for params in [None, Some(&limited)].iter() {
+			let dataframe = prepared.dataframe(*params, false).unwrap();
+			assert_eq!(dataframe, DataFrame::new(true, Opcode::Text, message.payload.to_vec()));
+		}
+	}
+
+	#[test]
+	fn test_send_prepared_through_deflater() {
+		use dataframe::DataFrame;
+		use hyper::buffer::BufReader;
+		use sender::Sender;
+
+		let message = Message::text("The quick brown fox jumps over the lazy dog");
+		let prepared = PreparedMessage::new(&message).unwrap();
+		let params = DeflateParams {
+			server_no_context_takeover: true,
+			.. DeflateParams::default()
+		};
+		let (deflater, _) = params.middleware(false);
+		let mut sender = Sender::new(Vec::new(), false);
+		sender.add_middleware(deflater);
+		for _ in 0..2 {
+			sender.send_prepared(&prepared, Some(&params)).unwrap();
+		}
+
+		let data = sender.get_ref().clone();
+		let mut reader = BufReader::new(&data[..]);
+		let (_, mut decompressor) = params.contexts(true);
+		for _ in 0..2 {
+			let dataframe = DataFrame::read_dataframe(&mut reader, false).unwrap();
+			assert!(dataframe.reserved[0]);
+			assert_eq!(&decompressor.decompress(&dataframe.data).unwrap()[..], &message.payload[..]);
+		}
+	}
+}
@@ -0,0 +1,114 @@
+//! Provides helpers for application protocols which carry length-prefixed payloads
+//! (e.g. Protocol Buffers or MessagePack) inside binary messages.
+//!
+//! This is purely an application-level convention: each binary message carries
+//! exactly one payload, preceded by its length as a 32-bit unsigned integer.
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use message::{Message, Type};
+use result::{WebSocketResult, WebSocketError};
+use ws;
+
+/// The encoding of the length prefix.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrefixFormat {
+	/// A little endian `u32`.
+	U32LittleEndian,
+	/// A big endian (network order) `u32`.
+	U32BigEndian,
+}
+
+/// Encodes and decodes length-prefixed payloads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LengthPrefixed {
+	format: PrefixFormat,
+	max_length: usize,
+}
+
+impl LengthPrefixed {
+	/// Creates a new LengthPrefixed using the given prefix format, which rejects
+	/// payloads longer than `max_length` bytes.
+	pub fn new(format: PrefixFormat, max_length: usize) -> LengthPrefixed {
+		LengthPrefixed {
+			format: format,
+			max_length: max_length,
+		}
+	}
+	/// Returns the prefix format.
+	pub fn format(&self) -> PrefixFormat {
+		self.format
+	}
+	/// Returns the maximum payload length.
+	pub fn max_length(&self) -> usize {
+		self.max_length
+	}
+	/// Prepends the length prefix to a payload.
+	pub fn encode(&self, payload: &[u8]) -> WebSocketResult<Vec<u8>> {
+		if payload.len() > self.max_length || payload.len() > u32::max_value() as usize {
+			return Err(WebSocketError::DataFrameError("Payload exceeds the maximum length"));
+		}
+		let mut data = vec![0; 4];
+		match self.format {
+			PrefixFormat::U32LittleEndian => LittleEndian::write_u32(&mut data, payload.len() as u32),
+			PrefixFormat::U32BigEndian => BigEndian::write_u32(&mut data, payload.len() as u32),
+		}
+		data.extend_from_slice(payload);
+		Ok(data)
+	}
+	/// Checks the length prefix of some data and returns the payload following it.
+	///
+	/// The prefix must match the length of the remaining data exactly.
+	pub fn decode<'a>(&self, data: &'a [u8]) -> WebSocketResult<&'a [u8]> {
+		if data.len() < 4 {
+			return Err(WebSocketError::DataFrameError("Missing length prefix"));
+		}
+		let length = match self.format {
+			PrefixFormat::U32LittleEndian => LittleEndian::read_u32(&data[..4]),
+			PrefixFormat::U32BigEndian => BigEndian::read_u32(&data[..4]),
+		} as usize;
+		if length > self.max_length {
+			return Err(WebSocketError::DataFrameError("Payload exceeds the maximum length"));
+		}
+		if length != data.len() - 4 {
+			return Err(WebSocketError::DataFrameError("Length prefix does not match payload"));
+		}
+		Ok(&data[4..])
+	}
+	/// Creates a binary message carrying the given payload.
+	pub fn encode_message(&self, payload: &[u8]) -> WebSocketResult<Message<'static>> {
+		Ok(Message::binary(try!(self.encode(payload))))
+	}
+	/// Returns the payload carried by a binary message.
+	pub fn decode_message<'a>(&self, message: &'a Message) -> WebSocketResult<&'a [u8]> {
+		if message.opcode != Type::Binary {
+			return Err(WebSocketError::ProtocolError("Expected a binary message"));
+		}
+		self.decode(&message.payload)
+	}
+	/// Sends the given payload as a binary message.
+	pub fn send<S>(&self, sender: &mut S, payload: &[u8]) -> WebSocketResult<()>
+	where S: ws::Sender {
+		sender.send_message(&try!(self.encode_message(payload)))
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use message::Message;
+
+	#[test]
+	fn test_length_prefixed() {
+		let framing = LengthPrefixed::new(PrefixFormat::U32BigEndian, 4);
+		let message = framing.encode_message(b"abc").unwrap();
+		assert_eq!(&message.payload[..], &[0, 0, 0, 3, b'a', b'b', b'c'][..]);
+		assert_eq!(framing.decode_message(&message).unwrap(), &b"abc"[..]);
+
+		let framing = LengthPrefixed::new(PrefixFormat::U32LittleEndian, 4);
+		assert_eq!(framing.encode(b"ab").unwrap(), vec![2, 0, 0, 0, b'a', b'b']);
+		assert!(framing.encode(b"abcde").is_err());
+		assert!(framing.decode(&[3, 0, 0, 0, b'a']).is_err());
+		assert!(framing.decode(&[5, 0, 0, 0, 1, 2, 3, 4, 5]).is_err());
+		assert!(framing.decode_message(&Message::text("\u{2}\0\0\0ab")).is_err());
+	}
+}
@@ -23,8 +23,9 @@ impl Debug for WebSocketAccept {
 impl FromStr for WebSocketAccept {
 	type Err = WebSocketError;
 
+	/// Parses a Base64 encoded value, ignoring any surrounding whitespace.
 	fn from_str(accept: &str) -> WebSocketResult<WebSocketAccept> {
-		match accept.from_base64() {
+		match accept.trim().from_base64() {
 			Ok(vec) => {
 				if vec.len() != 20 {
 					return Err(WebSocketError::ProtocolError(
@@ -101,6 +102,12 @@ mod tests {
 		
 		assert_eq!(&headers.to_string()[..], "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n");
 	}
+	#[test]
+	fn test_header_accept_whitespace() {
+		let value = vec![b" s3pPLMBiTxaQ9kYGzzhZRbK+xOo= \t".to_vec()];
+		let accept: WebSocketAccept = Header::parse_header(&value[..]).unwrap();
+		assert_eq!(accept.serialize(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+	}
 	#[bench]
 	fn bench_header_accept_new(b: &mut test::Bencher) {
 		let key = WebSocketKey::new();
@@ -20,6 +20,47 @@ impl Deref for WebSocketExtensions {
     }
 }
 
+impl WebSocketExtensions {
+	/// Matches the extensions accepted by a server against the offers in this header,
+	/// returning the offer which each accepted extension corresponds to.
+	///
+	/// A client may offer the same extension several times with different parameters,
+	/// in order of preference, and the server accepts at most one of them. The accepted
+	/// extension is matched to the offer with the same parameter names, or failing that
+	/// the offer whose parameter names differ the least, preferring earlier offers.
+	///
+	/// An error is returned if the server accepted an extension which was not offered,
+	/// or accepted the same extension more than once.
+	pub fn match_accepted<'a>(&'a self, accepted: &WebSocketExtensions) -> WebSocketResult<Vec<&'a Extension>> {
+		let mut matched = Vec::with_capacity(accepted.len());
+		for (i, extension) in accepted.iter().enumerate() {
+			if accepted[..i].iter().any(|previous| previous.name == extension.name) {
+				return Err(WebSocketError::ResponseError(
+					"Extension was accepted more than once"
+				));
+			}
+			let best = self.iter()
+				.filter(|offer| offer.name == extension.name)
+				.min_by_key(|offer| param_difference(offer, extension));
+			match best {
+				Some(offer) => matched.push(offer),
+				None => return Err(WebSocketError::ResponseError(
+					"Extension was accepted but not offered"
+				)),
+			}
+		}
+		Ok(matched)
+	}
+}
+
+/// Counts the parameter names present in only one of two extensions.
+fn param_difference(a: &Extension, b: &Extension) -> usize {
+	let missing = |from: &Extension, to: &Extension| {
+		from.params.iter().filter(|param| !to.params.iter().any(|other| other.name == param.name)).count()
+	};
+	missing(a, b) + missing(b, a)
+}
+
 #[derive(PartialEq, Clone, Debug)]
 /// A WebSocket extension
 pub struct Extension {
@@ -52,7 +93,7 @@ impl FromStr for Extension {
 				)),
 			},
 			params: ext.map(|x| {
-				let mut pair = x.splitn(1, '=').map(|x| x.trim().to_string());
+				let mut pair = x.splitn(2, '=').map(|x| x.trim().to_string());
 				
 				Parameter {
 					name: pair.next().unwrap(),
@@ -136,6 +177,27 @@ mod tests {
 		
 		assert_eq!(&headers.to_string()[..], "Sec-WebSocket-Extensions: foo, bar; baz; qux=quux\r\n");
 	}
+	#[test]
+	fn test_match_accepted_extensions() {
+		let value = vec![b"permessage-deflate; server_no_context_takeover; client_max_window_bits, permessage-deflate, foo".to_vec()];
+		let offers: WebSocketExtensions = Header::parse_header(&value[..]).unwrap();
+
+		let parse = |value: &[u8]| -> WebSocketExtensions {
+			Header::parse_header(&[value.to_vec()][..]).unwrap()
+		};
+		// The server rejected the preferred parameters
+		let accepted = offers.match_accepted(&parse(b"permessage-deflate, foo")).unwrap();
+		assert_eq!(accepted, vec![&offers[1], &offers[2]]);
+		// The server accepted the preferred parameters, with a value of its choosing
+		let accepted = offers.match_accepted(&parse(b"permessage-deflate; server_no_context_takeover; client_max_window_bits=10")).unwrap();
+		assert_eq!(accepted, vec![&offers[0]]);
+		// Ties go to the earlier offer
+		let accepted = offers.match_accepted(&parse(b"permessage-deflate; server_no_context_takeover")).unwrap();
+		assert_eq!(accepted, vec![&offers[0]]);
+
+		assert!(offers.match_accepted(&parse(b"bar")).is_err());
+		assert!(offers.match_accepted(&parse(b"foo, foo")).is_err());
+	}
 	#[bench]
 	fn bench_header_extensions_parse(b: &mut test::Bencher) {
 		let value = vec![b"foo, bar; baz; qux=quux".to_vec()];
@@ -43,6 +43,7 @@ extern crate rustc_serialize as serialize;
 extern crate openssl;
 extern crate rand;
 extern crate byteorder;
+extern crate flate2;
 
 #[macro_use]
 extern crate bitflags;
@@ -52,7 +53,7 @@ extern crate test;
 
 pub use self::client::Client;
 pub use self::server::Server;
-pub use self::dataframe::DataFrame;
+pub use self::dataframe::{DataFrame, Opcode};
 pub use self::message::Message;
 pub use self::stream::WebSocketStream;
 pub use self::ws::Sender;
@@ -68,3 +69,12 @@ pub mod stream;
 pub mod header;
 pub mod receiver;
 pub mod sender;
+pub mod ratelimit;
+pub mod middleware;
+pub mod deflate;
+pub mod framing;
+pub mod shared;
+pub mod coalesce;
+pub mod ring;
+pub mod reconnect;
+pub mod metrics;
@@ -8,8 +8,6 @@ use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use ws::util::bytes_to_string;
 use ws;
 
-const FALSE_RESERVED_BITS: &'static [bool; 3] = &[false; 3];
-
 /// Valid types of messages (in the default implementation)
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Type {
@@ -25,6 +23,87 @@ pub enum Type {
 	Close = 8,
 }
 
+/// A close status code (RFC6455 7.4).
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum CloseCode {
+	/// 1000: Normal closure.
+	Normal,
+	/// 1001: The endpoint is going away, such as a server shutting down.
+	GoingAway,
+	/// 1002: The endpoint received data which violates the protocol.
+	ProtocolError,
+	/// 1003: The endpoint received a type of data it cannot accept.
+	Unsupported,
+	/// 1005: No status code was present. Must not be sent in a close message.
+	NoStatus,
+	/// 1006: The connection was closed without a close message. Must not be sent in a
+	/// close message.
+	Abnormal,
+	/// 1007: The endpoint received a message whose data was inconsistent with its type,
+	/// such as invalid UTF-8 in a text message.
+	InvalidPayload,
+	/// 1008: The endpoint received a message which violates its policy.
+	PolicyViolation,
+	/// 1009: The endpoint received a message which is too big to process.
+	TooBig,
+	/// 1010: The client expected the server to negotiate an extension which it did not.
+	MandatoryExt,
+	/// 1011: The server encountered an unexpected condition.
+	InternalError,
+	/// 1012: The server is restarting.
+	ServiceRestart,
+	/// 1013: The server is overloaded, and the client should try again later.
+	TryAgainLater,
+	/// 1015: The TLS handshake failed. Must not be sent in a close message.
+	TlsHandshake,
+	/// Any other status code.
+	Other(u16),
+}
+
+impl From<u16> for CloseCode {
+	fn from(code: u16) -> CloseCode {
+		match code {
+			1000 => CloseCode::Normal,
+			1001 => CloseCode::GoingAway,
+			1002 => CloseCode::ProtocolError,
+			1003 => CloseCode::Unsupported,
+			1005 => CloseCode::NoStatus,
+			1006 => CloseCode::Abnormal,
+			1007 => CloseCode::InvalidPayload,
+			1008 => CloseCode::PolicyViolation,
+			1009 => CloseCode::TooBig,
+			1010 => CloseCode::MandatoryExt,
+			1011 => CloseCode::InternalError,
+			1012 => CloseCode::ServiceRestart,
+			1013 => CloseCode::TryAgainLater,
+			1015 => CloseCode::TlsHandshake,
+			code => CloseCode::Other(code),
+		}
+	}
+}
+
+impl From<CloseCode> for u16 {
+	fn from(code: CloseCode) -> u16 {
+		match code {
+			CloseCode::Normal => 1000,
+			CloseCode::GoingAway => 1001,
+			CloseCode::ProtocolError => 1002,
+			CloseCode::Unsupported => 1003,
+			CloseCode::NoStatus => 1005,
+			CloseCode::Abnormal => 1006,
+			CloseCode::InvalidPayload => 1007,
+			CloseCode::PolicyViolation => 1008,
+			CloseCode::TooBig => 1009,
+			CloseCode::MandatoryExt => 1010,
+			CloseCode::InternalError => 1011,
+			CloseCode::ServiceRestart => 1012,
+			CloseCode::TryAgainLater => 1013,
+			CloseCode::TlsHandshake => 1015,
+			CloseCode::Other(code) => code,
+		}
+	}
+}
+
 /// Represents a WebSocket message.
 ///
 /// This message also has the ability to not own its payload, and stores its entire payload in
@@ -42,6 +121,8 @@ pub struct Message<'a> {
 	pub cd_status_code: Option<u16>,
     /// Main payload
 	pub payload: Cow<'a, [u8]>,
+    /// The reserved bits (RFC6455 5.2) to send this message with, for use by extensions
+	pub reserved: [bool; 3],
 }
 
 impl<'a> Message<'a> {
@@ -50,10 +131,13 @@ impl<'a> Message<'a> {
 			opcode: code,
 			cd_status_code: status,
 			payload: payload,
+			reserved: [false; 3],
 		}
 	}
 
     /// Create a new WebSocket message with text data
+    ///
+    /// Borrowed text is not copied, even when the message is masked for sending.
 	pub fn text<S>(data: S) -> Self
 	where S: Into<Cow<'a, str>> {
 		Message::new(Type::Text, None, match data.into() {
@@ -63,6 +147,9 @@ impl<'a> Message<'a> {
 	}
 
     /// Create a new WebSocket message with binary data
+    ///
+    /// Borrowed data, such as a `&[u8]`, is not copied, even when the message is
+    /// masked for sending.
 	pub fn binary<B>(data: B) -> Self
 	where B: IntoCowBytes<'a> {
 		Message::new(Type::Binary, None, data.into())
@@ -109,6 +196,84 @@ impl<'a> Message<'a> {
             Err(())
         }
     }
+
+    /// Sets the reserved bits this message is sent with.
+    ///
+    /// These bits belong to extensions, and must only be set if an extension which
+    /// defines them has been negotiated.
+	pub fn with_reserved(mut self, reserved: [bool; 3]) -> Self {
+		self.reserved = reserved;
+		self
+	}
+
+    /// Forms a message from a series of data frames, keeping the reserved bits of the
+    /// first data frame instead of rejecting them.
+    ///
+    /// This is for extensions which work with whole messages, and should only be used
+    /// once such an extension has been negotiated. Reserved bits on the continuation
+    /// frames are still rejected, and payloads are checked to be valid UTF-8 as usual.
+	pub fn from_dataframes_with_reserved<D>(frames: Vec<D>) -> WebSocketResult<Self>
+	where D: ws::dataframe::DataFrame {
+		Message::from_frames(frames, true, true)
+	}
+
+	fn from_frames<D>(frames: Vec<D>, validate_utf8: bool, keep_reserved: bool) -> WebSocketResult<Self>
+    where D: ws::dataframe::DataFrame {
+		let opcode = try!(frames.first().ok_or(WebSocketError::ProtocolError(
+			"No dataframes provided"
+		)).map(|d| d.opcode()));
+		let reserved = if keep_reserved {
+			*frames[0].reserved()
+		} else {
+			[false; 3]
+		};
+
+		let mut data = Vec::new();
+
+		for (i, dataframe) in frames.iter().enumerate() {
+			if i > 0 && dataframe.opcode() != Opcode::Continuation as u8 {
+				return Err(WebSocketError::ProtocolError(
+					"Unexpected non-continuation data frame"
+				));
+			}
+			if *dataframe.reserved() != [false; 3] && !(keep_reserved && i == 0) {
+				return Err(WebSocketError::ProtocolError(
+					"Unsupported reserved bits received"
+				));
+			}
+			data.extend(dataframe.payload().iter().cloned());
+		}
+
+		let message = match Opcode::new(opcode) {
+			Some(Opcode::Text) => {
+				if validate_utf8 {
+					Message::text(try!(bytes_to_string(&data[..])))
+				} else {
+					Message::new(Type::Text, None, Cow::Owned(data))
+				}
+			}
+			Some(Opcode::Binary) => Message::binary(data),
+			Some(Opcode::Close) => {
+				if data.len() > 0 {
+					let status_code = try!((&data[..]).read_u16::<BigEndian>());
+					if validate_utf8 {
+						let reason = try!(bytes_to_string(&data[2..]));
+						Message::close_because(status_code, reason)
+					} else {
+						Message::new(Type::Close, Some(status_code), Cow::Owned(data[2..].to_vec()))
+					}
+				} else {
+					Message::close()
+				}
+			}
+			Some(Opcode::Ping) => Message::ping(data),
+			Some(Opcode::Pong) => Message::pong(data),
+			_ => return Err(WebSocketError::ProtocolError(
+				"Unsupported opcode received"
+			)),
+		};
+		Ok(message.with_reserved(reserved))
+	}
 }
 
 impl<'a> ws::dataframe::DataFrame for Message<'a> {
@@ -124,10 +289,13 @@ impl<'a> ws::dataframe::DataFrame for Message<'a> {
 
 	#[inline(always)]
     fn reserved<'b>(&'b self) -> &'b [bool; 3] {
-		FALSE_RESERVED_BITS
+		&self.reserved
     }
 
 	fn payload<'b>(&'b self) -> Cow<'b, [u8]> {
+		if self.cd_status_code.is_none() {
+			return Cow::Borrowed(&self.payload);
+		}
 		let mut buf = Vec::with_capacity(self.size());
 		self.write_payload(&mut buf).ok();
 		Cow::Owned(buf)
@@ -162,44 +330,14 @@ impl<'a, 'b> ws::Message<'b, &'b Message<'a>> for Message<'a> {
 	/// Attempt to form a message from a series of data frames
 	fn from_dataframes<D>(frames: Vec<D>) -> WebSocketResult<Self>
     where D: ws::dataframe::DataFrame {
-		let opcode = try!(frames.first().ok_or(WebSocketError::ProtocolError(
-			"No dataframes provided"
-		)).map(|d| d.opcode()));
-
-		let mut data = Vec::new();
-
-		for (i, dataframe) in frames.iter().enumerate() {
-			if i > 0 && dataframe.opcode() != Opcode::Continuation as u8 {
-				return Err(WebSocketError::ProtocolError(
-					"Unexpected non-continuation data frame"
-				));
-			}
-			if *dataframe.reserved() != [false; 3] {
-				return Err(WebSocketError::ProtocolError(
-					"Unsupported reserved bits received"
-				));
-			}
-			data.extend(dataframe.payload().iter().cloned());
-		}
+		Message::from_frames(frames, true, false)
+	}
 
-		Ok(match Opcode::new(opcode) {
-			Some(Opcode::Text) => Message::text(try!(bytes_to_string(&data[..]))),
-			Some(Opcode::Binary) => Message::binary(data),
-			Some(Opcode::Close) => {
-				if data.len() > 0 {
-					let status_code = try!((&data[..]).read_u16::<BigEndian>());
-					let reason = try!(bytes_to_string(&data[2..]));
-					Message::close_because(status_code, reason)
-				} else {
-					Message::close()
-				}
-			}
-			Some(Opcode::Ping) => Message::ping(data),
-			Some(Opcode::Pong) => Message::pong(data),
-			_ => return Err(WebSocketError::ProtocolError(
-				"Unsupported opcode received"
-			)),
-		})
+	/// Attempt to form a message from a series of data frames, without checking that
+	/// text payloads and close reasons are valid UTF-8
+	fn from_dataframes_unvalidated<D>(frames: Vec<D>) -> WebSocketResult<Self>
+    where D: ws::dataframe::DataFrame {
+		Message::from_frames(frames, false, false)
 	}
 }
 
@@ -227,3 +365,15 @@ impl<'a> IntoCowBytes<'a> for Cow<'a, [u8]> {
 		self
 	}
 }
+
+impl<'a> IntoCowBytes<'a> for &'a str {
+	fn into(self) -> Cow<'a, [u8]> {
+		Cow::Borrowed(self.as_bytes())
+	}
+}
+
+impl<'a> IntoCowBytes<'a> for String {
+	fn into(self) -> Cow<'a, [u8]> {
+		Cow::Owned(self.into_bytes())
+	}
+}
@@ -0,0 +1,77 @@
+//! Provides hooks for gathering metrics on the traffic of a connection.
+use dataframe::Opcode;
+
+/// Callbacks made as data frames and messages cross a connection, for keeping counts
+/// of traffic. See `Client::set_metrics()`.
+///
+/// Every method does nothing by default. Lengths are of payloads as they are sent or
+/// received, so they are compressed if permessage-deflate is in use, and exclude the
+/// data frame headers. Control frames are reported as data frames but not as messages.
+/// The callbacks are made on the thread sending or receiving, so they should be quick;
+/// atomic counters are a good fit.
+pub trait Metrics: Send + Sync {
+	/// Called after a data frame has been written.
+	fn on_frame_sent(&self, _opcode: Opcode, _len: usize) {}
+	/// Called after a data frame has been read.
+	fn on_frame_received(&self, _opcode: Opcode, _len: usize) {}
+	/// Called after the last data frame of a text or binary message has been written,
+	/// with the total payload length of the message.
+	fn on_message_sent(&self, _len: usize) {}
+	/// Called after the last data frame of a text or binary message has been read,
+	/// with the total payload length of the message.
+	fn on_message_received(&self, _len: usize) {}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+	use hyper::buffer::BufReader;
+	use client::{Client, Receiver, Sender};
+	use message::Message;
+
+	#[derive(Default)]
+	struct Events(Mutex<Vec<String>>);
+
+	impl Metrics for Events {
+		fn on_frame_sent(&self, opcode: Opcode, len: usize) {
+			self.0.lock().unwrap().push(format!("frame sent {:?} {}", opcode, len));
+		}
+		fn on_frame_received(&self, opcode: Opcode, len: usize) {
+			self.0.lock().unwrap().push(format!("frame received {:?} {}", opcode, len));
+		}
+		fn on_message_sent(&self, len: usize) {
+			self.0.lock().unwrap().push(format!("message sent {}", len));
+		}
+		fn on_message_received(&self, len: usize) {
+			self.0.lock().unwrap().push(format!("message received {}", len));
+		}
+	}
+
+	#[test]
+	fn test_metrics() {
+		let data = [0x89, 0x02, b'h', b'i', 0x01, 0x01, b'a', 0x80, 0x02, b'b', b'c'];
+		let mut client = Client::new(
+			Sender::new(Vec::new(), false),
+			Receiver::new(BufReader::new(&data[..]), false)
+		);
+		let events = Arc::new(Events::default());
+		client.set_metrics(Some(events.clone()));
+
+		client.send_message(&Message::text("hello")).unwrap();
+		client.send_message(&Message::ping(vec![1, 2, 3])).unwrap();
+		assert_eq!(client.recv_until(|_| true, None).unwrap(), Message::ping(b"hi".to_vec()));
+		assert_eq!(client.recv_until(|_| true, None).unwrap(), Message::text("abc"));
+
+		assert_eq!(*events.0.lock().unwrap(), vec![
+			"frame sent Text 5",
+			"message sent 5",
+			"frame sent Ping 3",
+			"frame received Ping 2",
+			"frame sent Pong 2",
+			"frame received Text 1",
+			"frame received Continuation 2",
+			"message received 3",
+		]);
+	}
+}
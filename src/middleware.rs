@@ -0,0 +1,143 @@
+//! Provides a way to intercept the data frames sent and received by the default
+//! Sender and Receiver.
+//!
+//! Middleware is registered on a `Sender` or `Receiver` with `add_middleware()`, and
+//! each registered middleware sees every data frame in the order it was registered.
+//! A middleware may pass a frame on unchanged, transform it, drop it (by returning
+//! no frames) or inject additional frames (by returning more than one).
+use std::borrow::Cow;
+
+use dataframe::{DataFrame, Opcode};
+use result::{WebSocketResult, WebSocketError};
+use ws::dataframe::DataFrame as DataFrameable;
+
+/// An interceptor for outgoing and incoming data frames.
+///
+/// Both methods pass the frame through unchanged by default.
+pub trait Middleware: Send {
+	/// Called with each outgoing data frame before it is written, returning the
+	/// frames to pass on in its place.
+	fn outgoing(&mut self, dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+		Ok(vec![dataframe])
+	}
+	/// Called with each incoming data frame after it is read, returning the
+	/// frames to pass on in its place.
+	fn incoming(&mut self, dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+		Ok(vec![dataframe])
+	}
+	/// Called with the Receiver's limit on the size of received messages when the
+	/// middleware is added to it, and whenever the limit changes, so that middleware
+	/// which gathers or expands messages can apply it. Does nothing by default.
+	fn set_max_message_size(&mut self, _max: Option<usize>) {}
+}
+
+/// An ordered list of middleware.
+pub struct MiddlewareChain {
+	inner: Vec<Box<Middleware>>,
+}
+
+impl MiddlewareChain {
+	/// Creates a new, empty MiddlewareChain.
+	pub fn new() -> MiddlewareChain {
+		MiddlewareChain {
+			inner: Vec::new(),
+		}
+	}
+	/// Adds a middleware to the end of this chain.
+	pub fn push<M>(&mut self, middleware: M)
+	where M: Middleware + 'static {
+		self.inner.push(Box::new(middleware));
+	}
+	/// Passes the limit on the size of received messages to every middleware.
+	pub fn set_max_message_size(&mut self, max: Option<usize>) {
+		for middleware in self.inner.iter_mut() {
+			middleware.set_max_message_size(max);
+		}
+	}
+	/// Returns true if there is no middleware in this chain.
+	pub fn is_empty(&self) -> bool {
+		self.inner.is_empty()
+	}
+	/// Passes an outgoing data frame through every middleware in order.
+	pub fn outgoing(&mut self, dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+		let mut frames = vec![dataframe];
+		for middleware in self.inner.iter_mut() {
+			let mut next = Vec::with_capacity(frames.len());
+			for frame in frames {
+				next.extend(try!(middleware.outgoing(frame)));
+			}
+			frames = next;
+		}
+		Ok(frames)
+	}
+	/// Passes an incoming data frame through every middleware in order.
+	pub fn incoming(&mut self, dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+		let mut frames = vec![dataframe];
+		for middleware in self.inner.iter_mut() {
+			let mut next = Vec::with_capacity(frames.len());
+			for frame in frames {
+				next.extend(try!(middleware.incoming(frame)));
+			}
+			frames = next;
+		}
+		Ok(frames)
+	}
+}
+
+/// Copies any data frame into an owned `DataFrame`.
+pub fn to_owned_dataframe<D>(dataframe: &D) -> WebSocketResult<DataFrame>
+where D: DataFrameable {
+	let opcode = try!(Opcode::new(dataframe.opcode()).ok_or(
+		WebSocketError::DataFrameError("Invalid data frame opcode")
+	));
+	Ok(DataFrame {
+		finished: dataframe.is_last(),
+		reserved: *dataframe.reserved(),
+		opcode: opcode,
+		data: match dataframe.payload() {
+			Cow::Owned(data) => data,
+			Cow::Borrowed(data) => data.to_vec(),
+		},
+	})
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use dataframe::{DataFrame, Opcode};
+	use result::WebSocketResult;
+
+	struct Duplicate;
+
+	impl Middleware for Duplicate {
+		fn outgoing(&mut self, dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+			Ok(vec![dataframe.clone(), dataframe])
+		}
+	}
+
+	struct DropPings;
+
+	impl Middleware for DropPings {
+		fn outgoing(&mut self, dataframe: DataFrame) -> WebSocketResult<Vec<DataFrame>> {
+			if dataframe.opcode == Opcode::Ping {
+				Ok(Vec::new())
+			} else {
+				Ok(vec![dataframe])
+			}
+		}
+	}
+
+	#[test]
+	fn test_middleware_chain_order() {
+		let mut chain = MiddlewareChain::new();
+		chain.push(Duplicate);
+		chain.push(DropPings);
+
+		let text = DataFrame::new(true, Opcode::Text, b"foo".to_vec());
+		let ping = DataFrame::new(true, Opcode::Ping, b"bar".to_vec());
+
+		assert_eq!(chain.outgoing(text.clone()).unwrap(), vec![text.clone(), text.clone()]);
+		assert_eq!(chain.outgoing(ping.clone()).unwrap(), vec![]);
+		assert_eq!(chain.incoming(ping.clone()).unwrap(), vec![ping]);
+	}
+}
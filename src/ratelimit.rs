@@ -0,0 +1,139 @@
+//! Provides a token bucket rate limiter for outgoing data frames and messages.
+use std::thread;
+use std::time::{Duration, Instant};
+
+use result::WebSocketResult;
+use ws::dataframe::DataFrame;
+use ws;
+
+/// The unit that a `RateLimiter` counts tokens in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateUnit {
+	/// Every message (and every control frame) consumes one token.
+	Messages,
+	/// Every byte of data frame payload consumes one token.
+	Bytes,
+}
+
+/// A Sender that wraps another Sender and limits the rate at which it sends
+/// using a token bucket.
+///
+/// The bucket holds at most `burst` tokens and is refilled at `rate` tokens per
+/// second. When there are not enough tokens available, sending blocks the current
+/// thread until the bucket has been refilled.
+///
+/// Since this implements `ws::Sender`, it can be used anywhere the wrapped Sender
+/// could be, for example in `Client::new()`.
+pub struct RateLimiter<S> {
+	inner: S,
+	unit: RateUnit,
+	rate: f64,
+	burst: f64,
+	tokens: f64,
+	last: Instant,
+	in_message: bool,
+}
+
+impl<S> RateLimiter<S> {
+	/// Creates a new RateLimiter wrapping the given Sender.
+	///
+	/// The bucket starts full. Panics if `rate` is zero.
+	pub fn new(inner: S, rate: u32, burst: u32, unit: RateUnit) -> RateLimiter<S> {
+		assert!(rate > 0, "RateLimiter rate must be greater than zero");
+		RateLimiter {
+			inner: inner,
+			unit: unit,
+			rate: rate as f64,
+			burst: burst as f64,
+			tokens: burst as f64,
+			last: Instant::now(),
+			in_message: false,
+		}
+	}
+	/// Returns the unit this RateLimiter counts tokens in.
+	pub fn unit(&self) -> RateUnit {
+		self.unit
+	}
+	/// Returns a reference to the underlying Sender.
+	pub fn get_ref(&self) -> &S {
+		&self.inner
+	}
+	/// Returns a mutable reference to the underlying Sender.
+	pub fn get_mut(&mut self) -> &mut S {
+		&mut self.inner
+	}
+	/// Returns the underlying Sender.
+	pub fn into_inner(self) -> S {
+		self.inner
+	}
+
+	fn refill(&mut self) {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last);
+		let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+		self.tokens = (self.tokens + secs * self.rate).min(self.burst);
+		self.last = now;
+	}
+
+	fn acquire(&mut self, amount: f64) {
+		self.refill();
+		// Anything larger than the bucket may go into debt once the bucket is full
+		let needed = amount.min(self.burst);
+		if self.tokens < needed {
+			let wait = (needed - self.tokens) / self.rate;
+			thread::sleep(Duration::new(
+				wait as u64,
+				(wait.fract() * 1_000_000_000.0) as u32
+			));
+			self.refill();
+		}
+		self.tokens -= amount;
+	}
+}
+
+impl<S: ws::Sender> ws::Sender for RateLimiter<S> {
+	/// Waits until enough tokens are available, then sends a single data frame.
+	fn send_dataframe<D>(&mut self, dataframe: &D) -> WebSocketResult<()>
+	where D: DataFrame {
+		match self.unit {
+			RateUnit::Messages => {
+				if dataframe.opcode() >= 8 {
+					self.acquire(1.0);
+				}
+				else {
+					if !self.in_message {
+						self.acquire(1.0);
+					}
+					self.in_message = !dataframe.is_last();
+				}
+			}
+			RateUnit::Bytes => {
+				let size = dataframe.size() as f64;
+				self.acquire(size);
+			}
+		}
+		self.inner.send_dataframe(dataframe)
+	}
+
+	/// Flushes the underlying Sender.
+	fn flush(&mut self) -> WebSocketResult<()> {
+		self.inner.flush()
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use sender::Sender;
+	use message::Message;
+	use ws::Sender as SenderTrait;
+	#[test]
+	fn test_rate_limiter_burst() {
+		let mut limiter = RateLimiter::new(Sender::new(Vec::new(), false), 1, 2, RateUnit::Messages);
+		limiter.send_message(&Message::text("a")).unwrap();
+		limiter.send_message(&Message::binary(vec![1, 2])).unwrap();
+
+		assert!(limiter.tokens < 1.0);
+		assert_eq!(&limiter.get_ref().get_ref()[..], &[0x81, 0x01, b'a', 0x82, 0x02, 1, 2][..]);
+	}
+}
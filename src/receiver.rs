@@ -1,13 +1,24 @@
 //! The default implementation of a WebSocket Receiver.
 
-use std::io::Read;
+use std::io::{self, BufRead, Read};
+use std::cmp;
+use std::mem;
+use std::collections::VecDeque;
 use std::io::Result as IoResult;
+use std::sync::{Arc, Condvar, Mutex};
 use hyper::buffer::BufReader;
 
 use dataframe::{DataFrame, Opcode};
-use result::{WebSocketResult, WebSocketError};
+use result::{WebSocketResult, WebSocketError, CONTROL_FRAME_TOO_LONG};
 use stream::WebSocketStream;
 use stream::Shutdown;
+use middleware::{Middleware, MiddlewareChain};
+use ring::{RingBuffer, RingEvent};
+use client::CloseData;
+use metrics::Metrics;
+use ws::receiver::MessageMeta;
+use ws::util::header as dfh;
+use ws::util::utf8::Utf8Validator;
 use ws;
 
 /// A Receiver that wraps a Reader and provides a default implementation using
@@ -16,6 +27,69 @@ pub struct Receiver<R> {
 	inner: BufReader<R>,
 	buffer: Vec<DataFrame>,
 	mask: bool,
+	validate_utf8: bool,
+	forward_unknown_opcodes: bool,
+	strict: bool,
+	middleware: MiddlewareChain,
+	pending: VecDeque<DataFrame>,
+	paused: PauseHandle,
+	ring_message: Option<Opcode>,
+	max_frame_size: Option<usize>,
+	max_message_size: Option<usize>,
+	max_fragments: Option<usize>,
+	buffered_len: usize,
+	discarding: bool,
+	utf8: Option<Utf8Validator>,
+	partial: Vec<u8>,
+	metrics: Option<Arc<Metrics>>,
+	message_len: usize,
+	received_meta: MessageMeta,
+}
+
+/// The default limit on the size of received data frames and messages, in bytes.
+pub const DEFAULT_MAX_SIZE: usize = 16 << 20;
+
+/// The default limit on the number of data frames in a received message.
+pub const DEFAULT_MAX_FRAGMENTS: usize = 1 << 16;
+
+/// A handle which pauses and resumes reading on a `Receiver`, typically from another
+/// thread, for flow control.
+///
+/// While paused, the Receiver blocks before reading each data frame until it is
+/// resumed. Nothing is read from the connection in the meantime, so once the operating
+/// system's receive buffer fills up, TCP flow control stops the remote endpoint from
+/// sending more. Note that control frames are not read either, so pings go unanswered
+/// and a close message is not noticed until reading is resumed.
+#[derive(Clone)]
+pub struct PauseHandle {
+	inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseHandle {
+	fn new() -> PauseHandle {
+		PauseHandle {
+			inner: Arc::new((Mutex::new(false), Condvar::new())),
+		}
+	}
+	/// Stops the Receiver from reading further data frames.
+	pub fn pause(&self) {
+		*self.inner.0.lock().unwrap() = true;
+	}
+	/// Allows the Receiver to read data frames again.
+	pub fn resume(&self) {
+		*self.inner.0.lock().unwrap() = false;
+		self.inner.1.notify_all();
+	}
+	/// Returns true if the Receiver is paused.
+	pub fn is_paused(&self) -> bool {
+		*self.inner.0.lock().unwrap()
+	}
+	fn wait(&self) {
+		let mut paused = self.inner.0.lock().unwrap();
+		while *paused {
+			paused = self.inner.1.wait(paused).unwrap();
+		}
+	}
 }
 
 impl<R> Receiver<R>
@@ -26,6 +100,23 @@ where R: Read {
 			inner: reader,
 			buffer: Vec::new(),
 			mask: mask,
+			validate_utf8: true,
+			forward_unknown_opcodes: false,
+			strict: true,
+			middleware: MiddlewareChain::new(),
+			pending: VecDeque::new(),
+			paused: PauseHandle::new(),
+			ring_message: None,
+			max_frame_size: Some(DEFAULT_MAX_SIZE),
+			max_message_size: Some(DEFAULT_MAX_SIZE),
+			max_fragments: Some(DEFAULT_MAX_FRAGMENTS),
+			buffered_len: 0,
+			discarding: false,
+			utf8: None,
+			partial: Vec::new(),
+			metrics: None,
+			message_len: 0,
+			received_meta: MessageMeta { frame_count: 0, compressed: false },
 		}
 	}
 	/// Returns a reference to the underlying Reader.
@@ -36,6 +127,198 @@ where R: Read {
 	pub fn get_mut(&mut self) -> &mut BufReader<R> {
 		&mut self.inner
 	}
+	/// Returns the number of bytes which have been read from the underlying Reader
+	/// but not yet parsed into a data frame.
+	pub fn buffered_bytes(&self) -> usize {
+		self.inner.get_buf().len() + self.partial.len()
+	}
+	/// Sets whether the text payloads of received messages are checked to be valid UTF-8.
+	///
+	/// Text is checked one data frame at a time as it arrives, allowing for characters
+	/// split between data frames, so invalid text is reported without waiting for the
+	/// rest of the message. The rest of its data frames are then skipped, so receiving
+	/// can carry on, although the connection should be closed with status code 1007.
+	///
+	/// This is enabled by default. Disabling it is not conforming for an endpoint which
+	/// interprets the messages it receives, but avoids needless work for a relay which
+	/// passes them on without looking at them.
+	pub fn set_validate_utf8(&mut self, validate: bool) {
+		self.validate_utf8 = validate;
+	}
+	/// Sets whether data frames with a reserved opcode are passed on rather than rejected.
+	///
+	/// By default such a data frame fails with a protocol error, as required by RFC 6455,
+	/// and the connection should be closed with status code 1002. Enabling this allows a
+	/// proxy to relay experimental frames verbatim using `recv_dataframe()`.
+	pub fn set_forward_unknown_opcodes(&mut self, forward: bool) {
+		self.forward_unknown_opcodes = forward;
+	}
+	/// Returns whether data frames with a reserved opcode are passed on rather than rejected.
+	pub fn forwards_unknown_opcodes(&self) -> bool {
+		self.forward_unknown_opcodes
+	}
+	/// Sets whether every check required by RFC 6455 is made on what is received.
+	///
+	/// This is enabled by default. When enabled, data frames must be masked only if
+	/// they are sent by a client, must encode their length minimally, and must not have
+	/// reserved bits set which no extension uses. Control frames must be short and
+	/// unfragmented, close messages must carry a valid status code and reason, and text must be
+	/// valid UTF-8. Data frames with a reserved opcode are rejected.
+	///
+	/// Disabling it skips all of these checks, for interoperating with endpoints which
+	/// do not conform: reserved bits are ignored when receiving messages, and data frames
+	/// with a reserved opcode are passed on. This changes the settings of
+	/// `set_validate_utf8()` and `set_forward_unknown_opcodes()`, which can be changed
+	/// again individually afterwards.
+	pub fn set_strict(&mut self, strict: bool) {
+		self.strict = strict;
+		self.validate_utf8 = strict;
+		self.forward_unknown_opcodes = !strict;
+	}
+	/// Returns whether every check required by RFC 6455 is made on what is received.
+	pub fn is_strict(&self) -> bool {
+		self.strict
+	}
+	/// Limits the payload length of received data frames, or removes the limit if `max`
+	/// is None. The default is `DEFAULT_MAX_SIZE`.
+	///
+	/// A data frame whose header declares a longer payload fails with a data frame error
+	/// before any of the payload is read or memory is allocated for it. The connection
+	/// can no longer be used, and should be closed with status code 1009.
+	pub fn set_max_frame_size(&mut self, max: Option<usize>) {
+		self.max_frame_size = max;
+	}
+	/// Returns the limit on the payload length of received data frames.
+	pub fn max_frame_size(&self) -> Option<usize> {
+		self.max_frame_size
+	}
+	/// Limits the total payload length of the data frames of a received message, or
+	/// removes the limit if `max` is None. The default is `DEFAULT_MAX_SIZE`.
+	///
+	/// Once a message grows past the limit, it fails with a protocol error and the rest
+	/// of its data frames are skipped as they arrive, so receiving can carry on with the
+	/// next message. The connection should usually be closed with status code 1009
+	/// instead. This does not apply to `recv_into_ring()`, whose memory use is bounded
+	/// anyway. The limit is also passed to the Receiver's middleware, so that it applies
+	/// to compressed messages both before and after they are decompressed.
+	pub fn set_max_message_size(&mut self, max: Option<usize>) {
+		self.max_message_size = max;
+		self.middleware.set_max_message_size(max);
+	}
+	/// Returns the limit on the total payload length of received messages.
+	pub fn max_message_size(&self) -> Option<usize> {
+		self.max_message_size
+	}
+	/// Limits the number of data frames a received message may be split into, or
+	/// removes the limit if `max` is None. The default is `DEFAULT_MAX_FRAGMENTS`.
+	///
+	/// Each data frame held while the rest of a message arrives takes some memory beyond
+	/// its payload, so this bounds the memory used by messages sent in many tiny
+	/// fragments. A message with too many fails in the same way as one which is too
+	/// large; see `set_max_message_size()`.
+	pub fn set_max_fragments(&mut self, max: Option<usize>) {
+		self.max_fragments = max;
+	}
+	/// Returns the limit on the number of data frames in a received message.
+	pub fn max_fragments(&self) -> Option<usize> {
+		self.max_fragments
+	}
+	/// Sets the hooks called as data frames and messages are received, or removes them
+	/// if `metrics` is None. See `Metrics`.
+	///
+	/// Data frames are reported as they are read, before any middleware sees them.
+	pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+		self.metrics = metrics;
+	}
+	/// Returns a handle which can pause and resume reading on this Receiver.
+	pub fn pause_handle(&self) -> PauseHandle {
+		self.paused.clone()
+	}
+	/// Receives the rest of a data message, writing its payload into `ring` as each
+	/// data frame arrives, and marking the end of the message there.
+	///
+	/// Writing blocks while the ring is full, or holds as many ends of messages as its
+	/// capacity, so another thread must read from it meanwhile. Messages larger than the ring's capacity are passed through it in
+	/// pieces, so the memory held for a message is bounded by the capacity plus the
+	/// largest single data frame, which is always read whole. If a control frame
+	/// arrives during a message it is returned, and the next call carries on with the
+	/// message. If receiving fails part way through a message, the data already written
+	/// to the ring stays there, without an end of message being marked.
+	///
+	/// Text payloads are not checked to be valid UTF-8. This should not be mixed with
+	/// receiving messages in other ways on the same Receiver.
+	pub fn recv_into_ring(&mut self, ring: &RingBuffer) -> WebSocketResult<RingEvent> {
+		loop {
+			let frame = try!(ws::Receiver::recv_dataframe(self));
+			match frame.opcode {
+				Opcode::Continuation if self.ring_message.is_none() => {
+					return Err(WebSocketError::ProtocolError(
+						"Unexpected continuation data frame opcode"
+					));
+				}
+				Opcode::Continuation => (),
+				_ if frame.opcode as u8 >= 8 => return Ok(RingEvent::Control(frame)),
+				_ if self.ring_message.is_some() => {
+					self.ring_message = None;
+					return Err(WebSocketError::ProtocolError(
+						"Unexpected data frame opcode"
+					));
+				}
+				opcode => self.ring_message = Some(opcode),
+			}
+			try!(ring.push(&frame.data));
+			if frame.finished {
+				try!(ring.end_message());
+				return Ok(RingEvent::Message(self.ring_message.take().unwrap()));
+			}
+		}
+	}
+	/// Starts receiving a data message, returning a reader over its payload which reads
+	/// each data frame only as it is needed.
+	///
+	/// This allows messages larger than memory to be streamed elsewhere, as only one data
+	/// frame is held at a time. Control frames received before or during the message are
+	/// kept by the reader, and should be taken with `MessageReader::next_control_frame()`
+	/// between reads so that pings can be answered. If a close frame arrives during the
+	/// message, reading fails with an error of kind `ConnectionAborted`. Other errors,
+	/// including data frames which break the framing of the message, fail reading with
+	/// an error of kind `InvalidData` wrapping the `WebSocketError`, unless they are I/O
+	/// errors.
+	///
+	/// Text payloads are not checked to be valid UTF-8, and `set_max_message_size()`
+	/// does not apply. This should not be mixed with receiving messages in other ways
+	/// while a message is partly received.
+	pub fn recv_message_reader<'a>(&'a mut self) -> WebSocketResult<MessageReader<'a, R>> {
+		let mut control = VecDeque::new();
+		let first = loop {
+			let frame = try!(ws::Receiver::recv_dataframe(self));
+			match frame.opcode {
+				Opcode::Continuation => {
+					return Err(WebSocketError::ProtocolError(
+						"Unexpected continuation data frame opcode"
+					));
+				}
+				_ if frame.opcode as u8 >= 8 => control.push_back(frame),
+				_ => break frame,
+			}
+		};
+		Ok(MessageReader {
+			opcode: first.opcode,
+			finished: first.finished,
+			data: first.data,
+			position: 0,
+			control: control,
+			receiver: self,
+		})
+	}
+	/// Registers a middleware which sees every data frame after it is received.
+	///
+	/// Middleware is applied in the order it was added.
+	pub fn add_middleware<M>(&mut self, mut middleware: M)
+	where M: Middleware + 'static {
+		middleware.set_max_message_size(self.max_message_size);
+		self.middleware.push(middleware);
+	}
 }
 
 impl Receiver<WebSocketStream> {
@@ -63,12 +346,69 @@ impl Receiver<WebSocketStream> {
 impl<R: Read> ws::Receiver<DataFrame> for Receiver<R> {
 	/// Reads a single data frame from the remote endpoint.
 	fn recv_dataframe(&mut self) -> WebSocketResult<DataFrame> {
-		DataFrame::read_dataframe(&mut self.inner, self.mask)
+		loop {
+			self.paused.wait();
+			if let Some(frame) = self.pending.pop_front() {
+				return Ok(frame);
+			}
+			let frame = try!(self.read_frame());
+			// Kept for message_meta(), as middleware may merge or decompress data frames
+			match frame.opcode {
+				Opcode::Continuation => self.received_meta.frame_count += 1,
+				opcode if (opcode as u8) < 8 => {
+					self.received_meta = MessageMeta { frame_count: 1, compressed: frame.reserved[0] };
+				}
+				_ => (),
+			}
+			if let Some(ref metrics) = self.metrics {
+				metrics.on_frame_received(frame.opcode, frame.data.len());
+				if (frame.opcode as u8) < 8 {
+					self.message_len += frame.data.len();
+					if frame.finished {
+						metrics.on_message_received(self.message_len);
+						self.message_len = 0;
+					}
+				}
+			}
+			if !self.forward_unknown_opcodes && is_reserved(frame.opcode) {
+				return Err(WebSocketError::ProtocolError(
+					"Reserved data frame opcode received"
+				));
+			}
+			if self.strict && frame.opcode == Opcode::Close {
+				try!(CloseData::parse(&frame.data));
+			}
+			if self.middleware.is_empty() {
+				return Ok(frame);
+			}
+			self.pending.extend(try!(self.middleware.incoming(frame)));
+		}
+	}
+	/// Returns whether the text payloads of received messages are checked to be valid UTF-8.
+	fn validates_utf8(&self) -> bool {
+		self.validate_utf8
+	}
+	/// Describes the data frames of a message as they were received, before any
+	/// middleware saw them. A message decompressed by an `Inflater` is reported as
+	/// compressed, with as many data frames as it was sent in.
+	fn message_meta(&self, dataframes: &[DataFrame]) -> MessageMeta {
+		if self.middleware.is_empty() {
+			return MessageMeta::new(dataframes);
+		}
+		self.received_meta
 	}
 	/// Returns the data frames that constitute one message.
 	fn recv_message_dataframes(&mut self) -> WebSocketResult<Vec<DataFrame>> {
 		let mut finished = if self.buffer.is_empty() {
-			let first = try!(self.recv_dataframe());
+			let mut first = try!(self.recv_dataframe());
+			// Skip the rest of a message which was too large
+			while self.discarding && first.opcode == Opcode::Continuation {
+				self.discarding = !first.finished;
+				first = try!(self.recv_dataframe());
+			}
+			if (first.opcode as u8) < 8 {
+				self.discarding = false;
+			}
 
 			if first.opcode == Opcode::Continuation {
 				return Err(WebSocketError::ProtocolError(
@@ -76,8 +416,18 @@ impl<R: Read> ws::Receiver<DataFrame> for Receiver<R> {
 				));
 			}
 
+			// Text is checked as it arrives, unless it may be compressed
+			self.utf8 = if self.validate_utf8 && first.opcode == Opcode::Text
+				&& (first.reserved == [false; 3] || !self.strict) {
+				Some(Utf8Validator::new())
+			} else {
+				None
+			};
+
 			let finished = first.finished;
 			self.buffer.push(first);
+			try!(self.check_message_size(finished));
+			try!(self.check_utf8(finished));
 			finished
 		}
 		else {
@@ -90,21 +440,619 @@ impl<R: Read> ws::Receiver<DataFrame> for Receiver<R> {
 
 			match next.opcode as u8 {
 				// Continuation opcode
-				0 => self.buffer.push(next),
+				0 => {
+					self.buffer.push(next);
+					try!(self.check_message_size(finished));
+					try!(self.check_utf8(finished));
+				}
 				// Control frame
 				8...15 => {
-					return Ok(vec![next]);
+					return Ok(self.relax(vec![next]));
 				}
 				// Others
-				_ => return Err(WebSocketError::ProtocolError(
-					"Unexpected data frame opcode"
-				)),
+				_ => {
+					// Drop the unfinished message, so receiving can carry on afterwards
+					self.buffer.clear();
+					return Err(WebSocketError::ProtocolError(
+						"Unexpected data frame opcode"
+					));
+				}
+			}
+		}
+
+		let buffer = mem::replace(&mut self.buffer, Vec::new());
+
+		Ok(self.relax(buffer))
+	}
+}
+
+impl<R: Read> Receiver<R> {
+	/// Reads the next data frame once all of it has arrived.
+	///
+	/// If reading fails, for example because a read timeout elapsed, the part of the data
+	/// frame which has arrived is kept, so the next call carries on from there.
+	fn read_frame(&mut self) -> WebSocketResult<DataFrame> {
+		let mask = if self.strict { Some(self.mask) } else { None };
+		let max = self.max_frame_size.map(|max| max as u64);
+		loop {
+			// Usually the whole data frame is already in the BufReader. Once all of it has
+			// arrived it is consumed, even if it turns out to be invalid, so that the
+			// Receiver can carry on with the next one.
+			if self.partial.is_empty() {
+				let result = {
+					let buf = self.inner.get_buf();
+					match try!(frame_len(buf, max, self.strict)) {
+						Some(len) if buf.len() >= len => {
+							Some((len, DataFrame::read_dataframe_limited(&mut &buf[..len], mask, max)))
+						}
+						_ => None,
+					}
+				};
+				if let Some((len, result)) = result {
+					self.inner.consume(len);
+					return result;
+				}
+			}
+			else if let Some(len) = try!(frame_len(&self.partial, max, self.strict)) {
+				if self.partial.len() >= len {
+					let result = DataFrame::read_dataframe_limited(&mut &self.partial[..len], mask, max);
+					self.partial.drain(..len);
+					return result;
+				}
+			}
+
+			let read = match self.inner.fill_buf() {
+				Ok(buf) => {
+					self.partial.extend_from_slice(buf);
+					buf.len()
+				}
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => return Err(From::from(e)),
+			};
+			if read == 0 {
+				return Err(WebSocketError::NoDataAvailable);
+			}
+			self.inner.consume(read);
+		}
+	}
+}
+
+/// Returns the length of the data frame at the start of `buf`, or None if its header
+/// has not fully arrived yet.
+///
+/// If `strict`, a control frame longer than 125 bytes is rejected from its header, so
+/// that its payload is never buffered.
+fn frame_len(buf: &[u8], max: Option<u64>, strict: bool) -> WebSocketResult<Option<usize>> {
+	let mut rest = buf;
+	let header = match dfh::read_header_lenient(&mut rest) {
+		Ok(header) => header,
+		Err(WebSocketError::NoDataAvailable) => return Ok(None),
+		Err(e) => return Err(e),
+	};
+	if strict && header.opcode >= 8 && header.len > 125 {
+		return Err(WebSocketError::DataFrameError(CONTROL_FRAME_TOO_LONG));
+	}
+	let header_len = buf.len() - rest.len();
+	if max.map_or(false, |max| header.len > max) || header.len > (usize::max_value() - header_len) as u64 {
+		return Err(WebSocketError::DataFrameError("Data frame too large"));
+	}
+	Ok(Some(header_len + header.len as usize))
+}
+
+impl<R> Receiver<R> {
+	/// Drops the message being received if it has grown too large or has too many data
+	/// frames, skipping the rest of its data frames unless it is `finished`.
+	///
+	/// This is called as each data frame is added to the buffer, so the size of the
+	/// message is kept as a running total.
+	fn check_message_size(&mut self, finished: bool) -> WebSocketResult<()> {
+		let len = self.buffer.last().map_or(0, |frame| frame.data.len());
+		self.buffered_len = if self.buffer.len() <= 1 {
+			len
+		} else {
+			self.buffered_len.saturating_add(len)
+		};
+		let too_large = self.max_message_size.map_or(false, |max| self.buffered_len > max);
+		let too_many = self.max_fragments.map_or(false, |max| self.buffer.len() > max);
+		if too_large || too_many {
+			self.buffer.clear();
+			self.discarding = !finished;
+			return Err(WebSocketError::ProtocolError(
+				if too_large { "Message too large" } else { "Too many data frames in message" }
+			));
+		}
+		Ok(())
+	}
+	/// Checks that the text received so far is valid UTF-8, dropping the message and
+	/// skipping the rest of its data frames if not.
+	fn check_utf8(&mut self, finished: bool) -> WebSocketResult<()> {
+		let result = match (self.utf8.as_mut(), self.buffer.last()) {
+			(Some(validator), Some(frame)) => validator.feed(&frame.data).and_then(|()| {
+				if finished { validator.finish() } else { Ok(()) }
+			}),
+			_ => return Ok(()),
+		};
+		if let Err(e) = result {
+			self.buffer.clear();
+			self.discarding = !finished;
+			self.utf8 = None;
+			return Err(WebSocketError::Utf8Error(e));
+		}
+		Ok(())
+	}
+	/// Clears the reserved bits of the data frames of a message when not strict.
+	fn relax(&self, mut dataframes: Vec<DataFrame>) -> Vec<DataFrame> {
+		if !self.strict {
+			for dataframe in dataframes.iter_mut() {
+				dataframe.reserved = [false; 3];
+			}
+		}
+		dataframes
+	}
+}
+
+/// Reads the payload of a data message one data frame at a time. See
+/// `Receiver::recv_message_reader()`.
+pub struct MessageReader<'a, R: 'a> {
+	receiver: &'a mut Receiver<R>,
+	opcode: Opcode,
+	finished: bool,
+	data: Vec<u8>,
+	position: usize,
+	control: VecDeque<DataFrame>,
+}
+
+impl<'a, R: Read> MessageReader<'a, R> {
+	/// Returns the opcode of the message, either `Opcode::Text` or `Opcode::Binary`, or
+	/// a reserved opcode if those are forwarded.
+	pub fn opcode(&self) -> Opcode {
+		self.opcode
+	}
+	/// Returns true once the final data frame of the message has been received.
+	pub fn is_finished(&self) -> bool {
+		self.finished
+	}
+	/// Returns the oldest control frame received with the message which has not been
+	/// taken yet.
+	pub fn next_control_frame(&mut self) -> Option<DataFrame> {
+		self.control.pop_front()
+	}
+	fn next_frame(&mut self) -> WebSocketResult<()> {
+		loop {
+			let frame = try!(ws::Receiver::recv_dataframe(self.receiver));
+			match frame.opcode {
+				Opcode::Continuation => {
+					self.finished = frame.finished;
+					self.data = frame.data;
+					self.position = 0;
+					return Ok(());
+				}
+				Opcode::Close => {
+					self.control.push_back(frame);
+					return Err(WebSocketError::IoError(io::Error::new(
+						io::ErrorKind::ConnectionAborted, "Close frame received during the message"
+					)));
+				}
+				_ if frame.opcode as u8 >= 8 => self.control.push_back(frame),
+				_ => {
+					return Err(WebSocketError::ProtocolError(
+						"Unexpected data frame opcode"
+					));
+				}
+			}
+		}
+	}
+}
+
+impl<'a, R: Read> Read for MessageReader<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.position == self.data.len() {
+			if self.finished {
+				return Ok(0);
+			}
+			match self.next_frame() {
+				Ok(()) => (),
+				Err(WebSocketError::IoError(err)) => return Err(err),
+				Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+			}
+		}
+		let len = cmp::min(buf.len(), self.data.len() - self.position);
+		buf[..len].copy_from_slice(&self.data[self.position..self.position + len]);
+		self.position += len;
+		Ok(len)
+	}
+}
+
+fn is_reserved(opcode: Opcode) -> bool {
+	match opcode as u8 {
+		3...7 | 11...15 => true,
+		_ => false,
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use hyper::buffer::BufReader;
+	use dataframe::{DataFrame, Opcode};
+	use message::Message;
+	use ws::Receiver as ReceiverTrait;
+	use ws::MessageMeta;
+	use result::WebSocketError;
+	use std::io::{self, Read};
+
+	/// Returns one byte per read, and is interrupted before every read.
+	struct Flaky<'a> {
+		data: &'a [u8],
+		interrupt: bool,
+	}
+
+	impl<'a> Read for Flaky<'a> {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.interrupt = !self.interrupt;
+			if self.interrupt {
+				return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+			}
+			let len = self.data.len().min(buf.len()).min(1);
+			buf[..len].copy_from_slice(&self.data[..len]);
+			self.data = &self.data[len..];
+			Ok(len)
+		}
+	}
+
+	#[test]
+	fn test_recv_interrupted_reads() {
+		let data = [0x81, 0x85, 1, 2, 3, 4, b'H' ^ 1, b'e' ^ 2, b'l' ^ 3, b'l' ^ 4, b'o' ^ 1];
+		let flaky = Flaky { data: &data, interrupt: false };
+		let mut receiver = Receiver::new(BufReader::new(flaky), true);
+
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("Hello"));
+	}
+
+	#[test]
+	fn test_reserved_opcodes() {
+		for opcode in (3..8).chain(11..16) {
+			let data = [0x80 | opcode, 0x01, 0x2A, 0x82, 0x00];
+			let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+			match receiver.recv_dataframe() {
+				Err(WebSocketError::ProtocolError(_)) => (),
+				_ => panic!("Reserved opcode {} was not rejected", opcode),
+			}
+
+			let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+			receiver.set_forward_unknown_opcodes(true);
+			let frame = receiver.recv_dataframe().unwrap();
+			assert_eq!(frame.opcode as u8, opcode);
+			assert_eq!(frame.data, vec![0x2A]);
+			assert_eq!(receiver.recv_dataframe().unwrap().opcode, Opcode::Binary);
+		}
+	}
+
+	#[test]
+	fn test_oversized_control_frame() {
+		// Only the header has arrived, which is enough to reject the data frame
+		let data = [0x89, 0x7E, 0x00, 0xC8];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		match receiver.recv_dataframe() {
+			Err(WebSocketError::DataFrameError("Control frame length too long")) => (),
+			other => panic!("Expected the ping to be rejected but got {:?}", other),
+		}
+
+		let mut data = vec![0x89, 0x7E, 0x00, 0xC8];
+		data.extend(vec![0; 200]);
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		receiver.set_strict(false);
+		assert_eq!(receiver.recv_dataframe().unwrap().data.len(), 200);
+	}
+
+	#[test]
+	fn test_recover_after_errors() {
+		let data = vec![
+			0x09, 0x01, 0x00, // Fragmented ping
+			0x80, 0x01, b'a', // Unexpected continuation
+			0x01, 0x01, b'a', 0x82, 0x00, // Unfinished message interrupted by another
+			0x83, 0x00, // Reserved opcode
+			0x81, 0x01, 0xFF, // Invalid UTF-8
+			0x81, 0x02, b'o', b'k',
+		];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		for _ in 0..5 {
+			let error = ReceiverTrait::recv_message::<_, Message, _>(&mut receiver).unwrap_err();
+			assert!(error.is_recoverable(), "{:?}", error);
+		}
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("ok"));
+
+		let data = vec![0x81, 0x7E, 0x00, 0x01, b'a'];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		let error = ReceiverTrait::recv_message::<_, Message, _>(&mut receiver).unwrap_err();
+		assert!(!error.is_recoverable());
+	}
+
+	#[test]
+	fn test_recv_into_ring() {
+		use std::thread;
+		use ring::{RingBuffer, RingEvent};
+
+		let data = vec![
+			0x02, 0x03, 1, 2, 3,
+			0x89, 0x00, // Ping
+			0x80, 0x03, 4, 5, 6,
+			0x81, 0x01, b'a',
+		];
+		let ring = RingBuffer::new(2);
+		let reader = ring.clone();
+		let consumer = thread::spawn(move || {
+			let mut messages = Vec::new();
+			for _ in 0..2 {
+				let mut message = Vec::new();
+				(&reader).read_to_end(&mut message).unwrap();
+				messages.push(message);
 			}
+			messages
+		});
+
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		match receiver.recv_into_ring(&ring).unwrap() {
+			RingEvent::Control(frame) => assert_eq!(frame.opcode, Opcode::Ping),
+			other => panic!("Expected a ping, got {:?}", other),
+		}
+		assert_eq!(receiver.recv_into_ring(&ring).unwrap(), RingEvent::Message(Opcode::Binary));
+		assert_eq!(receiver.recv_into_ring(&ring).unwrap(), RingEvent::Message(Opcode::Text));
+		assert_eq!(consumer.join().unwrap(), vec![vec![1, 2, 3, 4, 5, 6], b"a".to_vec()]);
+	}
+
+	#[test]
+	fn test_message_reserved_bits() {
+		use sender::Sender;
+		use ws::Sender as SenderTrait;
+
+		let mut sender = Sender::new(Vec::new(), false);
+		let message = Message::binary(vec![1]).with_reserved([false, true, false]);
+		sender.send_message(&message).unwrap();
+		let data = sender.get_ref().clone();
+		assert_eq!(data, vec![0xA2, 0x01, 0x01]);
+
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		let dataframes = receiver.recv_message_dataframes().unwrap();
+		assert_eq!(Message::from_dataframes_with_reserved(dataframes).unwrap(), message);
+
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		assert!(ReceiverTrait::recv_message::<_, Message, _>(&mut receiver).is_err());
+	}
+
+	#[test]
+	fn test_strict() {
+		let invalid: Vec<&[u8]> = vec![
+			&[0x82, 0x81, 0, 0, 0, 0, 0x2A], // Masked data frame from the server
+			&[0x82, 0x7E, 0x00, 0x01, 0x2A], // Non-minimal length
+			&[0xC2, 0x01, 0x2A], // Unused reserved bit
+			&[0x09, 0x00], // Fragmented control frame
+			&[0x88, 0x01, 0x03], // Truncated close status code
+			&[0x88, 0x02, 0x03, 0xED], // Close status code 1005
+			&[0x81, 0x01, 0xFF], // Invalid UTF-8
+			&[0x83, 0x00], // Reserved opcode
+		];
+		for data in invalid {
+			let mut receiver = Receiver::new(BufReader::new(data), false);
+			assert!(receiver.is_strict());
+			assert!(ReceiverTrait::recv_message::<_, Message, _>(&mut receiver).is_err(), "{:?}", data);
+
+			let mut receiver = Receiver::new(BufReader::new(data), false);
+			receiver.set_strict(false);
+			assert!(receiver.recv_dataframe().is_ok(), "{:?}", data);
 		}
 
-		let buffer = self.buffer.clone();
-		self.buffer.clear();
+		let data = [0xC2, 0x01, 0x2A];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		receiver.set_strict(false);
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::binary(vec![0x2A]));
+
+		let data = [0x88, 0x02, 0x0F, 0xA0];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message.cd_status_code, Some(4000));
+	}
+
+	#[test]
+	fn test_pause_handle() {
+		use std::thread;
+		use std::time::{Duration, Instant};
+
+		let data = [0x82, 0x01, 0x2A];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		let handle = receiver.pause_handle();
+		handle.pause();
+		assert!(handle.is_paused());
+
+		let start = Instant::now();
+		let resumer = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			handle.resume();
+		});
+		let frame = receiver.recv_dataframe().unwrap();
+		assert!(start.elapsed() >= Duration::from_millis(50));
+		assert_eq!(frame.data, vec![0x2A]);
+		resumer.join().unwrap();
+	}
+
+	#[test]
+	fn test_recv_control_frame_during_fragmented_message() {
+		let data = vec![
+			0x01, 0x03, b'H', b'e', b'l', // Unfinished text frame
+			0x89, 0x02, b'h', b'i', // Ping
+			0x80, 0x02, b'l', b'o', // Final continuation frame
+		];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+
+		let ping = receiver.recv_message_dataframes().unwrap();
+		assert_eq!(ping, vec![DataFrame::new(true, Opcode::Ping, b"hi".to_vec())]);
+
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("Hello"));
+	}
+
+	#[test]
+	fn test_recv_message_with_meta() {
+		let data = vec![0x01, 0x01, b'a', 0x00, 0x01, b'b', 0x80, 0x01, b'c', 0x82, 0x01, 0x00];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+
+		let (message, meta): (Message, _) = receiver.recv_message_with_meta().unwrap();
+		assert_eq!(message, Message::text("abc"));
+		assert_eq!(meta, MessageMeta { frame_count: 3, compressed: false });
 
-		Ok(buffer)
+		let (_, meta): (Message, _) = receiver.recv_message_with_meta().unwrap();
+		assert_eq!(meta.frame_count, 1);
+	}
+
+	#[test]
+	fn test_buffered_bytes() {
+		let data = vec![0x82, 0x01, 0x01, 0x82, 0x02, 0x01];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		assert_eq!(receiver.buffered_bytes(), 0);
+
+		receiver.recv_dataframe().unwrap();
+		// Part of the second data frame has been read but not parsed
+		assert_eq!(receiver.buffered_bytes(), 3);
+	}
+
+	#[test]
+	fn test_recv_invalid_utf8_unvalidated() {
+		let data = vec![0x81, 0x02, 0xC3, 0x28];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		receiver.set_validate_utf8(false);
+
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(&*message.payload, &[0xC3, 0x28][..]);
+
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		assert!(ReceiverTrait::recv_message::<_, Message, _>(&mut receiver).is_err());
+	}
+
+	#[test]
+	fn test_recv_utf8_fragmented() {
+		// "κό" split part way through both characters
+		let data = vec![
+			0x01, 0x01, 0xCE,
+			0x00, 0x02, 0xBA, 0xCF,
+			0x80, 0x01, 0x8C,
+			0x01, 0x02, b'a', 0xCE,
+			0x00, 0x01, b'b', // Invalid, and the rest of the message is never sent
+		];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("κό"));
+		match ReceiverTrait::recv_message::<_, Message, _>(&mut receiver) {
+			Err(WebSocketError::Utf8Error(_)) => (),
+			other => panic!("Expected a UTF-8 error but got {:?}", other),
+		}
+
+		// The rest of an invalid message is skipped
+		let data = vec![
+			0x01, 0x01, 0xFF,
+			0x80, 0x01, b'x',
+			0x81, 0x02, b'o', b'k',
+		];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		assert!(ReceiverTrait::recv_message::<_, Message, _>(&mut receiver).is_err());
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("ok"));
+	}
+
+	#[test]
+	fn test_max_sizes() {
+		// The header declares an enormous payload which is never sent
+		let data = vec![0x82, 0x7F, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		match receiver.recv_dataframe() {
+			Err(WebSocketError::DataFrameError(_)) => (),
+			other => panic!("Expected a data frame error but got {:?}", other),
+		}
+
+		let data = vec![
+			0x01, 0x02, b'a', b'b',
+			0x00, 0x02, b'c', b'd', // Too large in total
+			0x89, 0x00,
+			0x80, 0x01, b'e',
+			0x81, 0x02, b'o', b'k',
+		];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		receiver.set_max_frame_size(Some(3));
+		receiver.set_max_message_size(Some(3));
+		match ReceiverTrait::recv_message::<_, Message, _>(&mut receiver) {
+			Err(WebSocketError::ProtocolError(_)) => (),
+			other => panic!("Expected a protocol error but got {:?}", other),
+		}
+		// The rest of the message is skipped, but not control frames within it
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::ping(Vec::new()));
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("ok"));
+
+		// The size is counted afresh for every message
+		let data = vec![
+			0x01, 0x02, b'a', b'b',
+			0x80, 0x01, b'c',
+			0x01, 0x01, b'd',
+			0x00, 0x01, b'e',
+			0x00, 0x00,
+			0x00, 0x00,
+			0x80, 0x00, // Too many data frames
+			0x81, 0x03, b'o', b'k', b'!',
+		];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		receiver.set_max_message_size(Some(3));
+		receiver.set_max_fragments(Some(4));
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("abc"));
+		match ReceiverTrait::recv_message::<_, Message, _>(&mut receiver) {
+			Err(WebSocketError::ProtocolError("Too many data frames in message")) => (),
+			other => panic!("Expected a protocol error but got {:?}", other),
+		}
+		let message: Message = receiver.recv_message().unwrap();
+		assert_eq!(message, Message::text("ok!"));
+	}
+
+	#[test]
+	fn test_recv_message_reader() {
+		let data = vec![
+			0x89, 0x00, // Ping before the message
+			0x02, 0x03, b'a', b'b', b'c',
+			0x89, 0x01, b'p', // Ping within the message
+			0x00, 0x00,
+			0x80, 0x02, b'd', b'e',
+			0x81, 0x01, b'f',
+			0x01, 0x01, b'g',
+			0x88, 0x00, // Close within the message
+		];
+		let mut receiver = Receiver::new(BufReader::new(&data[..]), false);
+		{
+			let mut reader = receiver.recv_message_reader().unwrap();
+			assert_eq!(reader.opcode(), Opcode::Binary);
+			assert_eq!(reader.next_control_frame(), Some(DataFrame::new(true, Opcode::Ping, vec![])));
+			let mut buf = [0; 4];
+			assert_eq!(reader.read(&mut buf).unwrap(), 3);
+			assert_eq!(reader.next_control_frame(), None);
+			let mut rest = Vec::new();
+			reader.read_to_end(&mut rest).unwrap();
+			assert_eq!(rest, b"de".to_vec());
+			assert!(reader.is_finished());
+			assert_eq!(reader.next_control_frame(), Some(DataFrame::new(true, Opcode::Ping, b"p".to_vec())));
+		}
+		{
+			let mut reader = receiver.recv_message_reader().unwrap();
+			assert_eq!(reader.opcode(), Opcode::Text);
+			let mut message = Vec::new();
+			reader.read_to_end(&mut message).unwrap();
+			assert_eq!(message, b"f".to_vec());
+		}
+		let mut reader = receiver.recv_message_reader().unwrap();
+		let mut message = Vec::new();
+		let err = reader.read_to_end(&mut message).unwrap_err();
+		assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+		assert_eq!(message, b"g".to_vec());
+		assert_eq!(reader.next_control_frame().map(|frame| frame.opcode), Some(Opcode::Close));
 	}
 }
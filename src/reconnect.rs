@@ -0,0 +1,439 @@
+//! Provides a Client wrapper which reconnects with exponential backoff.
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand;
+
+use client::{Client, CloseData};
+use dataframe::DataFrame;
+use message::{Message, Type};
+use result::WebSocketResult;
+use ws::dataframe::DataFrame as DataFrameable;
+use ws;
+
+/// How long a `ReconnectingClient` waits between failed connection attempts.
+///
+/// The delay starts at `initial` and is multiplied by `multiplier` after every failed
+/// attempt, up to `max`. A random fraction of up to `jitter` of each delay is taken off
+/// it, so that many clients which lost their connections at the same time do not all
+/// reconnect at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+	/// The delay after the first failed attempt.
+	pub initial: Duration,
+	/// The longest delay between attempts.
+	pub max: Duration,
+	/// The factor the delay grows by after each failed attempt.
+	pub multiplier: f64,
+	/// The largest fraction of each delay, from 0 to 1, which is randomly taken off.
+	pub jitter: f64,
+	/// The number of attempts to make before giving up, or None to keep trying.
+	pub max_attempts: Option<u32>,
+}
+
+impl Default for Backoff {
+	/// Starts at 100 milliseconds and doubles up to 30 seconds, with 20% jitter and no
+	/// limit on the number of attempts.
+	fn default() -> Backoff {
+		Backoff {
+			initial: Duration::from_millis(100),
+			max: Duration::from_secs(30),
+			multiplier: 2.0,
+			jitter: 0.2,
+			max_attempts: None,
+		}
+	}
+}
+
+impl Backoff {
+	/// Returns the delay to wait after the given number of failed attempts, counting
+	/// from zero, before jitter is applied.
+	pub fn delay(&self, attempt: u32) -> Duration {
+		let initial = duration_secs(self.initial);
+		let delay = initial * self.multiplier.powi(cmp::min(attempt, i32::max_value() as u32) as i32);
+		if !(delay < duration_secs(self.max)) {
+			return self.max;
+		}
+		secs_duration(delay)
+	}
+
+	fn jittered_delay(&self, attempt: u32) -> Duration {
+		let delay = duration_secs(self.delay(attempt));
+		let jitter = self.jitter.max(0.0).min(1.0);
+		secs_duration(delay * (1.0 - jitter * rand::random::<f64>()))
+	}
+}
+
+fn duration_secs(duration: Duration) -> f64 {
+	duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1_000_000_000.0
+}
+
+fn secs_duration(secs: f64) -> Duration {
+	Duration::new(secs as u64, ((secs - secs.floor()) * 1_000_000_000.0) as u32)
+}
+
+/// A Client which connects again whenever its connection is lost.
+///
+/// Connections are made by calling the given function, which would usually connect
+/// with `Client::connect()` and complete the handshake, so any settings such as
+/// subprotocols, headers or timeouts belong there. The first connection is made when
+/// the ReconnectingClient is first used. Failed attempts are retried after the delays
+/// given by the `Backoff`.
+///
+/// A connection which is lost also counts as a failed attempt, so the next connection
+/// is only made after a delay. The count of failed attempts, which the delay grows
+/// with, starts again once a connection has lasted as long as `set_healthy_after()`
+/// asks, unless the remote endpoint closed it with status code 1013 (Try Again Later).
+/// This keeps a server which accepts connections and closes them straight away from
+/// being reconnected to in a tight loop.
+///
+/// `send_message()` sends a whole message, so if sending fails it is sent again in full
+/// on a new connection, and the remote endpoint may receive it twice. `recv_message()`
+/// reconnects without returning when the remote endpoint closes the connection
+/// cleanly. If the connection is lost any other way, the error is returned, because
+/// a message may have been lost part way through being received, and the next call
+/// reconnects.
+pub struct ReconnectingClient<S, R> {
+	connect: Box<FnMut() -> WebSocketResult<Client<DataFrame, S, R>>>,
+	on_connect: Option<Box<FnMut(&mut Client<DataFrame, S, R>) -> WebSocketResult<()>>>,
+	backoff: Backoff,
+	client: Option<Client<DataFrame, S, R>>,
+	connections: u64,
+	failures: u32,
+	connected_at: Option<Instant>,
+	healthy_after: Duration,
+}
+
+impl<S: ws::Sender, R: ws::Receiver<DataFrame>> ReconnectingClient<S, R> {
+	/// Creates a new ReconnectingClient which connects by calling `connect`.
+	pub fn new<F>(connect: F, backoff: Backoff) -> ReconnectingClient<S, R>
+	where F: FnMut() -> WebSocketResult<Client<DataFrame, S, R>> + 'static {
+		ReconnectingClient {
+			connect: Box::new(connect),
+			on_connect: None,
+			backoff: backoff,
+			client: None,
+			connections: 0,
+			failures: 0,
+			connected_at: None,
+			healthy_after: Duration::from_secs(10),
+		}
+	}
+	/// Sets how long a connection must last before the delay between attempts starts
+	/// again from `Backoff::initial` once it is lost. The default is 10 seconds.
+	pub fn set_healthy_after(&mut self, healthy_after: Duration) {
+		self.healthy_after = healthy_after;
+	}
+	/// Returns how long a connection must last before the delay between attempts starts
+	/// again.
+	pub fn healthy_after(&self) -> Duration {
+		self.healthy_after
+	}
+	/// Sets a function to call with every new connection, including the first, before
+	/// it is used.
+	///
+	/// This can send whatever each connection needs first, such as subscriptions. If it
+	/// returns an error, the connection is dropped and the error returned, and the
+	/// attempt counts as a failed one.
+	pub fn set_on_connect<F>(&mut self, on_connect: F)
+	where F: FnMut(&mut Client<DataFrame, S, R>) -> WebSocketResult<()> + 'static {
+		self.on_connect = Some(Box::new(on_connect));
+	}
+	/// Returns the number of connections made so far, not counting those rejected by
+	/// the function given to `set_on_connect()`.
+	pub fn connections(&self) -> u64 {
+		self.connections
+	}
+	/// Returns the current connection, if there is one.
+	pub fn client(&mut self) -> Option<&mut Client<DataFrame, S, R>> {
+		self.client.as_mut()
+	}
+	/// Drops the current connection without closing it, so the next call reconnects.
+	pub fn disconnect(&mut self) {
+		self.client = None;
+	}
+	/// Connects if there is no connection, waiting between failed attempts, and returns
+	/// the connection.
+	///
+	/// If the last connection was lost or the last attempt failed, this waits before
+	/// the first attempt too. Once `max_attempts` attempts have failed, the last error
+	/// is returned.
+	pub fn connect(&mut self) -> WebSocketResult<&mut Client<DataFrame, S, R>> {
+		if self.client.is_none() {
+			let mut attempt = 0;
+			let mut client = loop {
+				if self.failures > 0 {
+					thread::sleep(self.backoff.jittered_delay(self.failures - 1));
+				}
+				match (self.connect)() {
+					Ok(client) => break client,
+					Err(e) => {
+						attempt += 1;
+						self.failures = self.failures.saturating_add(1);
+						if self.backoff.max_attempts.map_or(false, |max| attempt >= max) {
+							return Err(e);
+						}
+					}
+				}
+			};
+			if let Some(ref mut on_connect) = self.on_connect {
+				if let Err(e) = on_connect(&mut client) {
+					self.failures = self.failures.saturating_add(1);
+					return Err(e);
+				}
+			}
+			self.connections += 1;
+			self.connected_at = Some(Instant::now());
+			self.client = Some(client);
+		}
+		Ok(self.client.as_mut().unwrap())
+	}
+	/// Sends a message, connecting first if needed.
+	///
+	/// If sending fails, the message is sent again once on a new connection.
+	pub fn send_message<'m, M, D>(&mut self, message: &'m M) -> WebSocketResult<()>
+	where M: ws::Message<'m, D>, D: DataFrameable {
+		let mut retried = false;
+		loop {
+			let result = try!(self.connect()).send_message(message);
+			match result {
+				Ok(()) => return Ok(()),
+				Err(e) => {
+					self.lost(None);
+					if retried || e.is_recoverable() {
+						return Err(e);
+					}
+					retried = true;
+				}
+			}
+		}
+	}
+	/// Receives the next text or binary message, connecting first if needed.
+	///
+	/// Pings are answered as they arrive. When the remote endpoint closes the connection,
+	/// the close is acknowledged and a new connection is made after a delay. Any error
+	/// drops the connection and is returned, so that the next call reconnects.
+	pub fn recv_message(&mut self) -> WebSocketResult<Message<'static>> {
+		loop {
+			let result = try!(self.connect()).recv_until(|message| {
+				message.opcode == Type::Text || message.opcode == Type::Binary
+			}, None);
+			match result {
+				Ok(ref message) if message.opcode == Type::Close => {
+					if let Some(ref mut client) = self.client {
+						let reply = match message.cd_status_code {
+							Some(code) => Message::close_because(code, ""),
+							None => Message::close(),
+						};
+						let _ = client.send_message(&reply);
+					}
+					let close = message.cd_status_code.map(|code| CloseData::new(code, ""));
+					self.lost(close.as_ref());
+				}
+				Ok(message) => return Ok(message),
+				Err(e) => {
+					self.lost(None);
+					return Err(e);
+				}
+			}
+		}
+	}
+	/// Drops a connection which was lost, counting it as a failed attempt.
+	fn lost(&mut self, close: Option<&CloseData>) {
+		self.client = None;
+		let healthy = self.connected_at.take().map_or(false, |at| at.elapsed() >= self.healthy_after);
+		let try_again_later = close.map_or(false, |close| close.is_try_again_later());
+		if healthy && !try_again_later {
+			self.failures = 0;
+		}
+		self.failures = self.failures.saturating_add(1);
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::cell::Cell;
+	use std::net::TcpListener;
+	use std::rc::Rc;
+	use std::thread;
+	use std::time::Duration;
+	use client::{Client, Receiver, Sender};
+	use message::Message;
+	use result::WebSocketError;
+	use server;
+	use stream::WebSocketStream;
+	use url::Url;
+
+	#[test]
+	fn test_backoff_delay() {
+		let backoff = Backoff {
+			initial: Duration::from_millis(100),
+			max: Duration::from_secs(1),
+			multiplier: 2.0,
+			jitter: 0.5,
+			max_attempts: None,
+		};
+		let delays: Vec<u64> = (0..6).map(|attempt| {
+			let delay = backoff.delay(attempt);
+			delay.as_secs() * 1000 + delay.subsec_nanos() as u64 / 1_000_000
+		}).collect();
+		assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+		assert_eq!(backoff.delay(10000), Duration::from_secs(1));
+		for _ in 0..100 {
+			let delay = backoff.jittered_delay(0);
+			assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+		}
+	}
+
+	#[test]
+	fn test_max_attempts() {
+		let attempts = Rc::new(Cell::new(0));
+		let counter = attempts.clone();
+		let backoff = Backoff {
+			initial: Duration::from_millis(1),
+			max_attempts: Some(3),
+			..Backoff::default()
+		};
+		let mut client: ReconnectingClient<Sender<WebSocketStream>, Receiver<WebSocketStream>> =
+			ReconnectingClient::new(move || {
+				counter.set(counter.get() + 1);
+				Err(WebSocketError::Timeout)
+			}, backoff);
+		assert_eq!(client.send_message(&Message::text("hi")), Err(WebSocketError::Timeout));
+		assert_eq!(attempts.get(), 3);
+		assert_eq!(client.connections(), 0);
+	}
+
+	#[test]
+	fn test_reconnect_after_close() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+		let thread = thread::spawn(move || {
+			// The first connection echoes one message, then closes
+			let stream = listener.accept().unwrap().0;
+			let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+			let message = client.recv_until(|_| true, None).unwrap();
+			client.send_message(&message).unwrap();
+			client.send_message(&Message::close()).unwrap();
+			client.recv_until(|_| true, None).unwrap();
+			drop(client);
+
+			// The second connection greets the client, then echoes
+			let stream = listener.accept().unwrap().0;
+			let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+			client.send_message(&Message::text("welcome back")).unwrap();
+			client.run_echo().unwrap();
+		});
+
+		let subscriptions = Rc::new(Cell::new(0));
+		let counter = subscriptions.clone();
+		let mut client = ReconnectingClient::new(move || {
+			let response = try!(try!(Client::connect(&url)).send());
+			try!(response.validate());
+			Ok(response.begin())
+		}, Backoff::default());
+		client.set_on_connect(move |_| {
+			counter.set(counter.get() + 1);
+			Ok(())
+		});
+
+		client.send_message(&Message::text("hi")).unwrap();
+		assert_eq!(client.recv_message().unwrap(), Message::text("hi"));
+		assert_eq!(client.recv_message().unwrap(), Message::text("welcome back"));
+		assert_eq!(client.connections(), 2);
+		assert_eq!(subscriptions.get(), 2);
+
+		client.send_message(&Message::text("again")).unwrap();
+		assert_eq!(client.recv_message().unwrap(), Message::text("again"));
+		client.client().unwrap().send_message(&Message::close()).unwrap();
+		thread.join().unwrap();
+	}
+
+	#[test]
+	fn test_try_again_later_delays_reconnect() {
+		use std::time::Instant;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+		let thread = thread::spawn(move || {
+			// The first connection is closed straight away with 1013
+			let stream = listener.accept().unwrap().0;
+			let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+			client.send_message(&Message::close_because(1013, "Busy")).unwrap();
+			client.recv_until(|_| true, None).unwrap();
+			drop(client);
+			let closed_at = Instant::now();
+
+			let stream = listener.accept().unwrap().0;
+			let reconnected_after = closed_at.elapsed();
+			let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+			client.send_message(&Message::text("welcome back")).unwrap();
+			client.run_echo().unwrap();
+			reconnected_after
+		});
+
+		let backoff = Backoff {
+			initial: Duration::from_millis(200),
+			jitter: 0.0,
+			..Backoff::default()
+		};
+		let mut client = ReconnectingClient::new(move || {
+			let response = try!(try!(Client::connect(&url)).send());
+			try!(response.validate());
+			Ok(response.begin())
+		}, backoff);
+		// Even a long-lived connection closed with 1013 counts as a failure
+		client.set_healthy_after(Duration::from_millis(0));
+		assert_eq!(client.recv_message().unwrap(), Message::text("welcome back"));
+		assert_eq!(client.connections(), 2);
+		client.client().unwrap().send_message(&Message::close()).unwrap();
+		assert!(thread.join().unwrap() >= Duration::from_millis(150));
+	}
+
+	#[test]
+	fn test_on_connect_failure_delays_reconnect() {
+		use std::time::Instant;
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let url = Url::parse(&format!("ws://{}/", listener.local_addr().unwrap())).unwrap();
+		let thread = thread::spawn(move || {
+			for _ in 0..2 {
+				let stream = listener.accept().unwrap().0;
+				let mut client = server::Request::read(&stream, &stream).unwrap().accept().send().unwrap();
+				let _ = client.recv_until(|_| true, None);
+			}
+		});
+
+		let backoff = Backoff {
+			initial: Duration::from_millis(200),
+			jitter: 0.0,
+			..Backoff::default()
+		};
+		let mut client = ReconnectingClient::new(move || {
+			let response = try!(try!(Client::connect(&url)).send());
+			try!(response.validate());
+			Ok(response.begin())
+		}, backoff);
+		// The first subscription is rejected
+		let rejected = Rc::new(Cell::new(false));
+		let seen = rejected.clone();
+		client.set_on_connect(move |_| {
+			if seen.get() {
+				return Ok(());
+			}
+			seen.set(true);
+			Err(WebSocketError::ProtocolError("Subscription rejected"))
+		});
+
+		assert_eq!(client.connect().err(), Some(WebSocketError::ProtocolError("Subscription rejected")));
+		assert_eq!(client.connections(), 0);
+		let started = Instant::now();
+		client.connect().unwrap();
+		assert!(started.elapsed() >= Duration::from_millis(150));
+		assert_eq!(client.connections(), 1);
+		client.client().unwrap().send_message(&Message::close()).unwrap();
+		thread.join().unwrap();
+	}
+}
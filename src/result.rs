@@ -5,13 +5,24 @@ use std::str::Utf8Error;
 use std::error::Error;
 use std::convert::From;
 use std::fmt;
+use std::mem;
 use openssl::ssl::error::SslError;
 use hyper::Error as HttpError;
 use url::ParseError;
+use message::CloseCode;
 
 /// The type used for WebSocket results
 pub type WebSocketResult<T> = Result<T, WebSocketError>;
 
+/// The message of the `DataFrameError` for a control frame longer than 125 bytes.
+pub const CONTROL_FRAME_TOO_LONG: &'static str = "Control frame length too long";
+/// The message of the `ProtocolError` for a fragmented control frame.
+pub const FRAGMENTED_CONTROL_FRAME: &'static str = "Illegal fragmented control frame";
+/// The message of the `DataFrameError` for an unmasked data frame sent to a server.
+pub const EXPECTED_MASKED: &'static str = "Expected masked data frame";
+/// The message of the `DataFrameError` for a masked data frame sent to a client.
+pub const EXPECTED_UNMASKED: &'static str = "Expected unmasked data frame";
+
 /// Represents a WebSocket error
 #[derive(Debug)]
 pub enum WebSocketError {
@@ -23,8 +34,26 @@ pub enum WebSocketError {
 	ResponseError(&'static str),
 	/// Invalid WebSocket data frame error
 	DataFrameError(&'static str),
+	/// The proxy failed to connect to the remote endpoint
+	ProxyError(&'static str),
 	/// No data available
 	NoDataAvailable,
+	/// Too many pings were sent without receiving a pong
+	UnansweredPings,
+	/// Connecting or the opening handshake did not finish in time
+	Timeout,
+	/// The server was shut down with a `ShutdownHandle`
+	ServerShutdown,
+	/// The connection has been closed by the remote endpoint
+	ConnectionClosed {
+		/// Whether the remote endpoint sent a close message, rather than the connection
+		/// ending without one.
+		clean: bool,
+		/// The status code of the close message, if it had one.
+		code: Option<u16>,
+		/// The reason given in the close message, if it had a status code.
+		reason: Option<String>,
+	},
 	/// An input/output error
 	IoError(io::Error),
 	/// An HTTP parsing error
@@ -39,39 +68,121 @@ pub enum WebSocketError {
 	Utf8Error(Utf8Error),
 }
 
-impl fmt::Display for WebSocketError {
-	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-		try!(fmt.write_str("WebSocketError: "));
-		try!(fmt.write_str(self.description()));
-		Ok(())
+impl WebSocketError {
+	/// Returns true if a connection can still be used after a Receiver returned this error.
+	///
+	/// Protocol and UTF-8 errors are returned once the offending data frames have been
+	/// read completely, so the Receiver is left at the start of the next data frame and
+	/// the caller may carry on receiving, or close the connection as the protocol
+	/// requires. When a read times out, the part of a data frame which has arrived is
+	/// kept, so receiving again carries on where it stopped. After any other error, such
+	/// as an invalid data frame header or the connection failing part way through a data
+	/// frame, the Receiver can no longer find the data frame boundaries and the
+	/// connection must be dropped.
+	pub fn is_recoverable(&self) -> bool {
+		match *self {
+			WebSocketError::ProtocolError(_) | WebSocketError::Utf8Error(_) => true,
+			WebSocketError::Timeout => true,
+			WebSocketError::IoError(ref e) => {
+				e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut
+			}
+			_ => false,
+		}
+	}
+	/// Returns the status code the connection must be closed with after a Receiver
+	/// returned this error, if the protocol calls for one.
+	///
+	/// Invalid UTF-8 calls for 1007 (Invalid Payload). Control frames which are too long
+	/// or fragmented, and data frames which are masked when they should not be or the
+	/// other way around, call for 1002 (Protocol Error).
+	pub fn close_code(&self) -> Option<u16> {
+		match *self {
+			WebSocketError::Utf8Error(_) => Some(CloseCode::InvalidPayload.into()),
+			WebSocketError::DataFrameError(CONTROL_FRAME_TOO_LONG) |
+			WebSocketError::ProtocolError(FRAGMENTED_CONTROL_FRAME) |
+			WebSocketError::DataFrameError(EXPECTED_MASKED) |
+			WebSocketError::DataFrameError(EXPECTED_UNMASKED) => Some(CloseCode::ProtocolError.into()),
+			_ => None,
+		}
 	}
 }
 
-impl Error for WebSocketError {
-	fn description(&self) -> &str {
-		match *self {
-            WebSocketError::ProtocolError(_) => "WebSocket protocol error",
+impl fmt::Display for WebSocketError {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		try!(fmt.write_str("WebSocketError: "));
+		try!(fmt.write_str(match *self {
+			WebSocketError::ProtocolError(_) => "WebSocket protocol error",
 			WebSocketError::RequestError(_) => "WebSocket request error",
 			WebSocketError::ResponseError(_) => "WebSocket response error",
 			WebSocketError::DataFrameError(_) => "WebSocket data frame error",
+			WebSocketError::ProxyError(_) => "Proxy error",
 			WebSocketError::NoDataAvailable => "No data available",
+			WebSocketError::UnansweredPings => "Too many unanswered pings",
+			WebSocketError::Timeout => "Timed out",
+			WebSocketError::ServerShutdown => "Server shut down",
+			WebSocketError::ConnectionClosed { clean: true, .. } => "Connection closed",
+			WebSocketError::ConnectionClosed { clean: false, .. } => "Connection closed abnormally",
 			WebSocketError::IoError(_) => "I/O failure",
 			WebSocketError::HttpError(_) => "HTTP failure",
 			WebSocketError::UrlError(_) => "URL failure",
 			WebSocketError::SslError(_) => "SSL failure",
 			WebSocketError::Utf8Error(_) => "UTF-8 failure",
-            WebSocketError::WebSocketUrlError(_) => "WebSocket URL failure",
+			WebSocketError::WebSocketUrlError(_) => "WebSocket URL failure",
+		}));
+		Ok(())
+	}
+}
+
+/// Errors are compared for use in tests.
+///
+/// Variants holding a message are equal if their messages are, and I/O errors are equal
+/// if their kinds are. HTTP and SSL errors are equal if they are the same variant of
+/// the wrapped error, with I/O errors inside them compared by kind, since the wrapped
+/// types cannot be compared themselves. URL and UTF-8 errors are compared directly.
+impl PartialEq for WebSocketError {
+	fn eq(&self, other: &WebSocketError) -> bool {
+		match (self, other) {
+			(&WebSocketError::ProtocolError(a), &WebSocketError::ProtocolError(b)) => a == b,
+			(&WebSocketError::RequestError(a), &WebSocketError::RequestError(b)) => a == b,
+			(&WebSocketError::ResponseError(a), &WebSocketError::ResponseError(b)) => a == b,
+			(&WebSocketError::DataFrameError(a), &WebSocketError::DataFrameError(b)) => a == b,
+			(&WebSocketError::ProxyError(a), &WebSocketError::ProxyError(b)) => a == b,
+			(&WebSocketError::NoDataAvailable, &WebSocketError::NoDataAvailable) => true,
+			(&WebSocketError::UnansweredPings, &WebSocketError::UnansweredPings) => true,
+			(&WebSocketError::Timeout, &WebSocketError::Timeout) => true,
+			(&WebSocketError::ServerShutdown, &WebSocketError::ServerShutdown) => true,
+			(&WebSocketError::ConnectionClosed { clean: a, code: ref a_code, reason: ref a_reason },
+			 &WebSocketError::ConnectionClosed { clean: b, code: ref b_code, reason: ref b_reason }) => {
+				a == b && a_code == b_code && a_reason == b_reason
+			}
+			(&WebSocketError::IoError(ref a), &WebSocketError::IoError(ref b)) => a.kind() == b.kind(),
+			(&WebSocketError::HttpError(ref a), &WebSocketError::HttpError(ref b)) => match (a, b) {
+				(&HttpError::Io(ref a), &HttpError::Io(ref b)) => a.kind() == b.kind(),
+				(a, b) => mem::discriminant(a) == mem::discriminant(b),
+			},
+			(&WebSocketError::UrlError(ref a), &WebSocketError::UrlError(ref b)) => a == b,
+			(&WebSocketError::WebSocketUrlError(ref a), &WebSocketError::WebSocketUrlError(ref b)) => a == b,
+			(&WebSocketError::SslError(ref a), &WebSocketError::SslError(ref b)) => match (a, b) {
+				(&SslError::StreamError(ref a), &SslError::StreamError(ref b)) => a.kind() == b.kind(),
+				(a, b) => mem::discriminant(a) == mem::discriminant(b),
+			},
+			(&WebSocketError::Utf8Error(ref a), &WebSocketError::Utf8Error(ref b)) => a == b,
+			_ => false,
 		}
 	}
+}
 
-	fn cause(&self) -> Option<&Error> {
+impl Eq for WebSocketError {}
+
+impl Error for WebSocketError {
+	fn source(&self) -> Option<&(Error + 'static)> {
 		match *self {
 			WebSocketError::IoError(ref error) => Some(error),
 			WebSocketError::HttpError(ref error) => Some(error),
 			WebSocketError::UrlError(ref error) => Some(error),
 			WebSocketError::SslError(ref error) => Some(error),
 			WebSocketError::Utf8Error(ref error) => Some(error),
-            WebSocketError::WebSocketUrlError(ref error) => Some(error),
+			WebSocketError::WebSocketUrlError(ref error) => Some(error),
 			_ => None,
 		}
 	}
@@ -117,27 +228,95 @@ impl From<WSUrlErrorKind> for WebSocketError {
 }
 
 /// Represents a WebSocket URL error
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum WSUrlErrorKind {
     /// Fragments are not valid in a WebSocket URL
     CannotSetFragment,
     /// The scheme provided is invalid for a WebSocket
     InvalidScheme,
+    /// The port is not a number from 1 to 65535
+    InvalidPort,
+    /// A username or password was given, which cannot be used to connect
+    CannotSetCredentials,
 }
 
 impl fmt::Display for WSUrlErrorKind {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         try!(fmt.write_str("WebSocket Url Error: "));
-        try!(fmt.write_str(self.description()));
+        try!(fmt.write_str(match *self {
+            WSUrlErrorKind::CannotSetFragment => "WebSocket URL cannot set fragment",
+            WSUrlErrorKind::InvalidScheme => "WebSocket URL invalid scheme",
+            WSUrlErrorKind::InvalidPort => "WebSocket URL invalid port",
+            WSUrlErrorKind::CannotSetCredentials => "WebSocket URL cannot set credentials",
+        }));
         Ok(())
     }
 }
 
-impl Error for WSUrlErrorKind {
-    fn description(&self) -> &str {
-        match *self {
-            WSUrlErrorKind::CannotSetFragment => "WebSocket URL cannot set fragment",
-            WSUrlErrorKind::InvalidScheme => "WebSocket URL invalid scheme"
-        }
-    }
+impl Error for WSUrlErrorKind {}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::error::Error;
+	use std::io;
+
+	#[test]
+	fn test_close_code() {
+		use dataframe::DataFrame;
+		use std::str;
+
+		// Errors from the places that raise them, so that rewording one is noticed
+		let frames: [(&[u8], bool); 4] = [
+			(&[0x89, 0x7E, 0x00, 0xC8], false), // Too long
+			(&[0x09, 0x00], false), // Fragmented
+			(&[0x81, 0x00], true), // Not masked
+			(&[0x81, 0x80, 1, 2, 3, 4], false), // Masked
+		];
+		for &(data, should_be_masked) in frames.iter() {
+			let error = DataFrame::read_dataframe(&mut &data[..], should_be_masked).unwrap_err();
+			assert_eq!(error.close_code(), Some(1002));
+		}
+		let invalid = vec![0xFF];
+		let error = WebSocketError::from(str::from_utf8(&invalid).unwrap_err());
+		assert_eq!(error.close_code(), Some(1007));
+		assert_eq!(WebSocketError::ProtocolError("Message too large").close_code(), None);
+	}
+
+	#[test]
+	fn test_error_source() {
+		let err = WebSocketError::from(io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+		let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+		assert_eq!(source.kind(), io::ErrorKind::BrokenPipe);
+
+		let err = WebSocketError::from(HttpError::Method);
+		assert!(err.source().unwrap().downcast_ref::<HttpError>().is_some());
+
+		let err = WebSocketError::from(WSUrlErrorKind::InvalidScheme);
+		assert_eq!(err.source().unwrap().to_string(), "WebSocket Url Error: WebSocket URL invalid scheme");
+		assert_eq!(err.to_string(), "WebSocketError: WebSocket URL failure");
+		assert!(WebSocketError::NoDataAvailable.source().is_none());
+	}
+
+	#[test]
+	fn test_error_eq() {
+		assert_eq!(WebSocketError::ProtocolError("a"), WebSocketError::ProtocolError("a"));
+		assert!(WebSocketError::ProtocolError("a") != WebSocketError::ProtocolError("b"));
+		assert!(WebSocketError::ProtocolError("a") != WebSocketError::RequestError("a"));
+		assert_eq!(WebSocketError::Timeout, WebSocketError::Timeout);
+
+		let io = |kind, message| WebSocketError::IoError(io::Error::new(kind, message));
+		assert_eq!(io(io::ErrorKind::BrokenPipe, "a"), io(io::ErrorKind::BrokenPipe, "b"));
+		assert!(io(io::ErrorKind::BrokenPipe, "a") != io(io::ErrorKind::TimedOut, "a"));
+
+		assert_eq!(WebSocketError::HttpError(HttpError::Method), WebSocketError::HttpError(HttpError::Method));
+		assert!(WebSocketError::HttpError(HttpError::Method) != WebSocketError::HttpError(HttpError::Version));
+		let http_io = |kind| WebSocketError::HttpError(HttpError::Io(io::Error::new(kind, "")));
+		assert!(http_io(io::ErrorKind::BrokenPipe) != http_io(io::ErrorKind::TimedOut));
+
+		assert_eq!(
+			WebSocketError::from(WSUrlErrorKind::InvalidScheme),
+			WebSocketError::WebSocketUrlError(WSUrlErrorKind::InvalidScheme)
+		);
+	}
 }
@@ -0,0 +1,212 @@
+//! Provides a fixed capacity ring buffer for receiving messages with bounded memory.
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+
+use dataframe::{DataFrame, Opcode};
+
+/// What `Receiver::recv_into_ring()` received.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RingEvent {
+	/// The rest of a data message with the given opcode has been written to the ring.
+	Message(Opcode),
+	/// A control frame was received, possibly in the middle of a data message. The
+	/// data message is carried on with by the next call.
+	Control(DataFrame),
+}
+
+struct State {
+	data: VecDeque<u8>,
+	written: u64,
+	read: u64,
+	ends: VecDeque<u64>,
+	closed: bool,
+}
+
+/// A byte buffer with a fixed capacity, which is written to by one thread and read from
+/// by another, shared by cloning it.
+///
+/// Writing blocks while the buffer is full, until the reader drains it. The writer marks
+/// where each message ends, and reading returns `Ok(0)` at the end of each message, then
+/// carries on with the next one. At most `capacity` ends of messages are held, so that
+/// empty messages cannot fill memory either.
+#[derive(Clone)]
+pub struct RingBuffer {
+	inner: Arc<(Mutex<State>, Condvar)>,
+	capacity: usize,
+}
+
+impl RingBuffer {
+	/// Creates a new RingBuffer which holds at most `capacity` bytes.
+	///
+	/// Panics if `capacity` is zero.
+	pub fn new(capacity: usize) -> RingBuffer {
+		assert!(capacity > 0, "RingBuffer capacity must be greater than zero");
+		RingBuffer {
+			inner: Arc::new((Mutex::new(State {
+				data: VecDeque::with_capacity(capacity),
+				written: 0,
+				read: 0,
+				ends: VecDeque::new(),
+				closed: false,
+			}), Condvar::new())),
+			capacity: capacity,
+		}
+	}
+	/// Returns the most bytes this RingBuffer holds.
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+	/// Returns the number of bytes which have been written but not yet read.
+	pub fn len(&self) -> usize {
+		self.inner.0.lock().unwrap().data.len()
+	}
+	/// Returns true if there are no bytes which have been written but not yet read.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+	/// Writes all of `data`, blocking whenever the buffer is full until it is read from.
+	///
+	/// Fails with an error of kind `BrokenPipe` if the buffer has been closed.
+	pub fn push(&self, mut data: &[u8]) -> io::Result<()> {
+		let &(ref state, ref changed) = &*self.inner;
+		let mut state = state.lock().unwrap();
+		while !data.is_empty() {
+			if state.closed {
+				return Err(io::Error::new(io::ErrorKind::BrokenPipe, "The ring buffer is closed"));
+			}
+			let space = self.capacity - state.data.len();
+			if space == 0 {
+				state = changed.wait(state).unwrap();
+				continue;
+			}
+			let len = cmp::min(space, data.len());
+			state.data.extend(data[..len].iter().cloned());
+			state.written += len as u64;
+			data = &data[len..];
+			changed.notify_all();
+		}
+		Ok(())
+	}
+	/// Marks the end of a message at the current write position, blocking while
+	/// `capacity` ends of messages are waiting to be read.
+	///
+	/// Fails with an error of kind `BrokenPipe` if the buffer has been closed.
+	pub fn end_message(&self) -> io::Result<()> {
+		let &(ref state, ref changed) = &*self.inner;
+		let mut state = state.lock().unwrap();
+		loop {
+			if state.closed {
+				return Err(io::Error::new(io::ErrorKind::BrokenPipe, "The ring buffer is closed"));
+			}
+			if state.ends.len() < self.capacity {
+				break;
+			}
+			state = changed.wait(state).unwrap();
+		}
+		let written = state.written;
+		state.ends.push_back(written);
+		changed.notify_all();
+		Ok(())
+	}
+	/// Closes the buffer. Further writes fail, and once the remaining data has been
+	/// read, reads return `Ok(0)` immediately.
+	pub fn close(&self) {
+		let &(ref state, ref changed) = &*self.inner;
+		state.lock().unwrap().closed = true;
+		changed.notify_all();
+	}
+	/// Returns true if the buffer has been closed.
+	pub fn is_closed(&self) -> bool {
+		self.inner.0.lock().unwrap().closed
+	}
+}
+
+impl<'a> Read for &'a RingBuffer {
+	/// Reads data from the current message, blocking until some is available.
+	///
+	/// Returns `Ok(0)` once at the end of each message, or whenever the buffer is
+	/// closed and empty.
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let &(ref state, ref changed) = &*self.inner;
+		let mut state = state.lock().unwrap();
+		loop {
+			if state.ends.front() == Some(&state.read) {
+				state.ends.pop_front();
+				changed.notify_all();
+				return Ok(0);
+			}
+			if !state.data.is_empty() {
+				break;
+			}
+			if state.closed {
+				return Ok(0);
+			}
+			state = changed.wait(state).unwrap();
+		}
+		let mut len = cmp::min(buf.len(), state.data.len());
+		if let Some(&end) = state.ends.front() {
+			len = cmp::min(len, (end - state.read) as usize);
+		}
+		for (byte, slot) in state.data.drain(..len).zip(buf.iter_mut()) {
+			*slot = byte;
+		}
+		state.read += len as u64;
+		changed.notify_all();
+		Ok(len)
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::io::Read;
+	use std::thread;
+
+	#[test]
+	fn test_ring_buffer() {
+		let ring = RingBuffer::new(4);
+		let writer = ring.clone();
+		let thread = thread::spawn(move || {
+			writer.push(b"hello world").unwrap();
+			writer.end_message().unwrap();
+			writer.push(b"!").unwrap();
+			writer.end_message().unwrap();
+			writer.close();
+		});
+
+		let mut message = Vec::new();
+		(&ring).read_to_end(&mut message).unwrap();
+		assert_eq!(message, b"hello world".to_vec());
+		message.clear();
+		(&ring).read_to_end(&mut message).unwrap();
+		assert_eq!(message, b"!".to_vec());
+		thread.join().unwrap();
+		assert!(ring.is_closed() && ring.is_empty());
+		assert!(ring.push(b"x").is_err());
+		assert!(ring.end_message().is_err());
+	}
+
+	#[test]
+	fn test_ring_buffer_empty_messages() {
+		use std::sync::mpsc;
+		use std::time::Duration;
+
+		let ring = RingBuffer::new(2);
+		let writer = ring.clone();
+		let (tx, rx) = mpsc::channel();
+		let thread = thread::spawn(move || {
+			for _ in 0..3 {
+				writer.end_message().unwrap();
+			}
+			tx.send(()).unwrap();
+		});
+
+		// The third end waits for the first to be read
+		assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+		assert_eq!((&ring).read(&mut [0; 1]).unwrap(), 0);
+		rx.recv().unwrap();
+		thread.join().unwrap();
+	}
+}
@@ -1,11 +1,22 @@
 //! The default implementation of a WebSocket Sender.
 
-use std::io::Write;
+use std::io::{ErrorKind, Write};
 use std::io::Result as IoResult;
-use result::WebSocketResult;
+use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::Duration;
+use result::{WebSocketResult, WebSocketError};
+use dataframe::{DataFrame as DataFrameStruct, Opcode};
+use message::Type;
 use ws::dataframe::DataFrame;
+use ws::util::mask::{MaskingKeyProvider, RandomMaskingKeys};
 use stream::WebSocketStream;
 use stream::Shutdown;
+use ratelimit::{RateLimiter, RateUnit};
+use middleware::{Middleware, MiddlewareChain, to_owned_dataframe};
+use coalesce::Coalescer;
+use deflate::{DeflateParams, PreparedMessage};
+use metrics::Metrics;
 use ws;
 
 /// A Sender that wraps a Writer and provides a default implementation using
@@ -13,6 +24,12 @@ use ws;
 pub struct Sender<W> {
 	inner: W,
 	mask: bool,
+	masking_keys: Box<MaskingKeyProvider>,
+	middleware: MiddlewareChain,
+	auto_fragment: Option<usize>,
+	coalesce: Option<Coalescer>,
+	metrics: Option<Arc<Metrics>>,
+	message_len: usize,
 }
 
 impl<W> Sender<W> {
@@ -21,6 +38,12 @@ impl<W> Sender<W> {
 		Sender {
 			inner: writer,
 			mask: mask,
+			masking_keys: Box::new(RandomMaskingKeys),
+			middleware: MiddlewareChain::new(),
+			auto_fragment: None,
+			coalesce: None,
+			metrics: None,
+			message_len: 0,
 		}
 	}
 	/// Returns a reference to the underlying Writer.
@@ -31,6 +54,43 @@ impl<W> Sender<W> {
 	pub fn get_mut(&mut self) -> &mut W {
 		&mut self.inner
 	}
+	/// Sets the largest payload sent in a single data frame.
+	///
+	/// When set, any text, binary or continuation data frame with a larger payload is
+	/// automatically split into several fragments of at most `size` bytes. Control frames
+	/// are never fragmented. The default of `None` sends every data frame as it is.
+	///
+	/// Panics if `size` is `Some(0)`.
+	pub fn set_auto_fragment(&mut self, size: Option<usize>) {
+		assert!(size != Some(0), "Fragment size must be greater than zero");
+		self.auto_fragment = size;
+	}
+	/// Returns the largest payload sent in a single data frame, if limited.
+	pub fn auto_fragment(&self) -> Option<usize> {
+		self.auto_fragment
+	}
+	/// Sets where the keys which data frames are masked with come from, if this Sender
+	/// masks them, as a client's does.
+	///
+	/// The default, `RandomMaskingKeys`, is what RFC 6455 requires. A fixed or
+	/// sequential provider makes the bytes sent reproducible, which is only meant for
+	/// tests. See `MaskingKeyProvider`.
+	pub fn set_masking_key_provider<P>(&mut self, provider: P)
+	where P: MaskingKeyProvider + 'static {
+		self.masking_keys = Box::new(provider);
+	}
+	/// Sets the hooks called as data frames and messages are sent, or removes them if
+	/// `metrics` is None. See `Metrics`.
+	pub fn set_metrics(&mut self, metrics: Option<Arc<Metrics>>) {
+		self.metrics = metrics;
+	}
+	/// Registers a middleware which sees every data frame before it is sent.
+	///
+	/// Middleware is applied in the order it was added.
+	pub fn add_middleware<M>(&mut self, middleware: M)
+	where M: Middleware + 'static {
+		self.middleware.push(middleware);
+	}
 }
 
 impl Sender<WebSocketStream> {
@@ -50,12 +110,334 @@ impl Sender<WebSocketStream> {
     pub fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
         self.inner.set_nonblocking(nonblocking)
     }
+
+	/// Sets whether outgoing data frames are coalesced into fewer, larger writes.
+	///
+	/// With `Some((max_delay, max_bytes))`, data frames are buffered and written at once
+	/// when `max_bytes` have been buffered, or when `max_delay` has passed since the oldest
+	/// of them was sent, whichever comes first. A helper thread performs the delayed
+	/// writes. This saves system calls and packets when sending many small messages in
+	/// bursts, at the cost of adding up to `max_delay` of latency to each of them, and
+	/// works independently of `TCP_NODELAY`. `send_message_flushed()` writes buffered
+	/// data immediately. Errors from delayed writes are returned by the next send.
+	///
+	/// `None` (the default) writes every data frame as soon as it is sent. Any buffered
+	/// data is written before the setting changes.
+	pub fn set_coalesce(&mut self, coalesce: Option<(Duration, usize)>) -> IoResult<()> {
+		if let Some(coalescer) = self.coalesce.take() {
+			try!(coalescer.flush());
+		}
+		if let Some((max_delay, max_bytes)) = coalesce {
+			let writer = try!(self.inner.try_clone());
+			self.coalesce = Some(Coalescer::new(writer, max_delay, max_bytes));
+		}
+		Ok(())
+	}
+}
+
+impl<W: Write> Sender<W> {
+	/// Sends a single message and flushes the underlying Writer before returning.
+	///
+	/// When this returns `Ok(())` every data frame of the message has been handed
+	/// to the Writer and the Writer has been flushed, so no part of the message
+	/// remains buffered within this process.
+	pub fn send_message_flushed<'m, M, D>(&mut self, message: &'m M) -> WebSocketResult<()>
+	where M: ws::Message<'m, D>, D: DataFrame {
+		try!(ws::Sender::send_message(self, message));
+		ws::Sender::flush(self)
+	}
+	/// Returns how long the oldest data frame which has been sent but not yet written to
+	/// the connection has been waiting, or None if there is none.
+	///
+	/// Data frames only wait when coalescing is enabled with `set_coalesce()`, so this
+	/// is always None otherwise. A long wait indicates that the connection cannot keep
+	/// up. Data already written to the operating system's send buffer is not counted.
+	pub fn send_queue_latency(&self) -> Option<Duration> {
+		self.coalesce.as_ref().and_then(|coalescer| coalescer.oldest_age())
+	}
+	/// Sends a payload as a single message with the given data opcode, regardless
+	/// of where the payload came from.
+	///
+	/// `opcode` must be `Type::Text` or `Type::Binary`. A text payload is checked to
+	/// be valid UTF-8 first; use `send_as_unvalidated()` to skip the check.
+	pub fn send_as(&mut self, opcode: Type, payload: &[u8]) -> WebSocketResult<()> {
+		if opcode == Type::Text {
+			try!(from_utf8(payload));
+		}
+		self.send_as_unvalidated(opcode, payload)
+	}
+	/// Like `send_as()`, but never checks that a text payload is valid UTF-8.
+	pub fn send_as_unvalidated(&mut self, opcode: Type, payload: &[u8]) -> WebSocketResult<()> {
+		let opcode = match opcode {
+			Type::Text => Opcode::Text,
+			Type::Binary => Opcode::Binary,
+			_ => return Err(WebSocketError::ProtocolError(
+				"Only text and binary messages can be sent with send_as"
+			)),
+		};
+		let dataframe = DataFrameStruct::new(true, opcode, payload.to_vec());
+		ws::Sender::send_dataframe(self, &dataframe)
+	}
+	/// Sends a message compressed in advance, on a connection which negotiated the
+	/// given permessage-deflate parameters, or no compression if None.
+	///
+	/// A masking Sender is taken to belong to a client. Returns an error without
+	/// sending anything if the message cannot be sent compressed on this connection;
+	/// see `PreparedMessage::dataframe()`.
+	pub fn send_prepared(&mut self, message: &PreparedMessage, params: Option<&DeflateParams>) -> WebSocketResult<()> {
+		let dataframe = try!(message.dataframe(params, self.mask));
+		ws::Sender::send_dataframe(self, &dataframe)
+	}
+	/// Sends a single message, splitting each of its data frames into fragments with
+	/// payloads of at most `size` bytes, regardless of `auto_fragment()`.
+	///
+	/// Panics if `size` is zero.
+	pub fn send_message_fragmented<'m, M, D>(&mut self, message: &'m M, size: usize) -> WebSocketResult<()>
+	where M: ws::Message<'m, D>, D: DataFrame {
+		assert!(size > 0, "Fragment size must be greater than zero");
+		for ref dataframe in message.dataframes() {
+			let owned = try!(to_owned_dataframe(dataframe));
+			for frame in try!(self.middleware.outgoing(owned)) {
+				for fragment in fragment(frame, size) {
+					try!(self.write_dataframe(&fragment));
+				}
+			}
+		}
+		Ok(())
+	}
+	/// Starts sending a message of the given type one fragment at a time, which allows
+	/// control messages to be sent between the fragments.
+	///
+	/// `opcode` must be `Type::Text` or `Type::Binary`. Every fragment passes through
+	/// middleware and auto-fragmentation like any other data frame. Text fragments are
+	/// not checked to be valid UTF-8. No other data frames may be sent until the message
+	/// is finished with `FragmentWriter::finish()`; if the FragmentWriter is dropped
+	/// first, the message is left unfinished and the connection must be closed.
+	pub fn begin_fragmented<'a>(&'a mut self, opcode: Type) -> WebSocketResult<FragmentWriter<'a, W>> {
+		let opcode = match opcode {
+			Type::Text => Opcode::Text,
+			Type::Binary => Opcode::Binary,
+			_ => return Err(WebSocketError::ProtocolError(
+				"Only text and binary messages can be fragmented"
+			)),
+		};
+		Ok(FragmentWriter {
+			sender: self,
+			opcode: opcode,
+		})
+	}
+	fn write_dataframe<D>(&mut self, dataframe: &D) -> WebSocketResult<()>
+	where D: DataFrame {
+		let masking_key = if self.mask {
+			Some(self.masking_keys.next_key())
+		} else {
+			None
+		};
+		try!(match self.coalesce {
+			Some(ref coalescer) => dataframe.write_to_with_key(&mut &*coalescer, masking_key),
+			None => dataframe.write_to_with_key(&mut self.inner, masking_key),
+		});
+		if let Some(ref metrics) = self.metrics {
+			let (opcode, len) = (dataframe.opcode(), dataframe.size());
+			metrics.on_frame_sent(Opcode::new(opcode).unwrap(), len);
+			if opcode < 8 {
+				self.message_len += len;
+				if dataframe.is_last() {
+					metrics.on_message_sent(self.message_len);
+					self.message_len = 0;
+				}
+			}
+		}
+		Ok(())
+	}
+	/// Wraps this Sender in a `RateLimiter` which allows at most `messages_per_sec`
+	/// messages per second, with bursts of up to `burst` messages.
+	///
+	/// To limit by payload bytes instead, use `RateLimiter::new()` with `RateUnit::Bytes`.
+	pub fn rate_limit(self, messages_per_sec: u32, burst: u32) -> RateLimiter<Sender<W>> {
+		RateLimiter::new(self, messages_per_sec, burst, RateUnit::Messages)
+	}
 }
 
 impl<W: Write> ws::Sender for Sender<W> {
 	/// Sends a single data frame to the remote endpoint.
 	fn send_dataframe<D>(&mut self, dataframe: &D) -> WebSocketResult<()>
 	where D: DataFrame {
-		dataframe.write_to(&mut self.inner, self.mask)
+		let needs_fragmenting = match self.auto_fragment {
+			Some(size) => dataframe.opcode() < 8 && dataframe.size() > size,
+			None => false,
+		};
+		if self.middleware.is_empty() && !needs_fragmenting {
+			return self.write_dataframe(dataframe);
+		}
+		let owned = try!(to_owned_dataframe(dataframe));
+		for frame in try!(self.middleware.outgoing(owned)) {
+			match self.auto_fragment {
+				Some(size) => for fragment in fragment(frame, size) {
+					try!(self.write_dataframe(&fragment));
+				},
+				None => try!(self.write_dataframe(&frame)),
+			}
+		}
+		Ok(())
+	}
+
+	/// Writes out any coalesced data frames and flushes the underlying Writer.
+	fn flush(&mut self) -> WebSocketResult<()> {
+		if let Some(ref coalescer) = self.coalesce {
+			try!(coalescer.flush());
+		}
+		loop {
+			match self.inner.flush() {
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+				result => return Ok(try!(result)),
+			}
+		}
+	}
+}
+
+/// Sends a single message one fragment at a time. See `Sender::begin_fragmented()`.
+pub struct FragmentWriter<'a, W: 'a> {
+	sender: &'a mut Sender<W>,
+	opcode: Opcode,
+}
+
+impl<'a, W: Write> FragmentWriter<'a, W> {
+	/// Sends the next fragment of the message, without finishing it.
+	pub fn send_fragment(&mut self, data: &[u8]) -> WebSocketResult<()> {
+		self.send(false, data)
+	}
+	/// Sends a control message between fragments.
+	///
+	/// Returns an error without sending anything if the message has any data frames.
+	pub fn send_control<'m, M, D>(&mut self, message: &'m M) -> WebSocketResult<()>
+	where M: ws::Message<'m, D>, D: DataFrame {
+		if message.dataframes().any(|dataframe| dataframe.opcode() < 8) {
+			return Err(WebSocketError::ProtocolError(
+				"Only control messages can be sent within a fragmented message"
+			));
+		}
+		ws::Sender::send_message(self.sender, message)
+	}
+	/// Sends the last fragment of the message, which may be empty.
+	pub fn finish(mut self, data: &[u8]) -> WebSocketResult<()> {
+		self.send(true, data)
+	}
+	fn send(&mut self, finished: bool, data: &[u8]) -> WebSocketResult<()> {
+		let dataframe = DataFrameStruct::new(finished, self.opcode, data.to_vec());
+		try!(ws::Sender::send_dataframe(self.sender, &dataframe));
+		self.opcode = Opcode::Continuation;
+		Ok(())
+	}
+}
+
+/// Splits a data frame into fragments with payloads of at most `size` bytes.
+///
+/// Only the first fragment keeps the opcode and reserved bits, and only the last
+/// keeps the FIN bit. Control frames are returned unchanged.
+fn fragment(dataframe: DataFrameStruct, size: usize) -> Vec<DataFrameStruct> {
+	if dataframe.opcode as u8 >= 8 || dataframe.data.len() <= size {
+		return vec![dataframe];
+	}
+	let count = (dataframe.data.len() + size - 1) / size;
+	dataframe.data.chunks(size).enumerate().map(|(i, chunk)| {
+		DataFrameStruct {
+			finished: dataframe.finished && i == count - 1,
+			reserved: if i == 0 { dataframe.reserved } else { [false; 3] },
+			opcode: if i == 0 { dataframe.opcode } else { Opcode::Continuation },
+			data: chunk.to_vec(),
+		}
+	}).collect()
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use message::{Message, Type};
+	use ws;
+
+	#[test]
+	fn test_send_as() {
+		let mut sender = Sender::new(Vec::new(), false);
+		sender.send_as(Type::Text, b"hi").unwrap();
+		sender.send_as(Type::Binary, b"hi").unwrap();
+		assert!(sender.send_as(Type::Text, &[0xFF]).is_err());
+		sender.send_as_unvalidated(Type::Text, &[0xFF]).unwrap();
+		assert!(sender.send_as(Type::Ping, b"hi").is_err());
+
+		assert_eq!(&sender.get_ref()[..], &[0x81, 0x02, b'h', b'i', 0x82, 0x02, b'h', b'i', 0x81, 0x01, 0xFF][..]);
+	}
+
+	#[test]
+	fn test_masking_key_provider() {
+		let mut sender = Sender::new(Vec::new(), true);
+		let mut next = 0u8;
+		sender.set_masking_key_provider(move || {
+			next += 1;
+			[next, 0, 0, 0]
+		});
+		ws::Sender::send_message(&mut sender, &Message::text("hi")).unwrap();
+		ws::Sender::send_message(&mut sender, &Message::text("hi")).unwrap();
+		assert_eq!(&sender.get_ref()[..], &[
+			0x81, 0x82, 1, 0, 0, 0, b'h' ^ 1, b'i',
+			0x81, 0x82, 2, 0, 0, 0, b'h' ^ 2, b'i',
+		][..]);
+
+		// Even an all-zero key leaves the frames marked as masked
+		sender.get_mut().clear();
+		sender.set_masking_key_provider(|| [0; 4]);
+		ws::Sender::send_message(&mut sender, &Message::text("hi")).unwrap();
+		assert_eq!(&sender.get_ref()[..], &[0x81, 0x82, 0, 0, 0, 0, b'h', b'i'][..]);
+
+		// Senders which do not mask ignore the provider
+		let mut sender = Sender::new(Vec::new(), false);
+		sender.set_masking_key_provider(|| [1, 2, 3, 4]);
+		ws::Sender::send_message(&mut sender, &Message::text("hi")).unwrap();
+		assert_eq!(&sender.get_ref()[..], &[0x81, 0x02, b'h', b'i'][..]);
+	}
+
+	#[test]
+	fn test_oversized_control_frame() {
+		let mut sender = Sender::new(Vec::new(), true);
+		assert!(ws::Sender::send_message(&mut sender, &Message::ping(vec![0; 126])).is_err());
+		assert!(ws::Sender::send_message(&mut sender, &Message::close_because(1000, "x".repeat(124))).is_err());
+		assert!(sender.get_ref().is_empty());
+		ws::Sender::send_message(&mut sender, &Message::ping(vec![0; 125])).unwrap();
+	}
+
+	#[test]
+	fn test_auto_fragment() {
+		let mut sender = Sender::new(Vec::new(), false);
+		sender.set_auto_fragment(Some(2));
+		ws::Sender::send_message(&mut sender, &Message::text("abcde")).unwrap();
+		ws::Sender::send_message(&mut sender, &Message::ping(b"abc".to_vec())).unwrap();
+
+		assert_eq!(&sender.get_ref()[..], &[
+			0x01, 0x02, b'a', b'b',
+			0x00, 0x02, b'c', b'd',
+			0x80, 0x01, b'e',
+			0x89, 0x03, b'a', b'b', b'c',
+		][..]);
+	}
+
+	#[test]
+	fn test_begin_fragmented() {
+		let mut sender = Sender::new(Vec::new(), false);
+		assert!(sender.begin_fragmented(Type::Ping).is_err());
+		{
+			let mut writer = sender.begin_fragmented(Type::Text).unwrap();
+			writer.send_fragment(b"ab").unwrap();
+			writer.send_control(&Message::ping(b"p".to_vec())).unwrap();
+			assert!(writer.send_control(&Message::text("no")).is_err());
+			writer.send_fragment(b"cd").unwrap();
+			writer.finish(b"").unwrap();
+		}
+
+		assert_eq!(&sender.get_ref()[..], &[
+			0x01, 0x02, b'a', b'b',
+			0x89, 0x01, b'p',
+			0x00, 0x02, b'c', b'd',
+			0x80, 0x00,
+		][..]);
 	}
 }
@@ -0,0 +1,224 @@
+//! Limits the number of simultaneous connections, in total or from a single address.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Counts open connections, and refuses new ones beyond a limit.
+///
+/// Clones share their count.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimiter {
+	state: Arc<(Mutex<usize>, Condvar)>,
+	max: usize,
+}
+
+impl ConnectionLimiter {
+	/// Creates a new ConnectionLimiter which allows `max` connections at once.
+	pub fn new(max: usize) -> ConnectionLimiter {
+		ConnectionLimiter {
+			state: Arc::new((Mutex::new(0), Condvar::new())),
+			max: max,
+		}
+	}
+	/// Returns the number of connections allowed at once.
+	pub fn max(&self) -> usize {
+		self.max
+	}
+	/// Returns the number of open connections.
+	pub fn count(&self) -> usize {
+		*self.state.0.lock().unwrap()
+	}
+	/// Counts a new connection, returning a permit which must be kept for as long as the
+	/// connection is open, or None if the limit has been reached.
+	pub fn acquire(&self) -> Option<ConnectionPermit> {
+		let mut count = self.state.0.lock().unwrap();
+		if *count >= self.max {
+			return None;
+		}
+		*count += 1;
+		Some(ConnectionPermit { state: self.state.clone() })
+	}
+	/// Like `acquire()`, but waits for a connection to be released if the limit has been
+	/// reached.
+	pub fn acquire_blocking(&self) -> ConnectionPermit {
+		let &(ref count, ref released) = &*self.state;
+		let mut count = count.lock().unwrap();
+		while *count >= self.max {
+			count = released.wait(count).unwrap();
+		}
+		*count += 1;
+		ConnectionPermit { state: self.state.clone() }
+	}
+	/// Like `acquire_blocking()`, but gives up and returns None if no connection is
+	/// released within `timeout`.
+	pub fn acquire_timeout(&self, timeout: Duration) -> Option<ConnectionPermit> {
+		let deadline = Instant::now() + timeout;
+		let &(ref count, ref released) = &*self.state;
+		let mut count = count.lock().unwrap();
+		while *count >= self.max {
+			let now = Instant::now();
+			if now >= deadline {
+				return None;
+			}
+			count = released.wait_timeout(count, deadline - now).unwrap().0;
+		}
+		*count += 1;
+		Some(ConnectionPermit { state: self.state.clone() })
+	}
+}
+
+/// Releases a connection counted by a `ConnectionLimiter` when dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+	state: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Drop for ConnectionPermit {
+	fn drop(&mut self) {
+		let &(ref count, ref released) = &*self.state;
+		*count.lock().unwrap() -= 1;
+		released.notify_one();
+	}
+}
+
+/// Counts the open connections from each address, and refuses new ones beyond a limit.
+///
+/// Clones share their counts. Only addresses with at least one open connection are
+/// tracked, so the memory used grows with the number of distinct addresses (or IPv6
+/// /64 networks) currently connected, at a few dozen bytes each, and shrinks again as
+/// they disconnect.
+#[derive(Debug, Clone)]
+pub struct IpLimiter {
+	counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+	max_per_ip: usize,
+	group_ipv6: bool,
+}
+
+impl IpLimiter {
+	/// Creates a new IpLimiter which allows `max_per_ip` connections from each address.
+	///
+	/// If `group_ipv6` is true, IPv6 addresses in the same /64 network share a count,
+	/// since a single host is commonly assigned a whole /64.
+	pub fn new(max_per_ip: usize, group_ipv6: bool) -> IpLimiter {
+		IpLimiter {
+			counts: Arc::new(Mutex::new(HashMap::new())),
+			max_per_ip: max_per_ip,
+			group_ipv6: group_ipv6,
+		}
+	}
+	/// Returns the number of connections allowed from each address.
+	pub fn max_per_ip(&self) -> usize {
+		self.max_per_ip
+	}
+	/// Counts a new connection from `addr`, returning a guard which must be kept for as
+	/// long as the connection is open, or None if the address is already at the limit.
+	pub fn acquire(&self, addr: IpAddr) -> Option<IpGuard> {
+		let key = self.key(addr);
+		let mut counts = self.counts.lock().unwrap();
+		let count = counts.entry(key).or_insert(0);
+		if *count >= self.max_per_ip {
+			if *count == 0 {
+				counts.remove(&key);
+			}
+			return None;
+		}
+		*count += 1;
+		Some(IpGuard {
+			counts: self.counts.clone(),
+			key: key,
+		})
+	}
+	/// Returns the number of open connections counted against `addr`.
+	pub fn count(&self, addr: IpAddr) -> usize {
+		let key = self.key(addr);
+		self.counts.lock().unwrap().get(&key).cloned().unwrap_or(0)
+	}
+
+	fn key(&self, addr: IpAddr) -> IpAddr {
+		match addr {
+			IpAddr::V6(addr) if self.group_ipv6 => {
+				let s = addr.segments();
+				IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+			}
+			addr => addr,
+		}
+	}
+}
+
+/// Releases a connection counted by an `IpLimiter` when dropped.
+#[derive(Debug)]
+pub struct IpGuard {
+	counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+	key: IpAddr,
+}
+
+impl Drop for IpGuard {
+	fn drop(&mut self) {
+		let mut counts = self.counts.lock().unwrap();
+		let remove = match counts.get_mut(&self.key) {
+			Some(count) => {
+				*count -= 1;
+				*count == 0
+			}
+			None => false,
+		};
+		if remove {
+			counts.remove(&self.key);
+		}
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::net::IpAddr;
+
+	#[test]
+	fn test_connection_limiter() {
+		use std::thread;
+		use std::time::Duration;
+
+		let limiter = ConnectionLimiter::new(2);
+		let first = limiter.acquire().unwrap();
+		let _second = limiter.acquire().unwrap();
+		assert!(limiter.acquire().is_none());
+		assert_eq!(limiter.count(), 2);
+		drop(first);
+		assert_eq!(limiter.count(), 1);
+
+		let _third = limiter.acquire_blocking();
+		let waiting = limiter.clone();
+		let thread = thread::spawn(move || {
+			let _fourth = waiting.acquire_blocking();
+		});
+		thread::sleep(Duration::from_millis(20));
+		assert_eq!(limiter.count(), 2);
+		drop(_third);
+		thread.join().unwrap();
+		assert_eq!(limiter.count(), 1);
+
+		let _fifth = limiter.acquire_timeout(Duration::from_millis(10)).unwrap();
+		assert!(limiter.acquire_timeout(Duration::from_millis(10)).is_none());
+	}
+
+	#[test]
+	fn test_ip_limiter() {
+		let limiter = IpLimiter::new(2, true);
+		let a: IpAddr = "192.0.2.1".parse().unwrap();
+		let first = limiter.acquire(a).unwrap();
+		let _second = limiter.acquire(a).unwrap();
+		assert!(limiter.acquire(a).is_none());
+		assert!(limiter.acquire("192.0.2.2".parse().unwrap()).is_some());
+		drop(first);
+		assert_eq!(limiter.count(a), 1);
+		assert!(limiter.acquire(a).is_some());
+
+		// Addresses in one IPv6 /64 share a count
+		let _b = limiter.acquire("2001:db8::1".parse().unwrap()).unwrap();
+		let _c = limiter.acquire("2001:db8::2:3".parse().unwrap()).unwrap();
+		assert!(limiter.acquire("2001:db8::ffff".parse().unwrap()).is_none());
+		assert!(limiter.acquire("2001:db8:0:1::1".parse().unwrap()).is_some());
+		assert!(IpLimiter::new(2, false).acquire("2001:db8::ffff".parse().unwrap()).is_some());
+	}
+}
@@ -3,16 +3,39 @@ use std::net::{SocketAddr, ToSocketAddrs, TcpListener};
 use std::net::Shutdown;
 use std::io::{Read, Write};
 use std::io;
+use std::cmp;
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 pub use self::request::Request;
 pub use self::response::Response;
+pub use self::proxy::TrustedProxies;
+pub use self::limit::{IpLimiter, ConnectionLimiter, ConnectionPermit};
+pub use self::shutdown::{ShutdownHandle, is_shutdown_error};
+pub use self::sni::{SniContexts, set_sni_selector};
 
-use stream::WebSocketStream;
+use stream::{WebSocketStream, SharedSslStream, TcpOptions};
+use result::{WebSocketResult, WebSocketError};
+use client::{Client, Sender, Receiver};
+use dataframe::DataFrame;
+use deflate::DeflateParams;
+
+use hyper::Error as HttpError;
+use hyper::header::Headers;
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use hyper::version::HttpVersion;
 
 use openssl::ssl::SslContext;
 use openssl::ssl::SslStream;
 
 pub mod request;
 pub mod response;
+pub mod proxy;
+pub mod limit;
+pub mod shutdown;
+pub mod sni;
 
 /// Represents a WebSocket server which can work with either normal (non-secure) connections, or secure WebSocket connections.
 ///
@@ -78,6 +101,15 @@ pub mod response;
 pub struct Server<'a> {
 	inner: TcpListener,
 	context: Option<&'a SslContext>,
+	trusted_proxies: TrustedProxies,
+	accept_filter: Option<Arc<Fn(SocketAddr) -> bool + Send + Sync>>,
+	ip_limiter: Option<IpLimiter>,
+	connection_limiter: Option<ConnectionLimiter>,
+	block_at_capacity: bool,
+	shutdown: Arc<AtomicBool>,
+	strict: bool,
+	deflate: Option<DeflateParams>,
+	tcp_options: Option<TcpOptions>,
 }
 
 impl<'a> Server<'a> {
@@ -86,6 +118,15 @@ impl<'a> Server<'a> {
 		Ok(Server {
 			inner: try!(TcpListener::bind(&addr)),
 			context: None,
+			trusted_proxies: TrustedProxies::new(),
+			accept_filter: None,
+			ip_limiter: None,
+			connection_limiter: None,
+			block_at_capacity: true,
+			shutdown: Arc::new(AtomicBool::new(false)),
+			strict: true,
+			deflate: None,
+			tcp_options: None,
 		})
 	}
 	/// Bind this Server to this socket, utilising the given SslContext
@@ -93,6 +134,15 @@ impl<'a> Server<'a> {
 		Ok(Server {
 			inner: try!(TcpListener::bind(&addr)),
 			context: Some(context),
+			trusted_proxies: TrustedProxies::new(),
+			accept_filter: None,
+			ip_limiter: None,
+			connection_limiter: None,
+			block_at_capacity: true,
+			shutdown: Arc::new(AtomicBool::new(false)),
+			strict: true,
+			deflate: None,
+			tcp_options: None,
 		})
 	}
 	/// Get the socket address of this server
@@ -105,13 +155,145 @@ impl<'a> Server<'a> {
 		let inner = try!(self.inner.try_clone());
 		Ok(Server {
 			inner: inner,
-			context: self.context
+			context: self.context,
+			trusted_proxies: self.trusted_proxies.clone(),
+			accept_filter: self.accept_filter.clone(),
+			ip_limiter: self.ip_limiter.clone(),
+			connection_limiter: self.connection_limiter.clone(),
+			block_at_capacity: self.block_at_capacity,
+			shutdown: self.shutdown.clone(),
+			strict: self.strict,
+			deflate: self.deflate,
+			tcp_options: self.tcp_options,
 		})
 	}
 
+	/// Sets the reverse proxies whose forwarding headers are trusted.
+	///
+	/// `serve()` uses these to set `Client::real_peer_addr()`. When handling requests
+	/// directly, pass them to `Request::real_peer_addr()` instead.
+	pub fn set_trusted_proxies(&mut self, proxies: TrustedProxies) {
+		self.trusted_proxies = proxies;
+	}
+	/// Sets a filter which is called with the address of every incoming TCP connection,
+	/// before the TLS handshake or anything else is done with it.
+	///
+	/// Connections for which the filter returns false are closed immediately, and
+	/// `accept()` carries on waiting for the next connection. This is the cheapest point
+	/// at which to turn away abusive addresses.
+	pub fn set_accept_filter<F>(&mut self, filter: F)
+	where F: Fn(SocketAddr) -> bool + Send + Sync + 'static {
+		self.accept_filter = Some(Arc::new(filter));
+	}
+	/// Limits the number of simultaneous connections `serve()` handles from a single
+	/// address, or removes the limit if `max` is None.
+	///
+	/// Connections beyond the limit are closed immediately, before their request is
+	/// read. If `group_ipv6` is true, IPv6 addresses in the same /64 network share a
+	/// limit. The limit applies to the address of the TCP connection, so it should not be
+	/// used behind a reverse proxy. See `IpLimiter` for the memory this uses.
+	pub fn set_max_connections_per_ip(&mut self, max: Option<usize>, group_ipv6: bool) {
+		self.ip_limiter = max.map(|max| IpLimiter::new(max, group_ipv6));
+	}
+	/// Limits the number of connections accepted by this Server (and its clones) which
+	/// may be open at once, or removes the limit if `max` is None.
+	///
+	/// Each accepted connection holds a `ConnectionPermit`, which is passed on from the
+	/// `Connection` to the `Request` and then to the `Client`, and released when that is
+	/// dropped. At the limit, `accept()` waits for a permit to be released before
+	/// accepting another connection if `block` is true. Otherwise it accepts and closes
+	/// the connection, returning an error of kind `ConnectionRefused`.
+	pub fn set_max_connections(&mut self, max: Option<usize>, block: bool) {
+		self.connection_limiter = max.map(ConnectionLimiter::new);
+		self.block_at_capacity = block;
+	}
+	/// Returns the number of connections counted against the limit set with
+	/// `set_max_connections()` which are still open, or 0 if there is no limit.
+	pub fn active_connections(&self) -> usize {
+		self.connection_limiter.as_ref().map_or(0, |limiter| limiter.count())
+	}
+	/// Sets whether the clients `serve()` passes to its handler make every check required
+	/// by RFC 6455 on what they receive. This is enabled by default.
+	///
+	/// See `Receiver::set_strict()`.
+	pub fn set_strict(&mut self, strict: bool) {
+		self.strict = strict;
+	}
+	/// Sets the permessage-deflate configuration `serve()` negotiates with clients that
+	/// offer the extension, or disables compression if `config` is None (the default).
+	///
+	/// See `server::Response::negotiate_deflate()`.
+	pub fn set_deflate(&mut self, config: Option<DeflateParams>) {
+		self.deflate = config;
+	}
+	/// Sets the socket options applied to every accepted connection before its TLS
+	/// handshake, or leaves the operating system's defaults if `options` is None (the
+	/// default).
+	pub fn set_tcp_options(&mut self, options: Option<TcpOptions>) {
+		self.tcp_options = options;
+	}
+	/// Returns the reverse proxies whose forwarding headers are trusted.
+	pub fn trusted_proxies(&self) -> &TrustedProxies {
+		&self.trusted_proxies
+	}
+	/// Returns a handle which can shut this Server down from another thread.
+	///
+	/// Once shut down, `accept()` fails with an error for which `is_shutdown_error()`
+	/// returns true, iterating over the Server ends and `serve()` returns. Connections
+	/// which have already been accepted are left open. Clones of this Server made with
+	/// `try_clone()` are shut down too, but only one blocked call to `accept()` is woken
+	/// up, so the others return once their next connection arrives.
+	pub fn shutdown_handle(&self) -> io::Result<ShutdownHandle> {
+		let addr = try!(self.inner.local_addr());
+		Ok(ShutdownHandle::new(self.shutdown.clone(), addr))
+	}
+	/// Returns true if this Server has been shut down.
+	pub fn is_shutdown(&self) -> bool {
+		self.shutdown.load(Ordering::SeqCst)
+	}
+
 	/// Wait for and accept an incoming WebSocket connection, returning a WebSocketRequest
+	///
+	/// See `set_max_connections()` for what happens when the connection limit is reached,
+	/// and `shutdown_handle()` for how to stop waiting.
 	pub fn accept(&mut self) -> io::Result<Connection<WebSocketStream, WebSocketStream>> {
-		let stream = try!(self.inner.accept()).0;
+		let mut permit = None;
+		if let Some(ref limiter) = self.connection_limiter {
+			// Wait in short steps, as a shutdown only wakes up the listener
+			while self.block_at_capacity && permit.is_none() {
+				if self.is_shutdown() {
+					return Err(io::Error::new(io::ErrorKind::NotConnected, WebSocketError::ServerShutdown));
+				}
+				permit = limiter.acquire_timeout(Duration::from_millis(100));
+			}
+		}
+		let stream = loop {
+			if self.is_shutdown() {
+				return Err(io::Error::new(io::ErrorKind::NotConnected, WebSocketError::ServerShutdown));
+			}
+			let (stream, addr) = match self.inner.accept() {
+				Ok(_) | Err(_) if self.is_shutdown() => continue,
+				result => try!(result),
+			};
+			match self.accept_filter {
+				Some(ref filter) if !filter(addr) => {
+					let _ = stream.shutdown(Shutdown::Both);
+				}
+				_ => break stream,
+			}
+		};
+		if let Some(ref limiter) = self.connection_limiter {
+			if permit.is_none() {
+				permit = limiter.acquire();
+				if permit.is_none() {
+					let _ = stream.shutdown(Shutdown::Both);
+					return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Connection limit reached"));
+				}
+			}
+		}
+		if let Some(ref options) = self.tcp_options {
+			try!(options.apply(&stream));
+		}
 		let wsstream = match self.context {
 			Some(context) => {
 				let sslstream = match SslStream::accept(context, stream) {
@@ -120,11 +302,122 @@ impl<'a> Server<'a> {
 						return Err(io::Error::new(io::ErrorKind::Other, err));
 					}
 				};
-				WebSocketStream::Ssl(sslstream)
+				WebSocketStream::Ssl(SharedSslStream::new(sslstream))
 			}
 			None => { WebSocketStream::Tcp(stream) }
 		};
-		Ok(Connection(try!(wsstream.try_clone()), try!(wsstream.try_clone())))
+		Ok(Connection(try!(wsstream.try_clone()), try!(wsstream.try_clone()), permit))
+	}
+
+	/// Completes a WebSocket handshake for a request whose head has already been read
+	/// and parsed elsewhere, such as by an existing HTTP server or router.
+	///
+	/// The request is validated, and if it is valid a Switching Protocols response is
+	/// written to `writer` and a Client is returned. If it is invalid, the validation
+	/// error is returned and nothing is written, so the caller can respond as it sees fit.
+	///
+	/// `reader` must carry on from the end of the request head; any data buffered by the
+	/// code which parsed the request must be passed on with it.
+	pub fn upgrade_from_parts<R, W>(reader: R, writer: W, method: Method, url: RequestUri, version: HttpVersion, headers: Headers) -> WebSocketResult<Client<DataFrame, Sender<W>, Receiver<R>>>
+	where R: Read, W: Write {
+		let request = Request::from_parts(method, url, version, headers, reader, writer);
+		try!(request.validate());
+		request.accept().send()
+	}
+
+	/// Accepts connections until the Server is shut down, handling each one on a new
+	/// thread.
+	///
+	/// For each connection the request is read and validated on its own thread. Invalid
+	/// requests are answered with a Bad Request response, valid ones are accepted and
+	/// the resulting client is passed to `handler`. Connections which fail before
+	/// reaching the handler are dropped.
+	///
+	/// At most `max_handlers` connections are processed at once; once that many are in
+	/// progress, no more connections are accepted until one of them finishes. See also
+	/// `set_max_connections_per_ip()`. If accepting fails, for example because the
+	/// process has run out of file descriptors, the next attempt is delayed by up to a
+	/// second, growing while the failures carry on.
+	///
+	/// Once the Server is shut down with a `ShutdownHandle`, this returns without waiting
+	/// for the connections which are still being handled.
+	///
+	/// Panics if `max_handlers` is zero.
+	pub fn serve<F>(&mut self, max_handlers: usize, handler: F)
+	where F: Fn(Client<DataFrame, Sender<WebSocketStream>, Receiver<WebSocketStream>>) + Send + Sync + 'static {
+		let handler = Arc::new(handler);
+		let proxies = Arc::new(self.trusted_proxies.clone());
+		let strict = self.strict;
+		let deflate = self.deflate;
+		assert!(max_handlers > 0, "There must be at least one handler");
+		let handlers = ConnectionLimiter::new(max_handlers);
+		let mut backoff = None;
+		loop {
+			// Wait for a free slot in short steps, so that a shutdown is noticed even while
+			// every slot is taken by a long-lived connection
+			let guard = loop {
+				if self.is_shutdown() {
+					return;
+				}
+				if let Some(guard) = handlers.acquire_timeout(Duration::from_millis(100)) {
+					break guard;
+				}
+			};
+			let mut connection = match self.accept() {
+				Ok(connection) => {
+					backoff = None;
+					connection
+				}
+				Err(ref e) if is_shutdown_error(e) => return,
+				Err(_) => {
+					// Errors such as running out of file descriptors would otherwise repeat
+					// at once
+					let delay = backoff.map_or(Duration::from_millis(5), |delay: Duration| {
+						cmp::min(delay * 2, Duration::from_secs(1))
+					});
+					backoff = Some(delay);
+					drop(guard);
+					thread::sleep(delay);
+					continue;
+				}
+			};
+			let ip_guard = match self.ip_limiter {
+				Some(ref limiter) => {
+					let guard = connection.0.peer_addr().ok()
+						.and_then(|addr| limiter.acquire(addr.ip()));
+					if guard.is_none() {
+						let _ = connection.shutdown(Shutdown::Both);
+						continue;
+					}
+					guard
+				}
+				None => None,
+			};
+			let handler = handler.clone();
+			let proxies = proxies.clone();
+			thread::spawn(move || {
+				let _guard = guard;
+				let _ip_guard = ip_guard;
+				let request = match connection.read_request() {
+					Ok(request) => request,
+					Err(_) => return,
+				};
+				if request.validate().is_err() {
+					let _ = request.fail().send_into_inner();
+					return;
+				}
+				let real_peer_addr = request.real_peer_addr(&proxies).ok();
+				let mut response = request.accept();
+				if let Some(ref config) = deflate {
+					response.negotiate_deflate(config);
+				}
+				if let Ok(mut client) = response.send() {
+					client.set_real_peer_addr(real_peer_addr);
+					client.get_mut_receiver().set_strict(strict);
+					handler(client);
+				}
+			});
+		}
 	}
 
     /// Changes whether the Server is in nonblocking mode.
@@ -140,18 +433,29 @@ impl<'a> Iterator for Server<'a> {
 	type Item = io::Result<Connection<WebSocketStream, WebSocketStream>>;
 
 	fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-		Some(self.accept())
+		match self.accept() {
+			Err(ref e) if is_shutdown_error(e) => None,
+			result => Some(result),
+		}
 	}
 }
 
 /// Represents a connection to the server that has not been processed yet.
-pub struct Connection<R: Read, W: Write>(R, W);
+pub struct Connection<R: Read, W: Write>(R, W, Option<ConnectionPermit>);
 
 impl<R: Read, W: Write> Connection<R, W> {
+	/// Takes the permit counting this connection against the Server's connection limit,
+	/// so that it can be kept if the connection is not read with `read_request()`.
+	pub fn take_permit(&mut self) -> Option<ConnectionPermit> {
+		self.2.take()
+	}
 	/// Process this connection and read the request.
 	pub fn read_request(self) -> io::Result<Request<R, W>> {
 		match Request::read(self.0, self.1) {
-			Ok(result) => { Ok(result) },
+			Ok(mut result) => {
+				result.set_permit(self.2);
+				Ok(result)
+			},
 			Err(err) => {
 				Err(io::Error::new(io::ErrorKind::InvalidInput, err))
 			}
@@ -160,6 +464,26 @@ impl<R: Read, W: Write> Connection<R, W> {
 }
 
 impl Connection<WebSocketStream, WebSocketStream> {
+	/// Process this connection and read the request, failing with an error of kind
+	/// `TimedOut` unless the complete request arrives within `timeout`.
+	///
+	/// If `line_timeout` is given, the request line must also arrive within that time.
+	/// This protects against clients which deliberately send their request slowly.
+	/// See `Request::read_timeout()`.
+	pub fn read_request_timeout(self, timeout: Duration, line_timeout: Option<Duration>) -> io::Result<Request<WebSocketStream, WebSocketStream>> {
+		match Request::read_timeout(self.0, self.1, timeout, line_timeout) {
+			Ok(mut result) => {
+				result.set_permit(self.2);
+				Ok(result)
+			},
+			Err(WebSocketError::HttpError(HttpError::Io(err))) => Err(err),
+			Err(WebSocketError::IoError(err)) => Err(err),
+			Err(err) => {
+				Err(io::Error::new(io::ErrorKind::InvalidInput, err))
+			}
+		}
+	}
+
     /// Shuts down the currennt connection in the specified way.
     /// All future IO calls to this connection will return immediately with an appropriate
     /// return value.
@@ -167,3 +491,133 @@ impl Connection<WebSocketStream, WebSocketStream> {
         self.0.shutdown(how)
     }
 }
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::io::ErrorKind;
+	use std::net::TcpStream;
+	use client;
+	use url::Url;
+
+	#[test]
+	fn test_max_connections() {
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		server.set_max_connections(Some(1), false);
+		let addr = server.local_addr().unwrap();
+		let url = Url::parse(&format!("ws://{}/", addr)).unwrap();
+
+		let connecting = thread::spawn(move || {
+			let response = client::Client::connect(&url).unwrap().send().unwrap();
+			response.validate().unwrap();
+			let _client = response.begin();
+			let _refused = TcpStream::connect(addr);
+		});
+		let request = server.accept().unwrap().read_request().unwrap();
+		let client = request.accept().send().unwrap();
+		assert_eq!(server.active_connections(), 1);
+		match server.accept() {
+			Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => (),
+			other => panic!("expected the connection to be refused, got {:?}", other.map(|_| ())),
+		}
+		connecting.join().unwrap();
+
+		// Dropping the Client releases its permit
+		drop(client);
+		assert_eq!(server.active_connections(), 0);
+	}
+
+	#[test]
+	fn test_shutdown_handle() {
+		let mut server = Server::bind("0.0.0.0:0").unwrap();
+		let addr = server.local_addr().unwrap();
+		let handle = server.shutdown_handle().unwrap();
+
+		// An accepted connection outlives the shutdown
+		let _peer = TcpStream::connect(("127.0.0.1", addr.port())).unwrap();
+		let connection = server.accept().unwrap();
+
+		let shutting_down = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			handle.clone().shutdown();
+			handle.shutdown();
+		});
+		match server.accept() {
+			Err(ref e) if is_shutdown_error(e) => (),
+			other => panic!("expected a shutdown error, got {:?}", other.map(|_| ())),
+		}
+		shutting_down.join().unwrap();
+		assert!(server.is_shutdown());
+		assert!(server.next().is_none());
+		assert!(!is_shutdown_error(&io::Error::new(ErrorKind::NotConnected, "not connected")));
+		assert!(connection.0.peer_addr().is_ok());
+	}
+
+	#[test]
+	fn test_serve_shutdown_at_capacity() {
+		use std::sync::mpsc;
+		use std::sync::Mutex;
+
+		let server = Server::bind("127.0.0.1:0").unwrap();
+		let handle = server.shutdown_handle().unwrap();
+		let url = Url::parse(&format!("ws://{}/", server.local_addr().unwrap())).unwrap();
+		let (started, handling) = mpsc::channel();
+		let (release, released) = mpsc::channel::<()>();
+		let started = Mutex::new(started);
+		let released = Mutex::new(released);
+		let serving = thread::spawn(move || {
+			let mut server = server;
+			// The only slot is held until the test ends
+			server.serve(1, move |_client| {
+				started.lock().unwrap().send(()).unwrap();
+				let _ = released.lock().unwrap().recv();
+			});
+		});
+
+		let response = client::Client::connect(&url).unwrap().send().unwrap();
+		response.validate().unwrap();
+		let _client = response.begin();
+		handling.recv().unwrap();
+		handle.shutdown();
+		serving.join().unwrap();
+		drop(release);
+	}
+
+	#[test]
+	fn test_shutdown_at_capacity() {
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		server.set_max_connections(Some(1), true);
+		let addr = server.local_addr().unwrap();
+		let handle = server.shutdown_handle().unwrap();
+
+		let _peer = TcpStream::connect(addr).unwrap();
+		let _connection = server.accept().unwrap();
+		assert_eq!(server.active_connections(), 1);
+
+		let shutting_down = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(50));
+			handle.shutdown();
+		});
+		match server.accept() {
+			Err(ref e) if is_shutdown_error(e) => (),
+			other => panic!("expected a shutdown error, got {:?}", other.map(|_| ())),
+		}
+		shutting_down.join().unwrap();
+	}
+
+	#[test]
+	fn test_tcp_options() {
+		use stream::TcpOptions;
+
+		let options = TcpOptions { nodelay: true, keepalive: Some(Duration::from_secs(30)) };
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		server.set_tcp_options(Some(options));
+		let url = Url::parse(&format!("ws://{}/", server.local_addr().unwrap())).unwrap();
+
+		let request = client::Client::connect_with_tcp_options(&url, &options).unwrap();
+		assert!(request.get_writer().tcp_ref().nodelay().unwrap());
+
+		let connection = server.accept().unwrap();
+		assert!(connection.0.tcp_ref().nodelay().unwrap());
+	}
+}
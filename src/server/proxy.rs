@@ -0,0 +1,195 @@
+//! Determines the real address of clients connecting through trusted reverse proxies.
+use std::io;
+use std::net::IpAddr;
+use std::str::{self, FromStr};
+
+use hyper::header::Headers;
+
+/// A set of networks whose `Forwarded` and `X-Forwarded-For` headers are trusted.
+///
+/// Headers are only believed when they were added by a trusted proxy, as any client
+/// may send them. When a connection comes from a trusted proxy, the forwarded addresses
+/// are examined from the most recent hop backwards, and the first address which is
+/// not itself a trusted proxy is the real client address.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+	networks: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+	/// Creates a new, empty set of trusted proxies.
+	pub fn new() -> TrustedProxies {
+		TrustedProxies {
+			networks: Vec::new(),
+		}
+	}
+	/// Trusts the given network in CIDR notation (e.g. `10.0.0.0/8`), or single address.
+	pub fn add(&mut self, network: &str) -> io::Result<()> {
+		let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid trusted proxy network");
+		let mut parts = network.splitn(2, '/');
+		let addr = try!(parts.next().unwrap_or("").parse::<IpAddr>().map_err(|_| invalid()));
+		let max = match addr {
+			IpAddr::V4(_) => 32,
+			IpAddr::V6(_) => 128,
+		};
+		let prefix = match parts.next() {
+			Some(prefix) => try!(prefix.parse::<u8>().map_err(|_| invalid())),
+			None => max,
+		};
+		if prefix > max {
+			return Err(invalid());
+		}
+		self.networks.push((addr, prefix));
+		Ok(())
+	}
+	/// Returns true if the given address is within a trusted network.
+	pub fn contains(&self, addr: IpAddr) -> bool {
+		self.networks.iter().any(|&(network, prefix)| {
+			match (network, addr) {
+				(IpAddr::V4(network), IpAddr::V4(addr)) => {
+					prefix_matches(&network.octets(), &addr.octets(), prefix)
+				}
+				(IpAddr::V6(network), IpAddr::V6(addr)) => {
+					prefix_matches(&network.octets(), &addr.octets(), prefix)
+				}
+				_ => false,
+			}
+		})
+	}
+	/// Returns the real address of a client connecting from `peer` with the given
+	/// request headers.
+	///
+	/// The `Forwarded` header is preferred over `X-Forwarded-For`. If `peer` is not a
+	/// trusted proxy, or there are no usable forwarded addresses, `peer` is returned.
+	pub fn real_peer_addr(&self, peer: IpAddr, headers: &Headers) -> IpAddr {
+		if !self.contains(peer) {
+			return peer;
+		}
+		let hops = match forwarded(headers) {
+			Some(hops) => hops,
+			None => x_forwarded_for(headers),
+		};
+		let mut real = peer;
+		for hop in hops.into_iter().rev() {
+			match hop {
+				Some(addr) => {
+					real = addr;
+					if !self.contains(addr) {
+						break;
+					}
+				}
+				// An obfuscated or unknown hop cannot be followed any further
+				None => break,
+			}
+		}
+		real
+	}
+}
+
+fn prefix_matches(network: &[u8], addr: &[u8], prefix: u8) -> bool {
+	let bytes = (prefix / 8) as usize;
+	let bits = prefix % 8;
+	if network[..bytes] != addr[..bytes] {
+		return false;
+	}
+	if bits == 0 {
+		return true;
+	}
+	let mask = 0xFFu8 << (8 - bits);
+	network[bytes] & mask == addr[bytes] & mask
+}
+
+fn header_values(headers: &Headers, name: &str) -> Option<Vec<String>> {
+	headers.get_raw(name).map(|lines| {
+		lines.iter()
+			.filter_map(|line| str::from_utf8(line).ok())
+			.flat_map(|line| line.split(','))
+			.map(|value| value.trim().to_string())
+			.collect()
+	})
+}
+
+fn forwarded(headers: &Headers) -> Option<Vec<Option<IpAddr>>> {
+	header_values(headers, "Forwarded").map(|elements| {
+		elements.iter().filter_map(|element| {
+			element.split(';')
+				.map(|pair| pair.trim())
+				.find(|pair| pair.len() > 4 && pair.get(..4).map_or(false, |name| name.eq_ignore_ascii_case("for=")))
+				.map(|pair| parse_node(pair[4..].trim_matches('"')))
+		}).collect()
+	})
+}
+
+fn x_forwarded_for(headers: &Headers) -> Vec<Option<IpAddr>> {
+	header_values(headers, "X-Forwarded-For")
+		.unwrap_or_else(Vec::new)
+		.iter()
+		.map(|value| parse_node(value))
+		.collect()
+}
+
+/// Parses an address which may be bracketed and may carry a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+	if node.starts_with('[') {
+		return node[1..].split(']').next().and_then(|addr| IpAddr::from_str(addr).ok());
+	}
+	if let Ok(addr) = IpAddr::from_str(node) {
+		return Some(addr);
+	}
+	// An IPv4 address with a port
+	node.split(':').next().and_then(|addr| IpAddr::from_str(addr).ok())
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use hyper::header::Headers;
+
+	fn proxies() -> TrustedProxies {
+		let mut proxies = TrustedProxies::new();
+		proxies.add("10.0.0.0/8").unwrap();
+		proxies.add("::1").unwrap();
+		proxies
+	}
+
+	#[test]
+	fn test_x_forwarded_for() {
+		let mut headers = Headers::new();
+		headers.set_raw("X-Forwarded-For", vec![b"198.51.100.1, 203.0.113.7, 10.1.2.3".to_vec()]);
+
+		let proxy = "10.0.0.1".parse().unwrap();
+		assert_eq!(proxies().real_peer_addr(proxy, &headers), "203.0.113.7".parse::<IpAddr>().unwrap());
+		// Headers from an untrusted peer are ignored
+		let client = "192.0.2.1".parse().unwrap();
+		assert_eq!(proxies().real_peer_addr(client, &headers), client);
+	}
+
+	#[test]
+	fn test_forwarded() {
+		let mut headers = Headers::new();
+		headers.set_raw("Forwarded", vec![b"for=\"[2001:db8::17]:4711\";proto=https, for=10.0.0.2".to_vec()]);
+		headers.set_raw("X-Forwarded-For", vec![b"192.0.2.1".to_vec()]);
+
+		let proxy = "::1".parse().unwrap();
+		assert_eq!(proxies().real_peer_addr(proxy, &headers), "2001:db8::17".parse::<IpAddr>().unwrap());
+	}
+
+	#[test]
+	fn test_forwarded_multibyte() {
+		let mut headers = Headers::new();
+		headers.set_raw("Forwarded", vec!["for=203.0.113.7, ab€x;f€r=1".as_bytes().to_vec()]);
+
+		let proxy = "10.0.0.1".parse().unwrap();
+		assert_eq!(proxies().real_peer_addr(proxy, &headers), "203.0.113.7".parse::<IpAddr>().unwrap());
+	}
+
+	#[test]
+	fn test_trusted_proxies_add() {
+		let mut proxies = TrustedProxies::new();
+		assert!(proxies.add("10.0.0.0/33").is_err());
+		assert!(proxies.add("example.com").is_err());
+		proxies.add("192.168.0.0/17").unwrap();
+		assert!(proxies.contains("192.168.127.1".parse().unwrap()));
+		assert!(!proxies.contains("192.168.128.1".parse().unwrap()));
+	}
+}
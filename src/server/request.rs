@@ -1,20 +1,28 @@
 //! The server-side WebSocket request.
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
+use std::cmp;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 
 use server::Response;
+use server::proxy::TrustedProxies;
+use server::limit::ConnectionPermit;
 use result::{WebSocketResult, WebSocketError};
+use stream::WebSocketStream;
 use header::{WebSocketKey, WebSocketVersion, WebSocketProtocol, WebSocketExtensions, Origin};
 
 pub use hyper::uri::RequestUri;
 
 use hyper::buffer::BufReader;
 use hyper::version::HttpVersion;
-use hyper::header::Headers;
+use hyper::header::{Headers, Host, ContentLength};
 use hyper::header::{Connection, ConnectionOption};
 use hyper::header::{Upgrade, ProtocolName};
-use hyper::http::h1::parse_request;
+use hyper::http::h1::{parse_request, Incoming};
+use hyper::Error as HttpError;
 use hyper::method::Method;
+use hyper::status::StatusCode;
 
 use unicase::UniCase;
 
@@ -33,8 +41,10 @@ pub struct Request<R: Read, W: Write> {
 	/// The headers of this request.
 	pub headers: Headers,
 	
+	raw_path: String,
 	reader: R,
 	writer: W,
+	permit: Option<ConnectionPermit>,
 }
 
 unsafe impl<R, W> Send for Request<R, W> where R: Read + Send, W: Write + Send { }
@@ -60,6 +70,17 @@ impl<R: Read, W: Write> Request<R, W> {
 	pub fn origin(&self) -> Option<&Origin> {
 		self.headers.get()
 	}
+	/// Short-cut to obtain the Host value.
+	pub fn host(&self) -> Option<&Host> {
+		self.headers.get()
+	}
+	/// Returns the request target exactly as it was sent by the client, without any
+	/// parsing or normalization.
+	///
+	/// For a request created with `from_parts()`, this is the target formatted from `url`.
+	pub fn raw_path(&self) -> &str {
+		&self.raw_path
+	}
 	/// Returns a reference to the inner Reader.
 	pub fn get_reader(&self) -> &R {
 		&self.reader
@@ -76,7 +97,19 @@ impl<R: Read, W: Write> Request<R, W> {
 	pub fn get_mut_writer(&mut self) -> &mut W {
 		&mut self.writer
 	}
+	/// Sets the permit counting this request's connection against the Server's connection
+	/// limit. It is passed on to the Client once the response is sent.
+	pub fn set_permit(&mut self, permit: Option<ConnectionPermit>) {
+		self.permit = permit;
+	}
+	/// Takes the permit counting this request's connection against the Server's
+	/// connection limit. See `Server::set_max_connections()`.
+	pub fn take_permit(&mut self) -> Option<ConnectionPermit> {
+		self.permit.take()
+	}
 	/// Return the inner Reader and Writer
+	///
+	/// Any connection permit is released, unless it was taken first with `take_permit()`.
 	pub fn into_inner(self) -> (R, W) {
 		(self.reader, self.writer)
 	}
@@ -90,17 +123,36 @@ impl<R: Read, W: Write> Request<R, W> {
 	/// the `validate` method.
 	pub fn read(reader: R, writer: W) -> WebSocketResult<Request<R, W>> {
 		let mut reader = BufReader::new(reader);
-		let request = try!(parse_request(&mut reader));
+		let (request, raw_path) = try!(read_head(&mut reader));
 
 		Ok(Request {
 			method: request.subject.0,
 			url: request.subject.1,
 			version: request.version,
 			headers: request.headers,
+			raw_path: raw_path,
 			reader: reader.into_inner(),
 			writer: writer,
+			permit: None,
 		})
 	}
+	/// Creates a request from a request head which has already been parsed, for example
+	/// by another HTTP server.
+	///
+	/// `reader` and `writer` should carry on from the end of the request head.
+	pub fn from_parts(method: Method, url: RequestUri, version: HttpVersion, headers: Headers, reader: R, writer: W) -> Request<R, W> {
+		let raw_path = url.to_string();
+		Request {
+			method: method,
+			url: url,
+			version: version,
+			headers: headers,
+			raw_path: raw_path,
+			reader: reader,
+			writer: writer,
+			permit: None,
+		}
+	}
 	/// Check if this constitutes a valid WebSocket upgrade request.
 	///
     /// Note that `accept()` calls this function internally, however this may be useful for
@@ -161,9 +213,410 @@ impl<R: Read, W: Write> Request<R, W> {
 		Response::new(self)
 	}
 	
+	/// Accept this request like `accept()`, choosing the subprotocol to use with
+	/// `selector`.
+	///
+	/// The selector is given the subprotocols requested by the client in order of
+	/// preference, without duplicates, and returns the one to use, or None to use none.
+	/// The list is empty if the client did not request any. The chosen subprotocol is
+	/// sent in the `Sec-WebSocket-Protocol` header and returned by `Client::protocol()`
+	/// once the response is sent. A subprotocol which the client did not request is not
+	/// sent, as the client would have to fail the connection. The selector is not called
+	/// if the request is invalid.
+	pub fn accept_with_protocol_selector<F>(self, selector: F) -> Response<R, W>
+	where F: FnOnce(&[String]) -> Option<String> {
+		let mut requested: Vec<String> = Vec::new();
+		if let Some(&WebSocketProtocol(ref protocols)) = self.protocol() {
+			for protocol in protocols {
+				if !requested.contains(protocol) {
+					requested.push(protocol.clone());
+				}
+			}
+		}
+		let mut response = self.accept();
+		if response.status == StatusCode::SwitchingProtocols {
+			match selector(&requested) {
+				Some(ref protocol) if requested.contains(protocol) => {
+					response.headers.set(WebSocketProtocol(vec![protocol.clone()]));
+				}
+				_ => (),
+			}
+		}
+		response
+	}
+
+	/// Accept this request like `accept()` if `predicate` returns true, otherwise reject
+	/// it with 403 Forbidden and close the connection.
+	///
+	/// This is meant for checking the `Origin` header against an allowlist, to stop other
+	/// sites' pages from connecting with the user's cookies. `origin()` returns None when
+	/// the header is missing, as it usually is for clients other than browsers, so the
+	/// predicate can decide whether to allow those separately. A rejected request returns
+	/// `WebSocketError::RequestError`.
+	pub fn accept_if<F>(self, predicate: F) -> WebSocketResult<Response<R, W>>
+	where F: FnOnce(&Request<R, W>) -> bool {
+		if predicate(&self) {
+			return Ok(self.accept());
+		}
+		try!(self.reject_with(StatusCode::Forbidden, Headers::new(), None));
+		Err(WebSocketError::RequestError("Request rejected"))
+	}
+
 	/// Fail this request by generating a Bad Request response
 	pub fn fail(self) -> Response<R, W> {
 		Response::bad_request(self)
 	}
+
+	/// Rejects this request by sending an HTTP response with the given status, headers
+	/// and body, then closing the connection.
+	///
+	/// This lets the client see why the upgrade was refused, for example 403 Forbidden
+	/// for an origin which is not allowed, or 426 Upgrade Required along with a
+	/// `Sec-WebSocket-Version` header for an unsupported version. `Content-Length` and
+	/// `Connection: close` are set on the response. The connection is closed by dropping
+	/// the Reader and Writer.
+	pub fn reject_with(mut self, status: StatusCode, mut headers: Headers, body: Option<Vec<u8>>) -> WebSocketResult<()> {
+		let body = body.unwrap_or_else(Vec::new);
+		headers.set(ContentLength(body.len() as u64));
+		headers.set(Connection::close());
+		let mut head = format!("{} {}\r\n{}\r\n", HttpVersion::Http11, status, headers).into_bytes();
+		head.extend(body);
+		let writer = self.get_mut_writer();
+		try!(writer.write_all(&head));
+		try!(writer.flush());
+		Ok(())
+	}
+}
+
+impl Request<WebSocketStream, WebSocketStream> {
+	/// Returns the real address of the client, taking the forwarding headers added by
+	/// trusted proxies into account. See `TrustedProxies::real_peer_addr()`.
+	pub fn real_peer_addr(&self, proxies: &TrustedProxies) -> io::Result<IpAddr> {
+		let peer = try!(self.get_reader().peer_addr()).ip();
+		Ok(proxies.real_peer_addr(peer, &self.headers))
+	}
+	/// Reads an inbound request, failing with an I/O error of kind `TimedOut` unless the
+	/// complete request arrives within `timeout`.
+	///
+	/// If `line_timeout` is given, the request line must also arrive within that time.
+	/// Unlike a plain read timeout, these limits cannot be extended by a client which
+	/// trickles in the request a few bytes at a time.
+	pub fn read_timeout(reader: WebSocketStream, writer: WebSocketStream, timeout: Duration,
+	                    line_timeout: Option<Duration>) -> WebSocketResult<Request<WebSocketStream, WebSocketStream>> {
+		let reader = try!(DeadlineReader::new(reader, timeout, line_timeout));
+		let mut reader = BufReader::new(reader);
+		let head = read_head(&mut reader);
+		let reader = try!(reader.into_inner().into_inner());
+		let (request, raw_path) = try!(head);
+
+		Ok(Request {
+			method: request.subject.0,
+			url: request.subject.1,
+			version: request.version,
+			headers: request.headers,
+			raw_path: raw_path,
+			reader: reader,
+			writer: writer,
+			permit: None,
+		})
+	}
+}
+
+/// Parses a request head, also returning the request target exactly as it was received.
+fn read_head<R: Read>(reader: &mut BufReader<R>) -> WebSocketResult<(Incoming<(Method, RequestUri)>, String)> {
+	// Buffer the request line first, which the parser will then carry on from
+	while !reader.get_buf().contains(&b'\n') {
+		// Errors are reported as the parser would report them
+		if try!(reader.read_into_buf().map_err(HttpError::Io)) == 0 {
+			break;
+		}
+	}
+	let raw_path = {
+		let buf = reader.get_buf();
+		let line = buf.split(|&byte| byte == b'\n').next().unwrap_or(&[]);
+		line.split(|&byte| byte == b' ').nth(1)
+			.map(|target| String::from_utf8_lossy(target).into_owned())
+	};
+	let request = try!(parse_request(reader));
+	let raw_path = raw_path.unwrap_or_else(|| request.subject.1.to_string());
+	Ok((request, raw_path))
+}
+
+/// A Reader which fails once its deadline has passed, even if data keeps arriving.
+struct DeadlineReader {
+	inner: WebSocketStream,
+	deadline: Instant,
+	line_deadline: Option<Instant>,
+	previous_timeout: Option<Duration>,
+}
+
+impl DeadlineReader {
+	fn new(inner: WebSocketStream, timeout: Duration, line_timeout: Option<Duration>) -> io::Result<DeadlineReader> {
+		let now = Instant::now();
+		let previous_timeout = try!(inner.tcp_ref().read_timeout());
+		Ok(DeadlineReader {
+			inner: inner,
+			deadline: now + timeout,
+			line_deadline: line_timeout.map(|timeout| now + timeout),
+			previous_timeout: previous_timeout,
+		})
+	}
+
+	/// Restores the original read timeout and returns the inner stream.
+	fn into_inner(self) -> io::Result<WebSocketStream> {
+		try!(self.inner.tcp_ref().set_read_timeout(self.previous_timeout));
+		Ok(self.inner)
+	}
 }
 
+impl Read for DeadlineReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let deadline = match self.line_deadline {
+			Some(line_deadline) => cmp::min(self.deadline, line_deadline),
+			None => self.deadline,
+		};
+		let now = Instant::now();
+		if now >= deadline {
+			return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out reading the request"));
+		}
+		try!(self.inner.tcp_ref().set_read_timeout(Some(deadline - now)));
+
+		match self.inner.read(buf) {
+			Ok(len) => {
+				if buf[..len].contains(&b'\n') {
+					self.line_deadline = None;
+				}
+				Ok(len)
+			}
+			Err(ref err) if (err.kind() == io::ErrorKind::WouldBlock ||
+			                 err.kind() == io::ErrorKind::TimedOut) && Instant::now() >= deadline => {
+				Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out reading the request"))
+			}
+			Err(err) => Err(err),
+		}
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use std::io::{ErrorKind, Write};
+	use std::net::{TcpListener, TcpStream};
+	use std::thread;
+	use std::time::Duration;
+	use hyper::Error as HttpError;
+	use result::WebSocketError;
+	use stream::WebSocketStream;
+	use super::*;
+
+	#[test]
+	fn test_raw_path() {
+		let data = b"GET http://example.com/a/../b%7e?x HTTP/1.1\r\nHost: example.com\r\n\r\n";
+		let request = Request::read(&data[..], Vec::new()).unwrap();
+		assert_eq!(request.raw_path(), "http://example.com/a/../b%7e?x");
+		assert!(request.url.to_string() != request.raw_path());
+	}
+
+	#[test]
+	fn test_validate_header_tokens() {
+		let valid = |connection: &str, upgrade: &str| {
+			let data = format!(
+				"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: {}\r\nUpgrade: {}\r\n\
+				Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+				connection, upgrade
+			);
+			Request::read(data.as_bytes(), Vec::new()).unwrap().validate().is_ok()
+		};
+		assert!(valid("Upgrade", "websocket"));
+		assert!(valid("upgrade", "WebSocket"));
+		assert!(valid("keep-alive, Upgrade", "websocket"));
+		assert!(valid("keep-alive,UPGRADE", "h2c, WEBSOCKET"));
+		assert!(!valid("keep-alive", "websocket"));
+		assert!(!valid("Upgraded", "websocket"));
+		assert!(!valid("Upgrade", "websockets"));
+	}
+
+	#[test]
+	fn test_upgrade_from_parts() {
+		use hyper::header::{Headers, Connection, ConnectionOption, Upgrade, Protocol, ProtocolName};
+		use hyper::method::Method;
+		use hyper::version::HttpVersion;
+		use unicase::UniCase;
+		use header::{WebSocketKey, WebSocketVersion};
+		use server::Server;
+
+		let mut headers = Headers::new();
+		headers.set(Connection(vec![ConnectionOption::ConnectionHeader(UniCase("Upgrade".to_string()))]));
+		headers.set(Upgrade(vec![Protocol { name: ProtocolName::WebSocket, version: None }]));
+		headers.set(WebSocketVersion::WebSocket13);
+		headers.set(WebSocketKey::new());
+		let url = RequestUri::AbsolutePath("/".to_string());
+
+		let client = Server::upgrade_from_parts(
+			&b""[..], Vec::new(), Method::Get, url.clone(), HttpVersion::Http11, headers.clone()
+		).unwrap();
+		let (sender, _) = client.split();
+		assert!(sender.get_ref().starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+		headers.remove::<Upgrade>();
+		assert!(Server::upgrade_from_parts(
+			&b""[..], Vec::new(), Method::Get, url, HttpVersion::Http11, headers
+		).is_err());
+	}
+
+	#[test]
+	fn test_read_timeout_trickled_request() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let client = thread::spawn(move || {
+			let mut stream = TcpStream::connect(addr).unwrap();
+			for byte in b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n".iter() {
+				if stream.write_all(&[*byte]).is_err() {
+					return;
+				}
+				thread::sleep(Duration::from_millis(20));
+			}
+		});
+
+		let stream = WebSocketStream::Tcp(listener.accept().unwrap().0);
+		let result = Request::read_timeout(
+			stream.try_clone().unwrap(), stream,
+			Duration::from_millis(2000), Some(Duration::from_millis(100))
+		);
+		match result {
+			Err(WebSocketError::HttpError(HttpError::Io(ref err))) if err.kind() == ErrorKind::TimedOut => (),
+			Err(err) => panic!("Expected a timeout but got {:?}", err),
+			Ok(_) => panic!("Expected a timeout but got Ok"),
+		}
+		client.join().unwrap();
+	}
+
+	#[test]
+	fn test_read_timeout_prompt_request() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let client = thread::spawn(move || {
+			let mut stream = TcpStream::connect(addr).unwrap();
+			stream.write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+		});
+
+		let stream = WebSocketStream::Tcp(listener.accept().unwrap().0);
+		let request = Request::read_timeout(
+			stream.try_clone().unwrap(), stream,
+			Duration::from_millis(2000), Some(Duration::from_millis(1000))
+		).unwrap();
+
+		assert_eq!(request.url, RequestUri::AbsolutePath("/chat".to_string()));
+		assert_eq!(request.get_reader().tcp_ref().read_timeout().unwrap(), None);
+		client.join().unwrap();
+	}
+
+	#[test]
+	fn test_negotiate_deflate() {
+		use deflate::DeflateParams;
+
+		let data = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+			Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+			Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n\r\n";
+		let request = Request::read(&data[..], Vec::new()).unwrap();
+		let mut response = request.accept();
+		assert!(response.negotiate_deflate(&DeflateParams::default()).is_some());
+		let client = response.send().unwrap();
+		assert!(client.compression().is_some());
+		let (sender, _) = client.split();
+		let written = String::from_utf8(sender.get_ref().clone()).unwrap();
+		assert!(written.contains("Sec-WebSocket-Extensions: permessage-deflate"));
+
+		let data = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+			Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+		let request = Request::read(&data[..], Vec::new()).unwrap();
+		let mut response = request.accept();
+		assert!(response.negotiate_deflate(&DeflateParams::default()).is_none());
+		assert!(response.send().unwrap().compression().is_none());
+	}
+
+	#[test]
+	fn test_reject_with() {
+		use hyper::header::Headers;
+		use hyper::status::StatusCode;
+
+		let data = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+			Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+		let mut written = Vec::new();
+		{
+			let request = Request::read(&data[..], &mut written).unwrap();
+			let body = b"Origin not allowed".to_vec();
+			request.reject_with(StatusCode::Forbidden, Headers::new(), Some(body)).unwrap();
+		}
+		let written = String::from_utf8(written).unwrap();
+		assert!(written.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+		assert!(written.contains("Content-Length: 18\r\n"));
+		assert!(written.contains("Connection: close\r\n"));
+		assert!(written.ends_with("\r\n\r\nOrigin not allowed"));
+	}
+
+	#[test]
+	fn test_accept_if() {
+		let request = |origin: &str| {
+			let data = format!(
+				"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+				Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+				origin
+			);
+			Request::read(io::Cursor::new(data.into_bytes()), Vec::new()).unwrap()
+		};
+		fn allowed<R: Read, W: Write>(request: &Request<R, W>) -> bool {
+			match request.origin() {
+				Some(origin) => origin.0 == "https://example.com",
+				None => true,
+			}
+		}
+
+		let response = request("Origin: https://example.com\r\n").accept_if(allowed).unwrap();
+		assert_eq!(response.status, StatusCode::SwitchingProtocols);
+		let response = request("").accept_if(allowed).unwrap();
+		assert_eq!(response.status, StatusCode::SwitchingProtocols);
+
+		let mut written = Vec::new();
+		let data = b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+			Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+			Origin: https://evil.example\r\n\r\n";
+		match Request::read(&data[..], &mut written).unwrap().accept_if(allowed) {
+			Err(WebSocketError::RequestError(_)) => (),
+			_ => panic!("expected the request to be rejected"),
+		}
+		assert!(written.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+	}
+
+	#[test]
+	fn test_accept_with_protocol_selector() {
+		let request = |protocols: &str| {
+			let data = format!(
+				"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+				Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+				protocols
+			);
+			Request::read(io::Cursor::new(data.into_bytes()), Vec::new()).unwrap()
+		};
+
+		let client = request("Sec-WebSocket-Protocol: chat.v2, chat.v1, chat.v2\r\n")
+			.accept_with_protocol_selector(|protocols| {
+				assert_eq!(protocols, &["chat.v2".to_string(), "chat.v1".to_string()][..]);
+				Some("chat.v1".to_string())
+			})
+			.send().unwrap();
+		assert_eq!(client.protocol(), Some("chat.v1"));
+		let (sender, _) = client.split();
+		let written = String::from_utf8(sender.get_ref().clone()).unwrap();
+		assert!(written.contains("Sec-WebSocket-Protocol: chat.v1\r\n"));
+
+		// Unrequested choices are not sent, and no header means an empty list
+		let response = request("Sec-WebSocket-Protocol: chat.v1\r\n")
+			.accept_with_protocol_selector(|_| Some("other".to_string()));
+		assert!(response.protocol().is_none());
+		let response = request("").accept_with_protocol_selector(|protocols| {
+			assert!(protocols.is_empty());
+			None
+		});
+		assert!(response.send().unwrap().protocol().is_none());
+	}
+}
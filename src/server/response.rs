@@ -17,6 +17,7 @@ use server::Request;
 use client::Client;
 use result::WebSocketResult;
 use dataframe::DataFrame;
+use deflate::DeflateParams;
 use ws::dataframe::DataFrame as DataFrameable;
 use ws;
 
@@ -29,7 +30,8 @@ pub struct Response<R: Read, W: Write> {
 	/// The HTTP version of this response
 	pub version: HttpVersion,
 
-	request: Request<R, W>
+	request: Request<R, W>,
+	compression: Option<DeflateParams>,
 }
 
 unsafe impl<R, W> Send for Response<R, W> where R: Read + Send, W: Write + Send { }
@@ -83,7 +85,8 @@ impl<R: Read, W: Write> Response<R, W> {
 			status: StatusCode::SwitchingProtocols,
 			headers: headers,
 			version: HttpVersion::Http11,
-			request: request
+			request: request,
+			compression: None,
 		}
 	}
 	/// Create a Bad Request response
@@ -92,7 +95,8 @@ impl<R: Read, W: Write> Response<R, W> {
 			status: StatusCode::BadRequest,
 			headers: Headers::new(),
 			version: HttpVersion::Http11,
-			request: request
+			request: request,
+			compression: None,
 		}
 	}
 	/// Short-cut to obtain a mutable reference to the WebSocketAccept value
@@ -113,6 +117,28 @@ impl<R: Read, W: Write> Response<R, W> {
 	pub fn extensions_mut(&mut self) -> Option<&mut WebSocketExtensions> {
 		self.headers.get_mut()
 	}
+	/// Accepts the permessage-deflate extension if the client offered it, choosing the
+	/// parameters with `DeflateParams::negotiate()` for a server configured with `config`.
+	///
+	/// The accepted extension is added to the response, and `send()` sets up compression
+	/// on the Client. Returns the accepted parameters, or None if the extension was not
+	/// offered.
+	pub fn negotiate_deflate(&mut self, config: &DeflateParams) -> Option<DeflateParams> {
+		let params = match self.request.extensions() {
+			Some(offers) => config.negotiate(offers),
+			None => None,
+		};
+		if let Some(params) = params {
+			if self.extensions().is_none() {
+				self.headers.set(WebSocketExtensions(Vec::new()));
+			}
+			if let Some(extensions) = self.headers.get_mut::<WebSocketExtensions>() {
+				extensions.0.push(params.to_extension());
+			}
+		}
+		self.compression = params;
+		params
+	}
 
 	/// Send this response with the given data frame type D, Sender B and Receiver C.
 	pub fn send_with<D, B, C>(mut self, sender: B, receiver: C) -> WebSocketResult<Client<D, B, C>>
@@ -142,9 +168,22 @@ impl<R: Read, W: Write> Response<R, W> {
 		let headers = self.headers.clone();
 		try!(write!(self.get_mut_writer(), "{} {}\r\n", version, status));
 		try!(write!(self.get_mut_writer(), "{}\r\n", headers));
+		let compression = self.compression;
+		let protocol = self.protocol().and_then(|protocols| protocols.first().cloned());
+		let permit = self.request.take_permit();
 		let (reader, writer) = self.into_inner();
-		let sender = Sender::new(writer, false);
-		let receiver = Receiver::new(BufReader::new(reader), true);
-		Ok(Client::new(sender, receiver))
+		let mut sender = Sender::new(writer, false);
+		let mut receiver = Receiver::new(BufReader::new(reader), true);
+		if let Some(params) = compression {
+			let (deflater, inflater) = params.middleware(false);
+			sender.add_middleware(deflater);
+			receiver.add_middleware(inflater);
+		}
+		let mut client = Client::new(sender, receiver);
+		client.set_compression(compression);
+		client.set_protocol(protocol);
+		client.set_response_headers(headers);
+		client.set_permit(permit);
+		Ok(client)
 	}
 }
@@ -0,0 +1,59 @@
+//! Stops a Server from accepting connections.
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use result::WebSocketError;
+
+/// Shuts down the Server it was obtained from. See `Server::shutdown_handle()`.
+///
+/// Clones shut down the same Server.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+	shutdown: Arc<AtomicBool>,
+	addr: SocketAddr,
+}
+
+impl ShutdownHandle {
+	/// Creates a ShutdownHandle which sets `shutdown`, then wakes up a call to `accept()`
+	/// blocked on the listener at `addr`.
+	pub fn new(shutdown: Arc<AtomicBool>, addr: SocketAddr) -> ShutdownHandle {
+		// A listener bound to every interface is reachable through the loopback one
+		let ip = match addr.ip() {
+			IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+			IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+			ip => ip,
+		};
+		ShutdownHandle {
+			shutdown: shutdown,
+			addr: SocketAddr::new(ip, addr.port()),
+		}
+	}
+	/// Stops the Server accepting connections.
+	///
+	/// A blocked call to `accept()` returns an error promptly, and every later call fails
+	/// straight away. Connections which have already been accepted are not affected.
+	/// Shutting down a Server more than once has no further effect.
+	pub fn shutdown(&self) {
+		if !self.shutdown.swap(true, Ordering::SeqCst) {
+			// Connecting to the listener is the portable way to wake a blocked accept()
+			let _ = TcpStream::connect_timeout(&self.addr, Duration::from_secs(1));
+		}
+	}
+	/// Returns true if the Server has been shut down.
+	pub fn is_shutdown(&self) -> bool {
+		self.shutdown.load(Ordering::SeqCst)
+	}
+}
+
+/// Returns true if `error` was returned by `Server::accept()` because the Server was
+/// shut down.
+///
+/// Such errors have the kind `NotConnected` and wrap `WebSocketError::ServerShutdown`.
+pub fn is_shutdown_error(error: &io::Error) -> bool {
+	error.get_ref()
+		.and_then(|inner| inner.downcast_ref::<WebSocketError>())
+		.map_or(false, |inner| *inner == WebSocketError::ServerShutdown)
+}
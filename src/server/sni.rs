@@ -0,0 +1,98 @@
+//! Chooses the certificate for each secure connection from the host name the client
+//! asked for with Server Name Indication (SNI).
+use std::collections::HashMap;
+
+use openssl::ssl::{Ssl, SslContext};
+
+/// The value a server name callback returns to carry on with the handshake.
+const SSL_TLSEXT_ERR_OK: i32 = 0;
+
+/// Certificates for several host names, each held in its own SslContext.
+///
+/// Host names are matched exactly, ignoring case.
+#[derive(Clone, Default)]
+pub struct SniContexts {
+	hosts: HashMap<String, SslContext>,
+}
+
+impl SniContexts {
+	/// Creates an empty SniContexts.
+	pub fn new() -> SniContexts {
+		SniContexts::default()
+	}
+	/// Uses `context` for connections to `hostname`.
+	pub fn add(&mut self, hostname: &str, context: SslContext) {
+		self.hosts.insert(hostname.to_lowercase(), context);
+	}
+	/// Returns the context used for connections to `hostname`, if any.
+	pub fn get(&self, hostname: &str) -> Option<&SslContext> {
+		self.hosts.get(&hostname.to_lowercase())
+	}
+	/// Makes `default` switch each connection over to the context added for the host
+	/// name the client asked for. See `set_sni_selector()`.
+	pub fn apply(self, default: &mut SslContext) {
+		set_sni_selector(default, move |hostname| self.get(hostname).cloned());
+	}
+}
+
+/// Makes `context` switch each connection over to the context `select` returns for the
+/// host name the client asked for with SNI.
+///
+/// `context` is used itself when the client sent no host name or `select` returns
+/// None, so it should hold the default certificate. Pass it to `Server::bind_secure()`
+/// as usual; connections still end up as `WebSocketStream::Ssl`. Replaces any server
+/// name callback set on `context` before.
+pub fn set_sni_selector<F>(context: &mut SslContext, select: F)
+where F: Fn(&str) -> Option<SslContext> + Send + Sync + 'static {
+	let select: Box<Fn(&str) -> Option<SslContext> + Send + Sync> = Box::new(select);
+	context.set_servername_callback_with_data(select_context, select);
+}
+
+fn select_context(ssl: &mut Ssl, _alert: &mut i32, select: &Box<Fn(&str) -> Option<SslContext> + Send + Sync>) -> i32 {
+	if let Some(hostname) = ssl.get_servername() {
+		if let Some(context) = select(&hostname) {
+			ssl.set_ssl_context(&context);
+		}
+	}
+	SSL_TLSEXT_ERR_OK
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use openssl::ssl::{SslMethod, SSL_OP_NO_TICKET};
+
+	#[test]
+	fn test_sni_contexts() {
+		let mut contexts = SniContexts::new();
+		contexts.add("One.Example", SslContext::new(SslMethod::Sslv23).unwrap());
+		contexts.add("two.example", SslContext::new(SslMethod::Sslv23).unwrap());
+		assert!(contexts.get("one.example").is_some());
+		assert!(contexts.get("TWO.EXAMPLE").is_some());
+		assert!(contexts.get("three.example").is_none());
+
+		let mut default = SslContext::new(SslMethod::Sslv23).unwrap();
+		contexts.apply(&mut default);
+	}
+
+	#[test]
+	fn test_select_context() {
+		let mut chosen = SslContext::new(SslMethod::Sslv23).unwrap();
+		chosen.set_options(SSL_OP_NO_TICKET);
+		let select: Box<Fn(&str) -> Option<SslContext> + Send + Sync> =
+			Box::new(move |hostname| if hostname == "one.example" { Some(chosen.clone()) } else { None });
+
+		let default = SslContext::new(SslMethod::Sslv23).unwrap();
+		let mut ssl = Ssl::new(&default).unwrap();
+		assert_eq!(select_context(&mut ssl, &mut 0, &select), SSL_TLSEXT_ERR_OK);
+		assert!(!ssl.get_ssl_context().get_options().contains(SSL_OP_NO_TICKET));
+
+		ssl.set_hostname("two.example").unwrap();
+		assert_eq!(select_context(&mut ssl, &mut 0, &select), SSL_TLSEXT_ERR_OK);
+		assert!(!ssl.get_ssl_context().get_options().contains(SSL_OP_NO_TICKET));
+
+		ssl.set_hostname("one.example").unwrap();
+		assert_eq!(select_context(&mut ssl, &mut 0, &select), SSL_TLSEXT_ERR_OK);
+		assert!(ssl.get_ssl_context().get_options().contains(SSL_OP_NO_TICKET));
+	}
+}
@@ -0,0 +1,416 @@
+//! Provides a Sender which can be cloned and used from several threads at once.
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+
+use dataframe::DataFrame;
+use middleware::to_owned_dataframe;
+use result::{WebSocketResult, WebSocketError};
+use ws::dataframe::DataFrame as DataFrameable;
+use ws;
+
+/// A cloneable handle to the sending half of a connection.
+///
+/// Every handle passes the data frames it sends through a channel to a single writer
+/// thread, which owns the underlying Sender. Sends are therefore serialized: the data
+/// frames of one message are never interleaved with those of another, and messages
+/// sent from one handle are written in the order they were sent.
+///
+/// Since sending only queues the data frames, an error while writing is reported by
+/// the next send on any handle, after which the writer thread has stopped and every
+/// further send fails. The writer thread stops once every handle has been dropped.
+/// `send_and_flush()` and `flush()` instead wait for the writer thread, and report
+/// errors directly.
+///
+/// By default any number of messages may be queued, so if the connection cannot keep
+/// up with the messages sent, the queue grows without limit. With a high-water mark
+/// (see `with_high_water_mark()`), a send waits while the payloads queued add up to at
+/// least that many bytes, until the writer thread has written enough of them.
+#[derive(Clone)]
+pub struct SharedSender {
+	channel: ChannelSender<Job>,
+	error: Arc<Mutex<Option<WebSocketError>>>,
+	backlog: Arc<Backlog>,
+}
+
+/// The number of payload bytes queued but not yet written.
+struct Backlog {
+	bytes: Mutex<usize>,
+	drained: Condvar,
+	high_water_mark: Option<usize>,
+	stopped: AtomicBool,
+}
+
+impl Backlog {
+	fn release(&self, bytes: usize) {
+		let mut queued = self.bytes.lock().unwrap();
+		*queued = queued.saturating_sub(bytes);
+		self.drained.notify_all();
+	}
+
+	/// Wakes every waiting send to fail, as nothing more will be written.
+	fn stop(&self) {
+		let _queued = self.bytes.lock().unwrap();
+		self.stopped.store(true, Ordering::SeqCst);
+		self.drained.notify_all();
+	}
+}
+
+/// Data frames to be written together, optionally followed by a flush whose result
+/// is sent back.
+struct Job {
+	dataframes: Vec<DataFrame>,
+	flushed: Option<ChannelSender<WebSocketResult<()>>>,
+}
+
+impl SharedSender {
+	/// Spawns a writer thread which sends everything it is given using `sender`, and
+	/// returns a handle to it.
+	pub fn new<S>(sender: S) -> SharedSender
+	where S: ws::Sender + Send + 'static {
+		SharedSender::spawn(sender, None)
+	}
+
+	/// Like `new()`, but sends wait while at least `bytes` bytes of payload are queued.
+	///
+	/// A message is queued whole once there is room, even if it takes the queue past
+	/// the high-water mark, and is then always written in full: messages are never
+	/// split or dropped because of the limit. Waiting sends carry on once the writer
+	/// thread has written enough, or fail if it has stopped. Use `try_send_message()`
+	/// to avoid waiting.
+	pub fn with_high_water_mark<S>(sender: S, bytes: usize) -> SharedSender
+	where S: ws::Sender + Send + 'static {
+		SharedSender::spawn(sender, Some(bytes))
+	}
+
+	fn spawn<S>(mut sender: S, high_water_mark: Option<usize>) -> SharedSender
+	where S: ws::Sender + Send + 'static {
+		let (tx, rx) = channel::<Job>();
+		let error = Arc::new(Mutex::new(None));
+		let writer_error = error.clone();
+		let backlog = Arc::new(Backlog {
+			bytes: Mutex::new(0),
+			drained: Condvar::new(),
+			high_water_mark: high_water_mark,
+			stopped: AtomicBool::new(false),
+		});
+		let writer_backlog = backlog.clone();
+		thread::spawn(move || {
+			for job in rx {
+				let bytes = payload_len(&job.dataframes);
+				let mut result = Ok(());
+				for dataframe in job.dataframes.iter() {
+					result = sender.send_dataframe(dataframe);
+					if result.is_err() {
+						break;
+					}
+				}
+				if result.is_ok() && job.flushed.is_some() {
+					result = sender.flush();
+				}
+				writer_backlog.release(bytes);
+				if let Err(e) = result {
+					match job.flushed {
+						Some(flushed) => { let _ = flushed.send(Err(e)); }
+						None => *writer_error.lock().unwrap() = Some(e),
+					}
+					writer_backlog.stop();
+					return;
+				}
+				if let Some(flushed) = job.flushed {
+					let _ = flushed.send(Ok(()));
+				}
+			}
+		});
+		SharedSender {
+			channel: tx,
+			error: error,
+			backlog: backlog,
+		}
+	}
+
+	/// Returns the number of payload bytes queued but not yet written.
+	pub fn queued_bytes(&self) -> usize {
+		*self.backlog.bytes.lock().unwrap()
+	}
+
+	/// Returns the high-water mark, if any. See `with_high_water_mark()`.
+	pub fn high_water_mark(&self) -> Option<usize> {
+		self.backlog.high_water_mark
+	}
+
+	/// Returns true if a send would be queued without waiting.
+	pub fn is_ready(&self) -> bool {
+		match self.backlog.high_water_mark {
+			Some(mark) => self.queued_bytes() < mark,
+			None => true,
+		}
+	}
+
+	/// Queues all the data frames of a single message to be sent together, or returns an
+	/// I/O error of kind `WouldBlock` without queuing anything if the high-water mark
+	/// has been reached.
+	pub fn try_send_message<'m, M, D>(&mut self, message: &'m M) -> WebSocketResult<()>
+	where M: ws::Message<'m, D>, D: DataFrameable {
+		let mut dataframes = Vec::new();
+		for ref dataframe in message.dataframes() {
+			dataframes.push(try!(to_owned_dataframe(dataframe)));
+		}
+		self.queue(dataframes, false)
+	}
+
+	/// Sends all the data frames of a single message and flushes the underlying Sender,
+	/// without any other handle's data frames being written in between, then waits
+	/// until both have been done.
+	pub fn send_and_flush<'m, M, D>(&mut self, message: &'m M) -> WebSocketResult<()>
+	where M: ws::Message<'m, D>, D: DataFrameable {
+		let mut dataframes = Vec::new();
+		for ref dataframe in message.dataframes() {
+			dataframes.push(try!(to_owned_dataframe(dataframe)));
+		}
+		self.queue_flushed(dataframes)
+	}
+
+	fn queue(&self, dataframes: Vec<DataFrame>, wait: bool) -> WebSocketResult<()> {
+		if let Some(e) = self.error.lock().unwrap().take() {
+			return Err(e);
+		}
+		try!(self.reserve(payload_len(&dataframes), wait));
+		self.channel.send(Job {
+			dataframes: dataframes,
+			flushed: None,
+		}).map_err(|_| stopped())
+	}
+
+	fn queue_flushed(&self, dataframes: Vec<DataFrame>) -> WebSocketResult<()> {
+		if let Some(e) = self.error.lock().unwrap().take() {
+			return Err(e);
+		}
+		try!(self.reserve(payload_len(&dataframes), true));
+		let (tx, rx) = channel();
+		try!(self.channel.send(Job {
+			dataframes: dataframes,
+			flushed: Some(tx),
+		}).map_err(|_| stopped()));
+		rx.recv().unwrap_or_else(|_| Err(stopped()))
+	}
+
+	/// Counts `bytes` as queued once the backlog is below the high-water mark, waiting
+	/// for it to drain if `wait` is set. Fails once the writer thread has stopped.
+	fn reserve(&self, bytes: usize, wait: bool) -> WebSocketResult<()> {
+		let mut queued = self.backlog.bytes.lock().unwrap();
+		loop {
+			if self.backlog.stopped.load(Ordering::SeqCst) {
+				return Err(self.error.lock().unwrap().take().unwrap_or_else(stopped));
+			}
+			if self.backlog.high_water_mark.map_or(true, |mark| *queued < mark) {
+				break;
+			}
+			if !wait {
+				return Err(WebSocketError::IoError(IoError::new(
+					ErrorKind::WouldBlock, "Too much data is queued to be sent"
+				)));
+			}
+			queued = self.backlog.drained.wait(queued).unwrap();
+		}
+		*queued += bytes;
+		Ok(())
+	}
+}
+
+fn payload_len(dataframes: &[DataFrame]) -> usize {
+	dataframes.iter().map(|dataframe| dataframe.data.len()).sum()
+}
+
+fn stopped() -> WebSocketError {
+	WebSocketError::IoError(IoError::new(ErrorKind::BrokenPipe, "The writer thread has stopped"))
+}
+
+impl ws::Sender for SharedSender {
+	/// Queues a single data frame to be sent.
+	fn send_dataframe<D>(&mut self, dataframe: &D) -> WebSocketResult<()>
+	where D: DataFrameable {
+		self.queue(vec![try!(to_owned_dataframe(dataframe))], true)
+	}
+
+	/// Queues all the data frames of a single message to be sent together.
+	fn send_message<'m, M, D>(&mut self, message: &'m M) -> WebSocketResult<()>
+	where M: ws::Message<'m, D>, D: DataFrameable {
+		let mut dataframes = Vec::new();
+		for ref dataframe in message.dataframes() {
+			dataframes.push(try!(to_owned_dataframe(dataframe)));
+		}
+		self.queue(dataframes, true)
+	}
+
+	/// Flushes the underlying Sender once everything queued so far has been written, and
+	/// waits until that has been done.
+	fn flush(&mut self) -> WebSocketResult<()> {
+		self.queue_flushed(Vec::new())
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::io::{self, Write};
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
+	use message::Message;
+	use result::WebSocketError;
+	use sender::Sender;
+	use ws::Sender as SenderTrait;
+
+	#[derive(Clone)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	/// Holds written data until it is flushed.
+	#[derive(Clone)]
+	struct FlushedBuffer {
+		pending: Vec<u8>,
+		flushed: Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl Write for FlushedBuffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.pending.extend_from_slice(buf);
+			Ok(buf.len())
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			self.flushed.lock().unwrap().extend(self.pending.drain(..));
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_shared_sender_send_and_flush() {
+		let flushed = Arc::new(Mutex::new(Vec::new()));
+		let buffer = FlushedBuffer { pending: Vec::new(), flushed: flushed.clone() };
+		let mut shared = SharedSender::new(Sender::new(buffer, false));
+
+		shared.send_message(&Message::binary(vec![1])).unwrap();
+		shared.send_and_flush(&Message::binary(vec![2])).unwrap();
+		assert_eq!(*flushed.lock().unwrap(), vec![0x82, 0x01, 1, 0x82, 0x01, 2]);
+
+		shared.send_message(&Message::binary(vec![3])).unwrap();
+		shared.flush().unwrap();
+		assert_eq!(flushed.lock().unwrap().len(), 9);
+	}
+
+	/// Blocks every write while the gate is locked.
+	struct GatedBuffer(Arc<Mutex<()>>, SharedBuffer);
+
+	impl Write for GatedBuffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let _open = self.0.lock().unwrap();
+			self.1.write(buf)
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_shared_sender_high_water_mark() {
+		let gate = Arc::new(Mutex::new(()));
+		let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+		let closed = gate.lock().unwrap();
+		let writer = GatedBuffer(gate.clone(), buffer.clone());
+		let mut shared = SharedSender::with_high_water_mark(Sender::new(writer, false), 4);
+		assert_eq!(shared.high_water_mark(), Some(4));
+
+		// The second message is accepted whole although it passes the mark
+		shared.send_message(&Message::binary(vec![1; 3])).unwrap();
+		assert!(shared.is_ready());
+		shared.send_message(&Message::binary(vec![2; 3])).unwrap();
+		assert!(!shared.is_ready());
+		assert_eq!(shared.queued_bytes(), 6);
+		match shared.try_send_message(&Message::binary(vec![3])) {
+			Err(WebSocketError::IoError(ref e)) if e.kind() == io::ErrorKind::WouldBlock => (),
+			other => panic!("expected WouldBlock, got {:?}", other),
+		}
+
+		// A waiting send carries on once the writer has caught up
+		let mut waiting = shared.clone();
+		let thread = thread::spawn(move || {
+			waiting.send_message(&Message::binary(vec![3])).unwrap();
+		});
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(shared.queued_bytes(), 6);
+		drop(closed);
+		thread.join().unwrap();
+		shared.flush().unwrap();
+		assert_eq!(shared.queued_bytes(), 0);
+		assert_eq!(*buffer.0.lock().unwrap(), vec![0x82, 0x03, 1, 1, 1, 0x82, 0x03, 2, 2, 2, 0x82, 0x01, 3]);
+	}
+
+	/// Fails every write once the gate is opened.
+	struct FailingBuffer(Arc<Mutex<()>>);
+
+	impl Write for FailingBuffer {
+		fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+			let _open = self.0.lock().unwrap();
+			Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_shared_sender_waiting_send_fails() {
+		let gate = Arc::new(Mutex::new(()));
+		let closed = gate.lock().unwrap();
+		let mut shared = SharedSender::with_high_water_mark(Sender::new(FailingBuffer(gate.clone()), false), 1);
+		shared.send_message(&Message::binary(vec![1; 3])).unwrap();
+
+		let mut waiting = shared.clone();
+		let thread = thread::spawn(move || {
+			waiting.send_message(&Message::binary(vec![2])).is_err()
+		});
+		thread::sleep(Duration::from_millis(50));
+		drop(closed);
+		assert!(thread.join().unwrap());
+		assert!(shared.send_message(&Message::binary(vec![3])).is_err());
+	}
+
+	#[test]
+	fn test_shared_sender_keeps_messages_whole() {
+		let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+		let shared = SharedSender::new(Sender::new(buffer.clone(), false));
+
+		let threads: Vec<_> = (0..4).map(|_| {
+			let mut shared = shared.clone();
+			thread::spawn(move || {
+				for _ in 0..25 {
+					shared.send_message(&Message::binary(vec![7; 3])).unwrap();
+				}
+			})
+		}).collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		let expected: Vec<u8> = (0..100).flat_map(|_| vec![0x82, 0x03, 7, 7, 7]).collect();
+		for _ in 0..100 {
+			if buffer.0.lock().unwrap().len() >= expected.len() {
+				break;
+			}
+			thread::sleep(Duration::from_millis(10));
+		}
+		assert_eq!(*buffer.0.lock().unwrap(), expected);
+	}
+}
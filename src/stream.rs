@@ -1,18 +1,133 @@
 //! Provides the default stream type for WebSocket connections.
 extern crate net2;
+extern crate socket2;
+#[cfg(unix)]
+extern crate libc;
 
-use std::io::{self, Read, Write};
+use std::cmp;
+use std::io::{self, IoSlice, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use self::net2::TcpStreamExt;
-use openssl::ssl::SslStream;
+use self::socket2::SockRef;
+use openssl::ssl::{Ssl, SslStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
 
 pub use std::net::{SocketAddr, Shutdown, TcpStream};
 
 /// A useful stream type for carrying WebSocket connections.
+///
+/// TLS connections use OpenSSL, which is the only TLS backend supported. The `Ssl`
+/// variant exposes the same `Read`, `Write`, address and shutdown methods as `Tcp`,
+/// so code using a WebSocketStream need not know which kind it has.
 pub enum WebSocketStream {
 	/// A TCP stream.
 	Tcp(TcpStream),
 	/// An SSL-backed TCP Stream
-	Ssl(SslStream<TcpStream>)
+	Ssl(SharedSslStream)
+}
+
+/// An SSL stream which can be cloned to read and write from different threads.
+///
+/// Clones share the same TLS session. Each call into OpenSSL is made while holding a
+/// lock shared by the clones, so records are never interleaved, but a read waits for
+/// data to arrive before taking the lock, so that writes are not held up by a read
+/// which is waiting. Once part of a record has arrived, the lock is held until the
+/// rest of it has been read.
+pub struct SharedSslStream {
+	stream: SslStream<TcpStream>,
+	lock: Arc<Mutex<()>>,
+}
+
+impl SharedSslStream {
+	/// Wraps an SSL stream which has completed its handshake.
+	pub fn new(stream: SslStream<TcpStream>) -> SharedSslStream {
+		SharedSslStream {
+			stream: stream,
+			lock: Arc::new(Mutex::new(())),
+		}
+	}
+	/// Returns a reference to the underlying TCP stream. Reading or writing it directly
+	/// would bypass SSL.
+	pub fn get_ref(&self) -> &TcpStream {
+		self.stream.get_ref()
+	}
+	/// Returns the OpenSSL `Ssl` object for the session, for example to inspect the
+	/// peer's certificate.
+	pub fn ssl(&self) -> &Ssl {
+		self.stream.ssl()
+	}
+	/// Returns a SharedSslStream which uses the same TLS session.
+	pub fn try_clone(&self) -> io::Result<SharedSslStream> {
+		Ok(SharedSslStream {
+			stream: try!(self.stream.try_clone()),
+			lock: self.lock.clone(),
+		})
+	}
+}
+
+impl Read for SharedSslStream {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		{
+			let _guard = self.lock.lock().unwrap();
+			if self.stream.ssl().pending() > 0 {
+				return self.stream.read(buf);
+			}
+		}
+		// Wait for the socket to have data, honouring its timeout and nonblocking mode
+		let mut byte = [0];
+		try!(self.stream.get_ref().peek(&mut byte));
+		let _guard = self.lock.lock().unwrap();
+		self.stream.read(buf)
+	}
+}
+
+impl Write for SharedSslStream {
+	fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
+		let _guard = self.lock.lock().unwrap();
+		self.stream.write(msg)
+	}
+
+	fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+		let _guard = self.lock.lock().unwrap();
+		self.stream.write_vectored(bufs)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		let _guard = self.lock.lock().unwrap();
+		self.stream.flush()
+	}
+}
+
+/// Socket options for a TCP connection, applied as soon as it is established and
+/// before any TLS or WebSocket handshake.
+///
+/// See `Client::connect_with_tcp_options()`, `Client::connect_with_timeout_and_tcp_options()`,
+/// `Socks5Proxy::set_tcp_options()` and `Server::set_tcp_options()`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TcpOptions {
+	/// Whether to disable Nagle's algorithm (`TCP_NODELAY`), so that small writes such
+	/// as the handshake go out without waiting.
+	pub nodelay: bool,
+	/// How long the connection may be idle before keepalive probes are sent, or None to
+	/// disable keepalive. Anything under a second is rounded up to a second, as some
+	/// operating systems count this in whole seconds and reject zero.
+	pub keepalive: Option<Duration>,
+}
+
+impl TcpOptions {
+	/// Sets these options on `stream`.
+	pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+		try!(TcpStreamExt::set_nodelay(stream, self.nodelay));
+		let keepalive = self.keepalive.map(|keepalive| {
+			let ms = keepalive.as_secs() * 1000 + keepalive.subsec_nanos() as u64 / 1_000_000;
+			cmp::min(cmp::max(ms, 1000), u32::max_value() as u64) as u32
+		});
+		TcpStreamExt::set_keepalive_ms(stream, keepalive)
+	}
 }
 
 impl Read for WebSocketStream {
@@ -32,10 +147,25 @@ impl Write for WebSocketStream {
 		}
 	}
 
+	fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+		match *self {
+			WebSocketStream::Tcp(ref mut inner) => inner.write_vectored(bufs),
+			WebSocketStream::Ssl(ref mut inner) => inner.write_vectored(bufs),
+		}
+	}
+
+	/// Flushes the stream.
+	///
+	/// For an SSL stream, this flushes the SSL layer and then the underlying TCP
+	/// stream, so once this returns every record written so far has been handed to
+	/// the socket rather than left in the SSL write buffer.
 	fn flush(&mut self) -> io::Result<()> {
 		match *self {
 			WebSocketStream::Tcp(ref mut inner) => inner.flush(),
-			WebSocketStream::Ssl(ref mut inner) => inner.flush(),
+			WebSocketStream::Ssl(ref mut inner) => {
+				try!(inner.flush());
+				inner.get_ref().flush()
+			}
 		}
 	}
 }
@@ -59,21 +189,128 @@ impl WebSocketStream {
 	pub fn set_nodelay(&mut self, nodelay: bool) -> io::Result<()> {
 		match *self {
 			WebSocketStream::Tcp(ref mut inner) => TcpStreamExt::set_nodelay(inner, nodelay),
-			WebSocketStream::Ssl(ref mut inner) => TcpStreamExt::set_nodelay(inner.get_mut(), nodelay),
+			WebSocketStream::Ssl(ref mut inner) => TcpStreamExt::set_nodelay(inner.get_ref(), nodelay),
 		}
 	}
 	/// See `TcpStream.set_keepalive()`.
 	pub fn set_keepalive(&mut self, delay_in_ms: Option<u32>) -> io::Result<()> {
 		match *self {
 			WebSocketStream::Tcp(ref mut inner) => TcpStreamExt::set_keepalive_ms(inner, delay_in_ms),
-			WebSocketStream::Ssl(ref mut inner) => TcpStreamExt::set_keepalive_ms(inner.get_mut(), delay_in_ms),
+			WebSocketStream::Ssl(ref mut inner) => TcpStreamExt::set_keepalive_ms(inner.get_ref(), delay_in_ms),
+		}
+	}
+	/// Sets the size of the socket's receive buffer (`SO_RCVBUF`).
+	///
+	/// Links with a high bandwidth and latency need buffers of at least the
+	/// bandwidth-delay product to be kept full. The operating system may round or limit
+	/// the size, and Linux doubles it to allow for bookkeeping, so `recv_buffer_size()`
+	/// may not return the same value.
+	pub fn set_recv_buffer_size(&mut self, size: usize) -> io::Result<()> {
+		TcpStreamExt::set_recv_buffer_size(self.tcp_ref(), size)
+	}
+	/// Returns the size of the socket's receive buffer. See `set_recv_buffer_size()`.
+	pub fn recv_buffer_size(&self) -> io::Result<usize> {
+		TcpStreamExt::recv_buffer_size(self.tcp_ref())
+	}
+	/// Sets the size of the socket's send buffer (`SO_SNDBUF`). See
+	/// `set_recv_buffer_size()`.
+	pub fn set_send_buffer_size(&mut self, size: usize) -> io::Result<()> {
+		TcpStreamExt::set_send_buffer_size(self.tcp_ref(), size)
+	}
+	/// Returns the size of the socket's send buffer. See `set_recv_buffer_size()`.
+	pub fn send_buffer_size(&self) -> io::Result<usize> {
+		TcpStreamExt::send_buffer_size(self.tcp_ref())
+	}
+	/// See `TcpStream.set_read_timeout()`.
+	///
+	/// When a read times out, an I/O error of kind `WouldBlock` or `TimedOut` (depending
+	/// on the platform) is returned through the Receiver. If this happens part way
+	/// through a data frame, the part which has arrived is kept by the Receiver, and the
+	/// next call carries on reading the rest of it.
+	pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+		match *self {
+			WebSocketStream::Tcp(ref mut inner) => inner.set_read_timeout(dur),
+			WebSocketStream::Ssl(ref mut inner) => inner.get_ref().set_read_timeout(dur),
+		}
+	}
+	/// See `TcpStream.set_write_timeout()`.
+	pub fn set_write_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+		match *self {
+			WebSocketStream::Tcp(ref mut inner) => inner.set_write_timeout(dur),
+			WebSocketStream::Ssl(ref mut inner) => inner.get_ref().set_write_timeout(dur),
+		}
+	}
+	/// See `TcpStream.read_timeout()`.
+	pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+		self.tcp_ref().read_timeout()
+	}
+	/// See `TcpStream.write_timeout()`.
+	pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+		self.tcp_ref().write_timeout()
+	}
+	/// Sets the IP type of service field (e.g. a DSCP marking) on outgoing packets.
+	///
+	/// This sets `IP_TOS` on IPv4 connections and `IPV6_TCLASS` on IPv6 connections.
+	/// It is available on Linux, Android, macOS, iOS, the BSDs and Windows, although
+	/// IPv6 connections are only supported on the Unix platforms.
+	#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+	          target_os = "freebsd", target_os = "netbsd", target_os = "openbsd", windows))]
+	pub fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+		let stream = self.tcp_ref();
+		match try!(stream.local_addr()) {
+			SocketAddr::V4(_) => SockRef::from(stream).set_tos(tos as u32),
+			SocketAddr::V6(_) => set_tclass_v6(stream, tos),
 		}
 	}
+	/// Sets the `SO_LINGER` option, which controls what happens to unsent data when the
+	/// socket is closed.
+	///
+	/// With `Some(timeout)`, closing the socket blocks for up to `timeout` while unsent
+	/// data is delivered, after which the connection is reset and any remaining data is
+	/// discarded. `Some(Duration::from_secs(0))` resets the connection immediately. With
+	/// `None` (the default), closing returns at once and delivery continues in the
+	/// background. This only concerns data already written to the socket: the WebSocket
+	/// closing handshake should still be completed first so that the remote endpoint
+	/// knows the connection is being closed deliberately.
+	pub fn set_linger(&mut self, linger: Option<Duration>) -> io::Result<()> {
+		SockRef::from(self.tcp_ref()).set_linger(linger)
+	}
+	/// Returns the current `SO_LINGER` option. See `set_linger()`.
+	pub fn linger(&self) -> io::Result<Option<Duration>> {
+		SockRef::from(self.tcp_ref()).linger()
+	}
 	/// See `TcpStream.shutdown()`.
+	///
+	/// For an SSL stream this shuts down the underlying TCP stream directly; no TLS
+	/// `close_notify` alert is sent, as the OpenSSL bindings used here do not expose
+	/// `SSL_shutdown`. The remote endpoint therefore cannot tell a clean TLS closure
+	/// from a truncated connection, and should rely on the WebSocket closing handshake
+	/// to know that the connection was closed deliberately.
 	pub fn shutdown(&mut self, shutdown: Shutdown) -> io::Result<()> {
 		match *self {
 			WebSocketStream::Tcp(ref mut inner) => inner.shutdown(shutdown),
-			WebSocketStream::Ssl(ref mut inner) => inner.get_mut().shutdown(shutdown),
+			WebSocketStream::Ssl(ref mut inner) => inner.get_ref().shutdown(shutdown),
+		}
+	}
+	/// Reads data without removing it from the stream, so that it is read again by the
+	/// next `read()`. See `TcpStream.peek()`.
+	///
+	/// This can be used to tell which protocol a newly accepted connection speaks before
+	/// handing it to `Request::read()`. It is not supported for SSL streams, as the
+	/// OpenSSL bindings used here cannot peek at decrypted data, and an error is returned.
+	pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+		match *self {
+			WebSocketStream::Tcp(ref inner) => inner.peek(buf),
+			WebSocketStream::Ssl(_) => Err(io::Error::new(
+				io::ErrorKind::Other, "Peeking is not supported on SSL streams"
+			)),
+		}
+	}
+	/// Returns a reference to the underlying TCP stream.
+	pub fn tcp_ref(&self) -> &TcpStream {
+		match *self {
+			WebSocketStream::Tcp(ref inner) => inner,
+			WebSocketStream::Ssl(ref inner) => inner.get_ref(),
 		}
 	}
 	/// See `TcpStream.try_clone()`.
@@ -92,3 +329,196 @@ impl WebSocketStream {
         }
     }
 }
+
+#[cfg(unix)]
+impl AsRawFd for WebSocketStream {
+	/// Returns the file descriptor of the underlying TCP stream, for registering it with
+	/// an event loop. Reading or writing it directly would bypass SSL.
+	fn as_raw_fd(&self) -> RawFd {
+		self.tcp_ref().as_raw_fd()
+	}
+}
+
+#[cfg(windows)]
+impl AsRawSocket for WebSocketStream {
+	/// Returns the socket of the underlying TCP stream, for registering it with an event
+	/// loop. Reading or writing it directly would bypass SSL.
+	fn as_raw_socket(&self) -> RawSocket {
+		self.tcp_ref().as_raw_socket()
+	}
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+          target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+fn set_tclass_v6(stream: &TcpStream, tclass: u8) -> io::Result<()> {
+	use std::mem;
+
+	let value = tclass as libc::c_int;
+	let result = unsafe {
+		libc::setsockopt(
+			stream.as_raw_fd(),
+			libc::IPPROTO_IPV6,
+			libc::IPV6_TCLASS,
+			&value as *const libc::c_int as *const libc::c_void,
+			mem::size_of::<libc::c_int>() as libc::socklen_t
+		)
+	};
+	if result == -1 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(())
+}
+
+#[cfg(windows)]
+fn set_tclass_v6(_stream: &TcpStream, _tclass: u8) -> io::Result<()> {
+	Err(io::Error::new(io::ErrorKind::Other, "IPV6_TCLASS is not supported on this platform"))
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::io::{ErrorKind, Read, Write};
+	use std::net::TcpListener;
+	use std::time::Duration;
+	use client::Receiver;
+	use hyper::buffer::BufReader;
+	use result::WebSocketError;
+	use ws::Receiver as ReceiverTrait;
+
+	#[test]
+	fn test_ssl_flush() {
+		use std::thread;
+		use openssl::crypto::hash::Type as HashType;
+		use openssl::ssl::{SslContext, SslMethod};
+		use openssl::x509::X509Generator;
+
+		let (cert, key) = X509Generator::new()
+			.set_bitlength(2048)
+			.set_valid_period(1)
+			.add_name("CN".to_string(), "localhost".to_string())
+			.set_sign_hash(HashType::SHA256)
+			.generate()
+			.unwrap();
+		let mut server_context = SslContext::new(SslMethod::Sslv23).unwrap();
+		server_context.set_certificate(&cert).unwrap();
+		server_context.set_private_key(&key).unwrap();
+
+		// More than fits in the socket buffers, so writing blocks part way through
+		let data: Vec<u8> = (0..1 << 20).map(|i| i as u8).collect();
+		let expected = data.clone();
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let addr = listener.local_addr().unwrap();
+		let thread = thread::spawn(move || {
+			let stream = listener.accept().unwrap().0;
+			let mut stream = SslStream::accept(&server_context, stream).unwrap();
+			let mut received = vec![0; expected.len()];
+			stream.read_exact(&mut received).unwrap();
+			assert!(received == expected);
+			stream.write_all(b"!").unwrap();
+			stream.flush().unwrap();
+		});
+
+		let client_context = SslContext::new(SslMethod::Sslv23).unwrap();
+		let tcp = TcpStream::connect(addr).unwrap();
+		let ssl = SslStream::connect(&client_context, tcp).unwrap();
+		let mut stream = WebSocketStream::Ssl(SharedSslStream::new(ssl));
+		stream.write_all(&data).unwrap();
+		stream.flush().unwrap();
+		// Nothing more is written, so the reply only comes if every record was flushed
+		stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+		let mut reply = [0];
+		stream.read_exact(&mut reply).unwrap();
+		assert_eq!(&reply, b"!");
+		thread.join().unwrap();
+	}
+
+	#[test]
+	fn test_read_timeout_mid_frame() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let mut stream = WebSocketStream::Tcp(listener.accept().unwrap().0);
+
+		// The OS may round the timeouts, so only check that they were set
+		let timeout = Some(Duration::from_millis(50));
+		stream.set_write_timeout(timeout).unwrap();
+		assert!(stream.write_timeout().unwrap().is_some());
+		stream.set_write_timeout(None).unwrap();
+		assert_eq!(stream.write_timeout().unwrap(), None);
+		stream.set_read_timeout(timeout).unwrap();
+		assert!(stream.read_timeout().unwrap().is_some());
+
+		// Only part of a five byte text frame arrives
+		peer.write_all(&[0x81, 0x05, b'H', b'e']).unwrap();
+		let mut receiver = Receiver::new(BufReader::new(stream), false);
+		match receiver.recv_dataframe() {
+			Err(WebSocketError::IoError(ref err))
+				if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => (),
+			other => panic!("Expected a timeout, got {:?}", other),
+		}
+
+		// The rest of the frame completes it on the next call
+		peer.write_all(&[b'l', b'l', b'o']).unwrap();
+		let frame = receiver.recv_dataframe().unwrap();
+		assert_eq!(frame.data, b"Hello".to_vec());
+		assert_eq!(receiver.buffered_bytes(), 0);
+	}
+
+	#[test]
+	fn test_buffer_sizes() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let mut stream = WebSocketStream::Tcp(listener.accept().unwrap().0);
+
+		// The OS may round the sizes up, or double them on Linux
+		stream.set_recv_buffer_size(64 * 1024).unwrap();
+		assert!(stream.recv_buffer_size().unwrap() >= 64 * 1024);
+		stream.set_send_buffer_size(64 * 1024).unwrap();
+		assert!(stream.send_buffer_size().unwrap() >= 64 * 1024);
+	}
+
+	#[test]
+	fn test_tcp_options() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+		let options = TcpOptions { nodelay: true, keepalive: Some(Duration::from_millis(30500)) };
+		options.apply(&stream).unwrap();
+		assert!(stream.nodelay().unwrap());
+		assert!(TcpStreamExt::keepalive_ms(&stream).unwrap().is_some());
+
+		for &ms in &[0, 500] {
+			let options = TcpOptions { nodelay: false, keepalive: Some(Duration::from_millis(ms)) };
+			options.apply(&stream).unwrap();
+			assert!(TcpStreamExt::keepalive_ms(&stream).unwrap().is_some());
+		}
+
+		TcpOptions::default().apply(&stream).unwrap();
+		assert!(!stream.nodelay().unwrap());
+		assert_eq!(TcpStreamExt::keepalive_ms(&stream).unwrap(), None);
+	}
+
+	#[test]
+	fn test_peek() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let mut stream = WebSocketStream::Tcp(listener.accept().unwrap().0);
+		peer.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+		let mut buf = [0; 4];
+		assert_eq!(stream.peek(&mut buf).unwrap(), 4);
+		assert_eq!(&buf, b"GET ");
+		let mut buf = [0; 3];
+		stream.read_exact(&mut buf).unwrap();
+		assert_eq!(&buf, b"GET");
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn test_as_raw_fd() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let tcp = listener.accept().unwrap().0;
+		let fd = tcp.as_raw_fd();
+		assert_eq!(WebSocketStream::Tcp(tcp).as_raw_fd(), fd);
+	}
+}
@@ -8,6 +8,7 @@ use result::WebSocketResult;
 use ws::util::header as dfh;
 use ws::util::mask::Masker;
 use ws::util::mask;
+use ws::util;
 
 /// A generic DataFrame. Every dataframe should be able to
 /// provide these methods. (If the payload is not known in advance then
@@ -38,6 +39,17 @@ pub trait DataFrame {
 
     /// Writes a DataFrame to a Writer.
     fn write_to<W>(&self, writer: &mut W, mask: bool) -> WebSocketResult<()>
+	where W: Write {
+    	let masking_key = if mask {
+            Some(mask::gen_mask())
+        } else {
+            None
+        };
+        self.write_to_with_key(writer, masking_key)
+    }
+
+    /// Writes a DataFrame to a Writer, masked with the given key if there is one.
+    fn write_to_with_key<W>(&self, writer: &mut W, masking_key: Option<[u8; 4]>) -> WebSocketResult<()>
 	where W: Write {
     	let mut flags = dfh::DataFrameFlags::empty();
     	if self.is_last() {
@@ -56,12 +68,6 @@ pub trait DataFrame {
             }
         }
 
-    	let masking_key = if mask {
-            Some(mask::gen_mask())
-        } else {
-            None
-        };
-
     	let header = dfh::DataFrameHeader {
     		flags: flags,
     		opcode: self.opcode() as u8,
@@ -69,14 +75,17 @@ pub trait DataFrame {
     		len: self.size() as u64,
     	};
 
-    	try!(dfh::write_header(writer, header));
+    	// The header is written at once, and together with an unmasked payload
+    	let mut head = Vec::with_capacity(14);
+    	try!(dfh::write_header(&mut head, header));
 
     	match masking_key {
     		Some(mask) => {
+                try!(writer.write_all(&head));
                 let mut masker = Masker::new(mask, writer);
                 try!(self.write_payload(&mut masker))
             },
-    		None => try!(self.write_payload(writer)),
+    		None => try!(util::write_all_vectored(writer, &[&head, &self.payload()])),
     	};
     	try!(writer.flush());
         Ok(())
@@ -121,4 +130,10 @@ where D: DataFrame {
 	where W: Write {
         D::write_to(self, writer, mask)
     }
+
+    #[inline(always)]
+    fn write_to_with_key<W>(&self, writer: &mut W, masking_key: Option<[u8; 4]>) -> WebSocketResult<()>
+	where W: Write {
+        D::write_to_with_key(self, writer, masking_key)
+    }
 }
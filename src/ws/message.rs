@@ -13,6 +13,14 @@ where F: DataFrame {
 	/// Attempt to form a message from a slice of data frames.
 	fn from_dataframes<D>(frames: Vec<D>) -> WebSocketResult<Self>
     where D: DataFrame;
+	/// Attempt to form a message from a slice of data frames, without checking that
+	/// text payloads are valid UTF-8.
+	///
+	/// By default this is the same as `from_dataframes()`.
+	fn from_dataframes_unvalidated<D>(frames: Vec<D>) -> WebSocketResult<Self>
+    where D: DataFrame {
+		Self::from_dataframes(frames)
+	}
 	/// Turns this message into an iterator over data frames
 	fn dataframes(&'a self) -> Self::DataFrameIterator;
 }
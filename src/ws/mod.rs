@@ -40,7 +40,7 @@
 pub use self::message::Message;
 pub use self::sender::Sender;
 pub use self::receiver::Receiver;
-pub use self::receiver::{DataFrameIterator, MessageIterator};
+pub use self::receiver::{DataFrameIterator, MessageIterator, MessageMeta};
 
 pub mod message;
 pub mod sender;
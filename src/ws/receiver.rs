@@ -16,6 +16,21 @@ where F: DataFrame {
 	/// Returns the data frames that constitute one message.
 	fn recv_message_dataframes(&mut self) -> WebSocketResult<Vec<F>>;
 
+	/// Returns whether the text payloads of received messages should be checked
+	/// to be valid UTF-8. Defaults to true.
+	fn validates_utf8(&self) -> bool {
+		true
+	}
+
+	/// Returns the MessageMeta for the data frames of a message just returned by
+	/// `recv_message_dataframes()`. Defaults to `MessageMeta::new(dataframes)`.
+	///
+	/// A receiver which transforms data frames as they arrive, such as by decompressing
+	/// them, can report the data frames as they were received instead.
+	fn message_meta(&self, dataframes: &[F]) -> MessageMeta {
+		MessageMeta::new(dataframes)
+	}
+
 	/// Returns an iterator over incoming data frames.
 	fn incoming_dataframes<'a>(&'a mut self) -> DataFrameIterator<'a, Self, F> {
 		DataFrameIterator {
@@ -30,7 +45,30 @@ where F: DataFrame {
           D: DataFrame
     {
 		let dataframes = try!(self.recv_message_dataframes());
-		Message::from_dataframes(dataframes)
+		if self.validates_utf8() {
+			Message::from_dataframes(dataframes)
+		}
+		else {
+			Message::from_dataframes_unvalidated(dataframes)
+		}
+	}
+
+	/// Reads a single message from this receiver, along with information about how
+	/// it was received.
+	fn recv_message_with_meta<'m, D, M, I>(&mut self) -> WebSocketResult<(M, MessageMeta)>
+	where M: Message<'m, D, DataFrameIterator = I>,
+          I: Iterator<Item = D>,
+          D: DataFrame
+    {
+		let dataframes = try!(self.recv_message_dataframes());
+		let meta = self.message_meta(&dataframes);
+		let message = if self.validates_utf8() {
+			try!(Message::from_dataframes(dataframes))
+		}
+		else {
+			try!(Message::from_dataframes_unvalidated(dataframes))
+		};
+		Ok((message, meta))
 	}
 
 	/// Returns an iterator over incoming messages.
@@ -45,6 +83,28 @@ where F: DataFrame {
 	}
 }
 
+/// Information about how a message was received.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MessageMeta {
+	/// The number of data frames the message was assembled from. This is greater than
+	/// one if the message was fragmented.
+	pub frame_count: usize,
+	/// Whether the first data frame had the RSV1 bit set, which the permessage-deflate
+	/// extension uses to mark compressed messages.
+	pub compressed: bool,
+}
+
+impl MessageMeta {
+	/// Creates the MessageMeta for a message made up of the given data frames.
+	pub fn new<F>(dataframes: &[F]) -> MessageMeta
+	where F: DataFrame {
+		MessageMeta {
+			frame_count: dataframes.len(),
+			compressed: dataframes.first().map(|dataframe| dataframe.reserved()[0]).unwrap_or(false),
+		}
+	}
+}
+
 /// An iterator over data frames from a Receiver.
 pub struct DataFrameIterator<'a, R, D>
 where R: 'a + Receiver<D>, D: DataFrame {
@@ -20,4 +20,10 @@ pub trait Sender {
 		}
 		Ok(())
 	}
+
+	/// Flushes any data buffered by this sender, so that everything sent so far has been
+	/// written out. Does nothing by default.
+	fn flush(&mut self) -> WebSocketResult<()> {
+		Ok(())
+	}
 }
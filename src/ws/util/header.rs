@@ -1,7 +1,7 @@
 //! Utility functions for reading and writing data frame headers.
 
-use std::io::{Read, Write};
-use result::{WebSocketResult, WebSocketError};
+use std::io::{self, Read, Write};
+use result::{WebSocketResult, WebSocketError, CONTROL_FRAME_TOO_LONG, FRAGMENTED_CONTROL_FRAME};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 bitflags! {
@@ -41,9 +41,7 @@ pub fn write_header<W>(writer: &mut W, header: DataFrameHeader) -> WebSocketResu
 		));
 	}
 	if header.opcode >= 8 && header.len >= 126 {
-		return Err(WebSocketError::DataFrameError(
-			"Control frame length too long"
-		));
+		return Err(WebSocketError::DataFrameError(CONTROL_FRAME_TOO_LONG));
 	}
 
 	// Write 'FIN', 'RSV1', 'RSV2', 'RSV3' and 'opcode'
@@ -76,8 +74,23 @@ pub fn write_header<W>(writer: &mut W, header: DataFrameHeader) -> WebSocketResu
 }
 
 /// Reads a data frame header.
+///
+/// If the header is of an illegal fragmented control frame, its payload is skipped
+/// before returning the error.
 pub fn read_header<R>(reader: &mut R) -> WebSocketResult<DataFrameHeader>
 	where R: Read {
+	read_header_checked(reader, true)
+}
+
+/// Reads a data frame header, without checking that the payload length is encoded
+/// minimally or that a control frame is short and unfragmented.
+pub fn read_header_lenient<R>(reader: &mut R) -> WebSocketResult<DataFrameHeader>
+	where R: Read {
+	read_header_checked(reader, false)
+}
+
+fn read_header_checked<R>(reader: &mut R, strict: bool) -> WebSocketResult<DataFrameHeader>
+	where R: Read {
 
 	let byte0 = try!(reader.read_u8());
 	let byte1 = try!(reader.read_u8());
@@ -89,7 +102,7 @@ pub fn read_header<R>(reader: &mut R) -> WebSocketResult<DataFrameHeader>
 		0...125 => (byte1 & 0x7F) as u64,
 		126 => {
 			let len = try!(reader.read_u16::<BigEndian>()) as u64;
-			if len <= 125 {
+			if strict && len <= 125 {
 				return Err(WebSocketError::DataFrameError(
 					"Invalid data frame length"
 				));
@@ -98,7 +111,7 @@ pub fn read_header<R>(reader: &mut R) -> WebSocketResult<DataFrameHeader>
 		}
 		127 => {
 			let len = try!(reader.read_u64::<BigEndian>());
-			if len <= 65535 {
+			if strict && len <= 65535 {
 				return Err(WebSocketError::DataFrameError(
 					"Invalid data frame length"
 				));
@@ -108,17 +121,8 @@ pub fn read_header<R>(reader: &mut R) -> WebSocketResult<DataFrameHeader>
 		_ => unreachable!(),
 	};
 
-	if opcode >= 8 {
-		if len >= 126 {
-			return Err(WebSocketError::DataFrameError(
-				"Control frame length too long"
-			));
-		}
-		if !flags.contains(FIN) {
-			return Err(WebSocketError::ProtocolError(
-				"Illegal fragmented control frame"
-			));
-		}
+	if strict && opcode >= 8 && len >= 126 {
+		return Err(WebSocketError::DataFrameError(CONTROL_FRAME_TOO_LONG));
 	}
 
 	let mask = if byte1 & 0x80 == 0x80 {
@@ -133,6 +137,12 @@ pub fn read_header<R>(reader: &mut R) -> WebSocketResult<DataFrameHeader>
 		None
 	};
 
+	if strict && opcode >= 8 && !flags.contains(FIN) {
+		// Skip the payload, so that the reader is left at the next data frame
+		try!(io::copy(&mut reader.take(len), &mut io::sink()));
+		return Err(WebSocketError::ProtocolError(FRAGMENTED_CONTROL_FRAME));
+	}
+
 	Ok(DataFrameHeader {
 		flags: flags,
 		opcode: opcode,
@@ -158,6 +168,14 @@ mod tests {
 		assert_eq!(obtained, expected);
 	}
 	#[test]
+	fn test_read_header_lenient() {
+		// A non-minimal length, and a fragmented control frame
+		for header in [&[0x81, 0x7E, 0x00, 0x05][..], &[0x09, 0x00][..]].iter() {
+			assert!(read_header(&mut &header[..]).is_err());
+			assert!(read_header_lenient(&mut &header[..]).is_ok());
+		}
+	}
+	#[test]
 	fn test_write_header_simple() {
 		let header = DataFrameHeader {
 			flags: FIN,
@@ -4,8 +4,14 @@ use std::io::Write;
 use std::io::Result as IoResult;
 use std::mem;
 
+/// The most bytes masked at once by a Masker.
+const SCRATCH_SIZE: usize = 4096;
+
 /// Struct to pipe data into another writer,
 /// while masking the data being written
+///
+/// Data is masked through a small buffer on the stack, so writing a large payload
+/// does not allocate a masked copy of it.
 pub struct Masker<'w, W>
 where W: Write + 'w {
     key: [u8; 4],
@@ -29,12 +35,15 @@ where W: Write + 'w {
 impl<'w, W> Write for Masker<'w, W>
 where W: Write + 'w {
     fn write(&mut self, data: &[u8]) -> IoResult<usize> {
-        let mut buf = Vec::with_capacity(data.len());
-        for &byte in data.iter() {
-            buf.push(byte ^ self.key[self.pos]);
-            self.pos = (self.pos + 1) % self.key.len();
-        }
-        self.end.write(&buf)
+        let mut buf = [0u8; SCRATCH_SIZE];
+        let len = ::std::cmp::min(data.len(), SCRATCH_SIZE);
+        buf[..len].copy_from_slice(&data[..len]);
+        mask_in_place(self.key, self.pos, &mut buf[..len]);
+        // Only advance past what was written, so that a short or interrupted
+        // write can be retried with the same data
+        let written = try!(self.end.write(&buf[..len]));
+        self.pos = (self.pos + written) % self.key.len();
+        Ok(written)
     }
 
     fn flush(&mut self) -> IoResult<()> {
@@ -48,20 +57,129 @@ pub fn gen_mask() -> [u8; 4] {
 	unsafe { mem::transmute(rand::random::<u32>()) }
 }
 
+/// A source of masking keys for the data frames a client sends.
+///
+/// Every data frame a client sends is masked with a key from its provider, so a
+/// provider can make the bytes on the wire predictable but cannot turn masking off.
+/// RFC 6455 requires keys which the remote endpoint cannot predict, so anything other
+/// than `RandomMaskingKeys` should only be used in tests.
+pub trait MaskingKeyProvider: Send {
+	/// Returns the key to mask the next data frame with.
+	fn next_key(&mut self) -> [u8; 4];
+}
+
+/// Provides a fresh random key for every data frame. This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomMaskingKeys;
+
+impl MaskingKeyProvider for RandomMaskingKeys {
+	fn next_key(&mut self) -> [u8; 4] {
+		gen_mask()
+	}
+}
+
+impl<F> MaskingKeyProvider for F
+where F: FnMut() -> [u8; 4] + Send {
+	fn next_key(&mut self) -> [u8; 4] {
+		self()
+	}
+}
+
 /// Masks data to send to a server and writes
 pub fn mask_data(mask: [u8; 4], data: &[u8]) -> Vec<u8> {
-    let mut out = Vec::with_capacity(data.len());
-    let zip_iter = data.iter().zip(mask.iter().cycle());
-    for (&buf_item, &key_item) in zip_iter {
-        out.push(buf_item ^ key_item);
-    }
+    let mut out = data.to_vec();
+    mask_in_place(mask, 0, &mut out);
     out
 }
 
+/// Masks (or unmasks) data in place, as if it started `offset` bytes into the payload.
+///
+/// Data is processed eight bytes at a time, which the compiler can vectorize further.
+pub fn mask_in_place(mask: [u8; 4], offset: usize, data: &mut [u8]) {
+    let mut key = [0u8; 8];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = mask[(offset + i) % 4];
+    }
+    let word = u64::from_ne_bytes(key);
+    let mut chunks = data.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(chunk);
+        chunk.copy_from_slice(&(u64::from_ne_bytes(bytes) ^ word).to_ne_bytes());
+    }
+    // Each chunk is a whole number of keys long, so the tail starts with the same key
+    for (byte, key) in chunks.into_remainder().iter_mut().zip(key.iter()) {
+        *byte ^= *key;
+    }
+}
+
 #[cfg(all(feature = "nightly", test))]
 mod tests {
 	use super::*;
 	use test;
+	use std::io::{self, Write};
+
+	/// Accepts at most three bytes per write, and is interrupted before every write.
+	struct Flaky {
+		data: Vec<u8>,
+		interrupt: bool,
+	}
+
+	impl Write for Flaky {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.interrupt = !self.interrupt;
+			if self.interrupt {
+				return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted"));
+			}
+			let len = buf.len().min(3);
+			self.data.extend_from_slice(&buf[..len]);
+			Ok(len)
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_masker_interrupted_writes() {
+		let key = [1u8, 2u8, 3u8, 4u8];
+		let original = b"The quick brown fox";
+		let mut flaky = Flaky { data: Vec::new(), interrupt: false };
+		Masker::new(key, &mut flaky).write_all(original).unwrap();
+
+		assert_eq!(mask_data(key, &flaky.data), original.to_vec());
+	}
+
+	#[test]
+	fn test_masker_large_payload() {
+		let key = [1u8, 2u8, 3u8, 4u8];
+		let original: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+		let mut masked = Vec::new();
+		Masker::new(key, &mut masked).write_all(&original).unwrap();
+
+		assert_eq!(mask_data(key, &masked), original);
+	}
+
+	/// Masks a byte at a time, as mask_in_place() did originally.
+	fn mask_scalar(mask: [u8; 4], offset: usize, data: &[u8]) -> Vec<u8> {
+		data.iter().enumerate().map(|(i, &byte)| byte ^ mask[(offset + i) % 4]).collect()
+	}
+
+	#[test]
+	fn test_mask_in_place() {
+		let key = gen_mask();
+		let original: Vec<u8> = (0..100).map(|i| (i * 7) as u8).collect();
+		for start in 0..9 {
+			for end in start..original.len() {
+				for offset in 0..4 {
+					let mut data = original[start..end].to_vec();
+					mask_in_place(key, offset, &mut data);
+					assert_eq!(data, mask_scalar(key, offset, &original[start..end]));
+				}
+			}
+		}
+	}
+
 	#[test]
 	fn test_mask_data() {
 		let key = [1u8, 2u8, 3u8, 4u8];
@@ -84,6 +202,28 @@ mod tests {
 		});
 	}
 
+	#[bench]
+	fn bench_mask_1mib(b: &mut test::Bencher) {
+		let mut buffer = vec![0x5a; 1 << 20];
+		let key = gen_mask();
+		b.bytes = buffer.len() as u64;
+		b.iter(|| {
+			mask_in_place(key, 1, &mut buffer);
+			test::black_box(&mut buffer);
+		});
+	}
+
+	#[bench]
+	fn bench_mask_1mib_scalar(b: &mut test::Bencher) {
+		let buffer = vec![0x5a; 1 << 20];
+		let key = gen_mask();
+		b.bytes = buffer.len() as u64;
+		b.iter(|| {
+			let mut output = mask_scalar(key, 1, &buffer);
+			test::black_box(&mut output);
+		});
+	}
+
 	#[bench]
 	fn bench_gen_mask(b: &mut test::Bencher) {
 		b.iter(|| {
@@ -3,7 +3,9 @@
 pub mod header;
 pub mod mask;
 pub mod url;
+pub mod utf8;
 
+use std::io::{self, IoSlice, Write};
 use std::str::from_utf8;
 use std::str::Utf8Error;
 
@@ -12,3 +14,96 @@ pub fn bytes_to_string(data: &[u8]) -> Result<String, Utf8Error> {
 	let utf8 = try!(from_utf8(data));
 	Ok(utf8.to_string())
 }
+
+/// Writes all of the given buffers, using vectored writes so that they can be written
+/// together in one system call.
+///
+/// If the writer reports writing nothing, the rest is written with ordinary writes.
+pub fn write_all_vectored<W: Write>(writer: &mut W, mut bufs: &[&[u8]]) -> io::Result<()> {
+	let mut offset = 0;
+	loop {
+		while !bufs.is_empty() && offset == bufs[0].len() {
+			bufs = &bufs[1..];
+			offset = 0;
+		}
+		if bufs.is_empty() {
+			return Ok(());
+		}
+		let mut slices = Vec::with_capacity(bufs.len());
+		slices.push(IoSlice::new(&bufs[0][offset..]));
+		slices.extend(bufs[1..].iter().map(|buf| IoSlice::new(buf)));
+		let mut written = match writer.write_vectored(&slices) {
+			Ok(0) => {
+				try!(writer.write_all(&bufs[0][offset..]));
+				for buf in &bufs[1..] {
+					try!(writer.write_all(buf));
+				}
+				return Ok(());
+			}
+			Ok(written) => written,
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e),
+		};
+		while written > 0 {
+			let remaining = bufs[0].len() - offset;
+			if written < remaining {
+				offset += written;
+				break;
+			}
+			written -= remaining;
+			bufs = &bufs[1..];
+			offset = 0;
+		}
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::io::{self, IoSlice, Write};
+
+	/// Records each call, writing at most `limit` bytes of the first buffer or, if
+	/// `vectored`, across all buffers.
+	struct Recorder {
+		calls: Vec<Vec<u8>>,
+		limit: usize,
+		vectored: bool,
+	}
+
+	impl Write for Recorder {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			let len = buf.len().min(self.limit);
+			self.calls.push(buf[..len].to_vec());
+			Ok(len)
+		}
+		fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+			if !self.vectored {
+				return Ok(0);
+			}
+			let data: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().cloned()).take(self.limit).collect();
+			let len = data.len();
+			self.calls.push(data);
+			Ok(len)
+		}
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_write_all_vectored() {
+		let bufs: &[&[u8]] = &[b"ab", b"", b"cde", b"f"];
+		let mut writer = Recorder { calls: Vec::new(), limit: 100, vectored: true };
+		write_all_vectored(&mut writer, bufs).unwrap();
+		assert_eq!(writer.calls, vec![b"abcdef".to_vec()]);
+
+		let mut writer = Recorder { calls: Vec::new(), limit: 3, vectored: true };
+		write_all_vectored(&mut writer, bufs).unwrap();
+		assert_eq!(writer.calls, vec![b"abc".to_vec(), b"def".to_vec()]);
+
+		// Falls back to ordinary writes
+		let mut writer = Recorder { calls: Vec::new(), limit: 100, vectored: false };
+		write_all_vectored(&mut writer, bufs).unwrap();
+		assert_eq!(writer.calls.concat(), b"abcdef".to_vec());
+	}
+}
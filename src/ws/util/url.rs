@@ -1,6 +1,6 @@
 //! Utility functions for dealing with URLs
 
-use url::{Url, Position};
+use url::{Url, Position, ParseError};
 use url::Host as UrlHost;
 use hyper::header::Host;
 use result::{WebSocketResult, WSUrlErrorKind};
@@ -89,7 +89,11 @@ impl<'a, T: ToWebSocketUrlComponents> ToWebSocketUrlComponents for &'a T {
 pub fn parse_url_str(url_str: &str) -> WebSocketResult<(Host, String, bool)> {
     // https://html.spec.whatwg.org/multipage/#parse-a-websocket-url's-components
     // Steps 1 and 2
-    let parsed_url = try!(Url::parse(url_str));
+    let parsed_url = match Url::parse(url_str) {
+        Ok(url) => url,
+        Err(ParseError::InvalidPort) => return Err(From::from(WSUrlErrorKind::InvalidPort)),
+        Err(e) => return Err(From::from(e)),
+    };
     parse_url(&parsed_url)
 }
 
@@ -110,8 +114,17 @@ pub fn parse_url(url: &Url) -> WebSocketResult<(Host, String, bool)> {
         _ => return Err(From::from(WSUrlErrorKind::InvalidScheme)),
     };
 
+    // Credentials are not sent in the opening handshake, so refuse them rather than
+    // silently connecting without them
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(From::from(WSUrlErrorKind::CannotSetCredentials));
+    }
+
     let host = url.host_str().unwrap().to_owned(); // Step 6
     let port = url.port_or_known_default(); // Steps 7 and 8
+    if port == Some(0) {
+        return Err(From::from(WSUrlErrorKind::InvalidPort));
+    }
 
     // steps 9, 10, 11
     let resource = url[Position::BeforePath..Position::AfterQuery].to_owned();
@@ -340,4 +353,26 @@ mod tests {
             Ok(_) => panic!("Expected CannotSetFragment, but got Ok"),
         }
     }
+
+    #[test]
+    fn test_parse_url_str_invalid_port() {
+        for url_str in &["ws://www.example.com:notaport/", "ws://www.example.com:65536/", "ws://www.example.com:0/"] {
+            match parse_url_str(url_str) {
+                Err(WebSocketError::WebSocketUrlError(WSUrlErrorKind::InvalidPort)) => (),
+                Err(e) => panic!("Expected InvalidPort, but got unexpected error {}", e),
+                Ok(_) => panic!("Expected InvalidPort, but got Ok"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_url_str_credentials() {
+        for url_str in &["ws://user:pass@www.example.com/", "ws://user@www.example.com/", "wss://:pass@www.example.com/"] {
+            match parse_url_str(url_str) {
+                Err(WebSocketError::WebSocketUrlError(WSUrlErrorKind::CannotSetCredentials)) => (),
+                Err(e) => panic!("Expected CannotSetCredentials, but got unexpected error {}", e),
+                Ok(_) => panic!("Expected CannotSetCredentials, but got Ok"),
+            }
+        }
+    }
 }
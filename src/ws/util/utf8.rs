@@ -0,0 +1,106 @@
+//! Incremental UTF-8 validation for fragmented text messages.
+use std::str::{from_utf8, Utf8Error};
+
+/// Checks that data is valid UTF-8 as it arrives in pieces.
+///
+/// A character may be split between pieces, but invalid data is reported as soon as
+/// the piece containing it is fed, rather than once the whole message has arrived.
+#[derive(Debug, Clone, Default)]
+pub struct Utf8Validator {
+	incomplete: [u8; 4],
+	len: usize,
+}
+
+impl Utf8Validator {
+	/// Creates a new Utf8Validator.
+	pub fn new() -> Utf8Validator {
+		Utf8Validator::default()
+	}
+	/// Checks the next piece of data.
+	pub fn feed(&mut self, mut data: &[u8]) -> Result<(), Utf8Error> {
+		// Complete the character left over from the previous piece first
+		while self.len > 0 && !data.is_empty() {
+			self.incomplete[self.len] = data[0];
+			self.len += 1;
+			data = &data[1..];
+			match from_utf8(&self.incomplete[..self.len]) {
+				Ok(_) => self.len = 0,
+				Err(e) => if e.error_len().is_some() {
+					self.len = 0;
+					return Err(e);
+				},
+			}
+		}
+		match from_utf8(data) {
+			Ok(_) => Ok(()),
+			Err(e) => match e.error_len() {
+				Some(_) => Err(e),
+				None => {
+					let rest = &data[e.valid_up_to()..];
+					self.incomplete[..rest.len()].copy_from_slice(rest);
+					self.len = rest.len();
+					Ok(())
+				}
+			},
+		}
+	}
+	/// Checks that the data did not end part way through a character, and resets the
+	/// Utf8Validator for the next message.
+	pub fn finish(&mut self) -> Result<(), Utf8Error> {
+		let result = from_utf8(&self.incomplete[..self.len]).map(|_| ());
+		self.reset();
+		result
+	}
+	/// Forgets any data fed so far.
+	pub fn reset(&mut self) {
+		self.len = 0;
+	}
+}
+
+#[cfg(all(feature = "nightly", test))]
+mod tests {
+	use super::*;
+	use std::str::from_utf8;
+
+	fn validate(pieces: &[&[u8]]) -> bool {
+		let mut validator = Utf8Validator::new();
+		pieces.iter().all(|piece| validator.feed(piece).is_ok()) && validator.finish().is_ok()
+	}
+
+	#[test]
+	fn test_split_characters() {
+		let samples: &[&[u8]] = &[
+			"κόσμε".as_bytes(),
+			"a€b𝄞c".as_bytes(),
+			b"\xce\xba\xe1",
+			b"\xed\xa0\x80",
+			b"\xf4\x90\x80\x80",
+			b"\xc0\xaf",
+			b"\xf0\x9f\x98",
+			b"ab\xffcd",
+		];
+		for data in samples {
+			let expected = from_utf8(data).is_ok();
+			assert_eq!(validate(&[data]), expected);
+			for i in 0..data.len() + 1 {
+				assert_eq!(validate(&[&data[..i], &data[i..]]), expected);
+				for j in i..data.len() + 1 {
+					assert_eq!(validate(&[&data[..i], &data[i..j], &data[j..]]), expected);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn test_fails_fast() {
+		let mut validator = Utf8Validator::new();
+		validator.feed(b"ok \xce").unwrap();
+		assert!(validator.feed(b"A").is_err());
+
+		let mut validator = Utf8Validator::new();
+		assert!(validator.feed(b"\xe0\x80").is_err());
+		validator.reset();
+		validator.feed("€".as_bytes()).unwrap();
+		validator.finish().unwrap();
+	}
+}